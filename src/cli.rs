@@ -1,28 +1,97 @@
 use structopt::StructOpt;
 #[derive(StructOpt)]
+#[structopt(after_help = "EXIT CODES:
+    0    Success
+    1    Templates which couldn't be loaded or rendered
+    2    Invalid config file
+    3    Network errors
+    4    HTTP errors
+    5    Downloaded images which were error pages or truncated
+    6    Unsupported links
+    7    Anti-bot challenges which couldn't be passed
+    8    Pages which couldn't be parsed
+    9    IO errors
+    10   Database errors
+    130  Interrupted
+
+When several jobs failed, the highest code is used.")]
 pub struct ApplicationMainEntry {
+    /// TOML file of settings, replaced by the ones given here. bishojo.toml is read if it exists
+    #[structopt(long)]
+    pub config: Option<String>,
+    /// Profile of the config file to apply over its other settings
+    #[structopt(long)]
+    pub profile: Option<String>,
     #[structopt(short = "j", long, default_value = "database.json")]
     pub json_database_location: String,
     #[structopt(short = "b", long, default_value = "database.bin")]
     pub binary_database_location: String,
+    #[structopt(long, default_value = "database.sqlite")]
+    pub sqlite_database_location: String,
+    #[structopt(long, default_value = "legacy")]
+    pub storage: AvailableStorage,
+    #[structopt(long, default_value = "5")]
+    pub backup_count: usize,
+    #[structopt(long, default_value = "100")]
+    pub checkpoint_items: usize,
+    #[structopt(long, default_value = "300")]
+    pub checkpoint_seconds: u64,
+    #[structopt(long, default_value = "60")]
+    pub shutdown_grace_seconds: u64,
     #[structopt(short, long)]
     pub proxy: Option<String>,
     #[structopt(short, long)]
     pub thread_limit: Option<usize>,
-    #[structopt(
-        long = "user-agent",
-        default_value = "Mozilla/5.0 (Windows NT 10.0; Win64; x64; rv:53.0) Gecko/20100101 Firefox/53.0"
-    )]
-    pub user_agent: String,
-    #[structopt(long, default_value = "20")]
-    pub timeout: u64,
-    #[structopt(long, short)]
-    pub retry: Option<usize>,
+    /// Requests per second sent to each host, unlimited if not given
+    #[structopt(long, parse(try_from_str = parse_rate))]
+    pub rate_limit: Option<f64>,
+    /// Requests to each host which may run at the same time, unlimited if not given. Unlike
+    /// --thread-limit, this holds across all jobs and counts downloads until they're done
+    #[structopt(long)]
+    pub max_concurrency: Option<usize>,
+    #[structopt(long = "user-agent")]
+    pub user_agent: Option<String>,
+    /// Connect timeout in seconds [default: 20]
+    #[structopt(long)]
+    pub timeout: Option<u64>,
+    #[structopt(flatten)]
+    pub retry: RetryOptions,
     #[structopt(subcommand)]
     pub subcommand: ApplicationSubCommand,
 }
 #[derive(StructOpt)]
+pub struct RetryOptions {
+    #[structopt(long, short)]
+    pub retry: Option<usize>,
+    /// Milliseconds to wait before the first retry, doubled for each following one [default: 1000]
+    #[structopt(long)]
+    pub retry_delay: Option<u64>,
+    /// Longest wait between retries in milliseconds, also caps Retry-After [default: 60000]
+    #[structopt(long)]
+    pub retry_max_delay: Option<u64>,
+    /// Errors to retry: transport, http, image and/or challenge. Parse, IO and database errors,
+    /// as well as client errors such as 404, are never retried
+    /// [default: transport,http,image,challenge]
+    #[structopt(long, require_delimiter = true)]
+    pub retry_on: Option<Vec<crate::retry::RetryClass>>,
+    /// Retry settings of a website, e.g. kkgal:retries=5,delay=2000,max-delay=120000,on=http
+    #[structopt(long, number_of_values = 1)]
+    pub site_retry: Vec<crate::retry::SiteRetryPolicy>,
+}
+#[derive(StructOpt)]
 pub enum ApplicationSubCommand {
+    /// Import the json and binary databases into the sqlite database
+    Migrate,
+    /// List database backups, or restore the ones taken at the given timestamp
+    Restore { timestamp: Option<String> },
+    /// Inspect the settings
+    Config(ConfigSubCommand),
+    #[structopt(flatten)]
+    Database(DatabaseSubCommand),
+}
+/// Commands which open the database, and save it once they are done.
+#[derive(StructOpt, Clone)]
+pub enum DatabaseSubCommand {
     FetchMetadata {
         #[structopt(long)]
         site: AvailableWebsite,
@@ -33,6 +102,14 @@ pub enum ApplicationSubCommand {
         #[structopt(short, long)]
         overwrite: bool
     },
+    /// Fetch metadata from the first index page on, until a page only lists known games
+    Sync {
+        #[structopt(long)]
+        site: AvailableWebsite,
+        /// Refetch known games listed on the pages that contain new ones
+        #[structopt(short, long)]
+        overwrite: bool,
+    },
     DownloadUserAvatars {
         #[structopt(long)]
         site: Option<AvailableWebsite>,
@@ -55,23 +132,131 @@ pub enum ApplicationSubCommand {
         #[structopt(long, short)]
         save_unparsable_games_list: Option<String>,
     },
+    /// Merge games stored more than once
+    Dedupe,
+    /// Show how a game changed between fetches
+    History {
+        #[structopt(long)]
+        game_id: u64,
+    },
+    /// Search games by name, tags, descriptions and comments
+    Search {
+        query: String,
+        #[structopt(flatten)]
+        filter: GameFilter,
+        #[structopt(long, default_value = "20")]
+        limit: usize,
+    },
+    /// Browse the database from a local web server
+    Serve {
+        #[structopt(long, default_value = "127.0.0.1:8080")]
+        address: String,
+        /// Directory of templates replacing the built in ones
+        #[structopt(long)]
+        template_directory: Option<String>,
+    },
+    /// Only the selected games are exported, along with the images they reference
+    ///
+    /// Html and markdown exports only rewrite the files which changed since the previous export
+    /// to the same directory, and remove the ones it no longer produces. Files of games which
+    /// weren't selected are kept until the games are removed from the database.
     Export {
+        #[structopt(flatten)]
+        filter: GameFilter,
         #[structopt(short, long)]
         markdown_location: Option<String>,
         #[structopt(short, long)]
         html_location: Option<String>,
         #[structopt(short, long)]
         prefer_online: bool,
+        /// Directory of html templates replacing the built in ones
+        #[structopt(long)]
+        template_directory: Option<String>,
+        /// Start markdown pages with YAML front matter
+        #[structopt(long)]
+        front_matter: bool,
+        /// Write downscaled copies of the images for the html pages, which link to the full ones
+        #[structopt(long)]
+        thumbnails: bool,
+        /// Directory of EPUB books, one per game. Stored images are always embedded
+        #[structopt(long)]
+        epub_location: Option<String>,
+        /// Put the games with all of these tags in a single book instead
+        #[structopt(long)]
+        epub_tag: Vec<String>,
+        /// Directory of the flat games, files and comments tables
+        #[structopt(long)]
+        tables_location: Option<String>,
+        /// csv, ndjson or sqlite
+        #[structopt(long, default_value = "csv")]
+        tables_format: AvailableTableFormat,
     },
 }
+#[derive(StructOpt)]
+pub enum ConfigSubCommand {
+    /// Print the settings in effect, after the config file, its profile and the command line
+    Show,
+}
+/// Games matching every given condition are selected.
+#[derive(StructOpt, Clone)]
+pub struct GameFilter {
+    #[structopt(long)]
+    pub game_id: Vec<u64>,
+    /// Only games with all of these tags
+    #[structopt(long)]
+    pub tag: Vec<String>,
+    #[structopt(long)]
+    pub site: Vec<AvailableWebsite>,
+    /// YYYY-MM-DD
+    #[structopt(long, parse(try_from_str = parse_date))]
+    pub published_after: Option<time::Date>,
+    /// YYYY-MM-DD
+    #[structopt(long, parse(try_from_str = parse_date))]
+    pub published_before: Option<time::Date>,
+    #[structopt(long)]
+    pub min_viewed: Option<u128>,
+    #[structopt(long)]
+    pub max_viewed: Option<u128>,
+}
+impl GameFilter {
+    pub fn matches(&self, game: &crate::saved::GameTextInformation) -> bool {
+        use std::str::FromStr;
+        (self.game_id.len() == 0 || self.game_id.contains(&game.id))
+            && self.tag.iter().all(|x| game.tags.contains(x))
+            && (self.site.len() == 0
+                || AvailableWebsite::from_str(&game.website)
+                    .map_or(false, |x| self.site.contains(&x)))
+            && self
+                .published_after
+                .map_or(true, |x| game.published.date() >= x)
+            && self
+                .published_before
+                .map_or(true, |x| game.published.date() <= x)
+            && self.min_viewed.map_or(true, |x| game.viewed >= x)
+            && self.max_viewed.map_or(true, |x| game.viewed <= x)
+    }
+}
 #[derive(PartialEq, Eq, Hash, Clone, Copy)]
 pub enum AvailableWebsite {
     KKGal,
+    Liuli,
 }
 impl AvailableWebsite {
-    pub fn to_struct(&self) -> Box<dyn crate::websites::GalgameWebsite> {
+    pub const ALL: [Self; 2] = [Self::KKGal, Self::Liuli];
+    pub fn to_struct(
+        &self,
+        settings: crate::config::SiteSettings,
+    ) -> Box<dyn crate::websites::GalgameWebsite> {
         match self {
-            Self::KKGal => Box::new(crate::websites::kkgal::KKGal {}),
+            Self::KKGal => Box::new(crate::websites::kkgal::KKGal { settings }),
+            Self::Liuli => Box::new(crate::websites::liuli::Liuli { settings }),
+        }
+    }
+    /// Name used on the command line, in the config file and in the database
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::KKGal => "kkgal",
+            Self::Liuli => "liuli",
         }
     }
 }
@@ -80,7 +265,88 @@ impl std::str::FromStr for AvailableWebsite {
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         match s {
             "kkgal" => Ok(Self::KKGal),
+            "liuli" => Ok(Self::Liuli),
             _ => Err("Unknown website"),
         }
     }
 }
+#[derive(Clone, Copy)]
+pub enum AvailableStorage {
+    Legacy,
+    Sqlite,
+}
+impl AvailableStorage {
+    pub fn to_struct(
+        &self,
+        arguments: &ApplicationMainEntry,
+        log_client: &crate::log::LoggingClient,
+    ) -> Result<Box<dyn crate::storage::Storage>, crate::error::Error> {
+        Ok(match self {
+            Self::Legacy => Box::new(crate::storage::legacy::LegacyStorage::open(
+                &arguments.json_database_location,
+                &arguments.binary_database_location,
+                arguments.backup_count,
+                log_client,
+            )),
+            Self::Sqlite => Box::new(crate::storage::sqlite::SqliteStorage::open(
+                &arguments.sqlite_database_location,
+                arguments.backup_count,
+            )?),
+        })
+    }
+    pub fn files<'a>(&self, arguments: &'a ApplicationMainEntry) -> Vec<&'a str> {
+        match self {
+            Self::Legacy => vec![
+                &arguments.json_database_location,
+                &arguments.binary_database_location,
+            ],
+            Self::Sqlite => vec![&arguments.sqlite_database_location],
+        }
+    }
+}
+impl std::str::FromStr for AvailableStorage {
+    type Err = &'static str;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "legacy" => Ok(Self::Legacy),
+            "sqlite" => Ok(Self::Sqlite),
+            _ => Err("Unknown storage"),
+        }
+    }
+}
+#[derive(Clone, Copy)]
+pub enum AvailableTableFormat {
+    Csv,
+    Ndjson,
+    Sqlite,
+}
+impl AvailableTableFormat {
+    pub fn write(&self, tables: &crate::tables::Tables, location: &str) -> Result<(), String> {
+        match self {
+            Self::Csv => tables.write_csv(location),
+            Self::Ndjson => tables.write_ndjson(location),
+            Self::Sqlite => tables.write_sqlite(location),
+        }
+    }
+}
+impl std::str::FromStr for AvailableTableFormat {
+    type Err = &'static str;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(Self::Csv),
+            "ndjson" => Ok(Self::Ndjson),
+            "sqlite" => Ok(Self::Sqlite),
+            _ => Err("Unknown table format"),
+        }
+    }
+}
+fn parse_date(s: &str) -> Result<time::Date, String> {
+    time::Date::parse(s, "%F").map_err(|x| x.to_string())
+}
+fn parse_rate(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(i) if i > 0.0 && i.is_finite() => Ok(i),
+        Ok(_) => Err(String::from("Rate must be a positive number")),
+        Err(i) => Err(i.to_string()),
+    }
+}
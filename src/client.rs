@@ -0,0 +1,171 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+/// How hard a single host may be hit.
+#[derive(Clone, Copy)]
+pub struct Limits {
+    /// Requests per second, with bursts of up to one second worth of requests
+    pub requests_per_second: Option<f64>,
+    /// Requests which may run at the same time, bodies included
+    pub max_concurrency: Option<usize>,
+}
+/// An `isahc::HttpClient` which applies `Limits` to each host separately. Each website has its
+/// own client for its proxy and user agent, but all of them share one `Limiter`, so the limits
+/// of a host hold whichever websites and jobs send the requests.
+pub struct HttpClient {
+    client: isahc::HttpClient,
+    limits: Limits,
+    limiter: Arc<Limiter>,
+}
+/// The hosts requested so far. A host gets the limits of the first client which sends it a
+/// request, e.g. an image host shared by two websites gets the limits of one of them.
+#[derive(Default)]
+pub struct Limiter {
+    hosts: Mutex<HashMap<String, Arc<Host>>>,
+}
+struct Host {
+    bucket: Option<Mutex<Bucket>>,
+    connections: Option<Arc<tokio::sync::Semaphore>>,
+}
+/// A token bucket refilled at `rate` tokens per second. Tokens may go below zero, which makes
+/// the requests queue up behind each other.
+struct Bucket {
+    rate: f64,
+    tokens: f64,
+    updated: std::time::Instant,
+}
+impl Bucket {
+    /// How long to wait before sending a request, taking its token now.
+    fn take(&mut self) -> Option<std::time::Duration> {
+        let now = std::time::Instant::now();
+        let capacity = self.rate.max(1.0);
+        self.tokens = (self.tokens + (now - self.updated).as_secs_f64() * self.rate).min(capacity);
+        self.updated = now;
+        self.tokens -= 1.0;
+        if self.tokens < 0.0 {
+            Some(std::time::Duration::from_secs_f64(-self.tokens / self.rate))
+        } else {
+            None
+        }
+    }
+}
+impl HttpClient {
+    pub fn new(client: isahc::HttpClient, limits: Limits, limiter: Arc<Limiter>) -> Self {
+        Self {
+            client,
+            limits,
+            limiter,
+        }
+    }
+    fn host(&self, uri: &isahc::http::Uri) -> Arc<Host> {
+        let limits = self.limits;
+        self.limiter
+            .hosts
+            .lock()
+            .unwrap()
+            .entry(uri.authority().map_or(String::new(), |x| x.to_string()))
+            .or_insert_with(|| {
+                Arc::new(Host {
+                    bucket: limits.requests_per_second.map(|x| {
+                        Mutex::new(Bucket {
+                            rate: x,
+                            tokens: x.max(1.0),
+                            updated: std::time::Instant::now(),
+                        })
+                    }),
+                    connections: limits
+                        .max_concurrency
+                        .map(|x| Arc::new(tokio::sync::Semaphore::new(x))),
+                })
+            })
+            .clone()
+    }
+    /// Waits for the limits of the host, then sends the request. The request keeps counting
+    /// against the concurrency limit until the body of the response is dropped.
+    pub async fn send_async(
+        &self,
+        request: isahc::http::Request<()>,
+    ) -> Result<isahc::http::Response<isahc::Body>, isahc::Error> {
+        let host = self.host(request.uri());
+        let permit = match &host.connections {
+            Some(i) => Some(i.clone().acquire_owned().await),
+            None => None,
+        };
+        let delay = host.bucket.as_ref().and_then(|x| x.lock().unwrap().take());
+        if let Some(i) = delay {
+            tokio::time::sleep(i).await;
+        }
+        let response = self.client.send_async(request).await?;
+        Ok(match permit {
+            Some(permit) => response.map(|body| {
+                let length = body.len();
+                let body = PermittedBody {
+                    body,
+                    _permit: permit,
+                };
+                match length {
+                    Some(i) => isahc::Body::from_reader_sized(body, i),
+                    None => isahc::Body::from_reader(body),
+                }
+            }),
+            None => response,
+        })
+    }
+}
+/// A response body which holds on to its slot of the concurrency limit.
+struct PermittedBody {
+    body: isahc::Body,
+    _permit: tokio::sync::OwnedSemaphorePermit,
+}
+impl futures::io::AsyncRead for PermittedBody {
+    fn poll_read(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut [u8],
+    ) -> std::task::Poll<std::io::Result<usize>> {
+        std::pin::Pin::new(&mut self.body).poll_read(cx, buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{HttpClient, Limiter, Limits};
+    use std::sync::Arc;
+    fn client(limiter: &Arc<Limiter>, requests_per_second: f64) -> HttpClient {
+        HttpClient::new(
+            isahc::HttpClient::new().unwrap(),
+            Limits {
+                requests_per_second: Some(requests_per_second),
+                max_concurrency: Some(1),
+            },
+            limiter.clone(),
+        )
+    }
+    #[test]
+    fn shares_hosts_across_clients() {
+        let limiter = Arc::new(Limiter::default());
+        let (first, second) = (client(&limiter, 1.0), client(&limiter, 5.0));
+        let uri: isahc::http::Uri = "https://images.example.com/1.png".parse().unwrap();
+        let host = second.host(&uri);
+        assert!(Arc::ptr_eq(&host, &first.host(&uri)));
+        assert_eq!(host.bucket.as_ref().unwrap().lock().unwrap().rate, 5.0);
+        let other: isahc::http::Uri = "https://example.com/".parse().unwrap();
+        assert!(!Arc::ptr_eq(&host, &first.host(&other)));
+        // Clients of another limiter don't count against it
+        let separate = client(&Arc::new(Limiter::default()), 1.0);
+        assert!(!Arc::ptr_eq(&host, &separate.host(&uri)));
+    }
+    #[test]
+    fn queues_requests_over_the_rate() {
+        let mut bucket = super::Bucket {
+            rate: 2.0,
+            tokens: 2.0,
+            updated: std::time::Instant::now(),
+        };
+        assert!(bucket.take().is_none());
+        assert!(bucket.take().is_none());
+        let first = bucket.take().unwrap();
+        let second = bucket.take().unwrap();
+        assert!(first > std::time::Duration::from_millis(400));
+        assert!(second > first + std::time::Duration::from_millis(400));
+    }
+}
@@ -0,0 +1,308 @@
+use crate::error::Error;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+/// Read when `--config` isn't given, if it exists
+pub const DEFAULT_LOCATION: &str = "bishojo.toml";
+/// Environment variables read by older versions, and what replaced them
+pub const OBSOLETE_VARIABLES: [(&str, &str); 4] = [
+    ("KKGAL_USE_DIRECT", "direct = true"),
+    ("KKGAL_DOWN_NO_PROXY", "download-direct = true"),
+    ("KKGAL_DOWN_PROXY", "download-proxy"),
+    ("KKGAL_AVATAR_SLEEP_SEC", "sleep"),
+];
+/// Settings shared by the config file, its profiles and the command line. Unset ones are left
+/// to the next of them, then to the defaults.
+#[derive(Serialize, Deserialize, Default)]
+#[serde(rename_all = "kebab-case")]
+pub struct Settings {
+    pub proxy: Option<String>,
+    pub user_agent: Option<String>,
+    pub timeout: Option<u64>,
+    pub thread_limit: Option<usize>,
+    pub retry: Option<usize>,
+    pub retry_delay: Option<u64>,
+    pub retry_max_delay: Option<u64>,
+    pub retry_on: Option<Vec<crate::retry::RetryClass>>,
+    pub rate_limit: Option<f64>,
+    pub max_concurrency: Option<usize>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub sites: BTreeMap<String, SiteSettings>,
+}
+/// Settings of a single website, under `[sites.<name>]`.
+#[derive(Serialize, Deserialize, Default, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub struct SiteSettings {
+    pub proxy: Option<String>,
+    /// Proxy of game downloads (kkgal)
+    pub download_proxy: Option<String>,
+    /// Download games without any proxy, even if `download-proxy` is set (kkgal)
+    pub download_direct: Option<bool>,
+    /// Resolve download links when fetching games, instead of storing the redirecting ones (kkgal)
+    pub direct: Option<bool>,
+    /// Seconds to wait after each avatar, on top of the rate limit
+    pub sleep: Option<u64>,
+    pub rate_limit: Option<f64>,
+    pub max_concurrency: Option<usize>,
+    pub user_agent: Option<String>,
+}
+/// The config file: settings, then named profiles of settings picked with `--profile`.
+#[derive(Deserialize, Default)]
+struct File {
+    #[serde(flatten)]
+    settings: Settings,
+    #[serde(default)]
+    profiles: BTreeMap<String, Settings>,
+}
+impl Settings {
+    /// Settings of `over` replace the ones of `self`.
+    fn merge(mut self, over: Settings) -> Settings {
+        for (name, site) in over.sites {
+            let merged = match self.sites.remove(&name) {
+                Some(i) => i.merge(site),
+                None => site,
+            };
+            self.sites.insert(name, merged);
+        }
+        Settings {
+            proxy: over.proxy.or(self.proxy),
+            user_agent: over.user_agent.or(self.user_agent),
+            timeout: over.timeout.or(self.timeout),
+            thread_limit: over.thread_limit.or(self.thread_limit),
+            retry: over.retry.or(self.retry),
+            retry_delay: over.retry_delay.or(self.retry_delay),
+            retry_max_delay: over.retry_max_delay.or(self.retry_max_delay),
+            retry_on: over.retry_on.or(self.retry_on),
+            rate_limit: over.rate_limit.or(self.rate_limit),
+            max_concurrency: over.max_concurrency.or(self.max_concurrency),
+            sites: self.sites,
+        }
+    }
+    /// Reads the config file and the selected profile. Settings given on the command line replace
+    /// the ones of both.
+    pub fn load(arguments: &crate::cli::ApplicationMainEntry) -> Result<Self, Error> {
+        let file = match &arguments.config {
+            Some(i) => read(i)?,
+            None if std::path::Path::new(DEFAULT_LOCATION).is_file() => read(DEFAULT_LOCATION)?,
+            None => File::default(),
+        };
+        let mut settings = Self::defaults().merge(file.settings);
+        if let Some(i) = &arguments.profile {
+            let mut profiles = file.profiles;
+            let profile = profiles
+                .remove(i)
+                .ok_or(Error::Config(format!("Profile {} does not exist", i)))?;
+            settings = settings.merge(profile);
+        }
+        let settings = settings.merge(Settings {
+            proxy: arguments.proxy.clone(),
+            user_agent: arguments.user_agent.clone(),
+            timeout: arguments.timeout,
+            thread_limit: arguments.thread_limit,
+            retry: arguments.retry.retry,
+            retry_delay: arguments.retry.retry_delay,
+            retry_max_delay: arguments.retry.retry_max_delay,
+            retry_on: arguments.retry.retry_on.clone(),
+            rate_limit: arguments.rate_limit,
+            max_concurrency: arguments.max_concurrency,
+            sites: BTreeMap::new(),
+        });
+        for name in settings.sites.keys() {
+            name.parse::<crate::cli::AvailableWebsite>()
+                .map_err(|x| Error::Config(format!("{}: {}", x, name)))?;
+        }
+        for site in &crate::cli::AvailableWebsite::ALL {
+            let name = site.name();
+            let site = settings.site(*site);
+            if site.rate_limit.map_or(false, |x| x <= 0.0 || !x.is_finite()) {
+                return Err(Error::Config(format!(
+                    "rate-limit of {} must be positive",
+                    name
+                )));
+            }
+            for i in site
+                .proxy
+                .iter()
+                .chain(&site.download_proxy)
+            {
+                i.parse::<isahc::http::Uri>()
+                    .map_err(|x| Error::Config(format!("Proxy of {}: {}", name, x)))?;
+            }
+            if let Some(i) = &site.user_agent {
+                isahc::http::HeaderValue::from_str(i)
+                    .map_err(|x| Error::Config(format!("User agent of {}: {}", name, x)))?;
+            }
+        }
+        Ok(settings)
+    }
+    fn defaults() -> Self {
+        Settings {
+            user_agent: Some(String::from(
+                "Mozilla/5.0 (Windows NT 10.0; Win64; x64; rv:53.0) Gecko/20100101 Firefox/53.0",
+            )),
+            timeout: Some(20),
+            retry: Some(0),
+            retry_delay: Some(1000),
+            retry_max_delay: Some(60000),
+            retry_on: Some(vec![
+                crate::retry::RetryClass::Transport,
+                crate::retry::RetryClass::Http,
+                crate::retry::RetryClass::Image,
+                crate::retry::RetryClass::Challenge,
+            ]),
+            ..Settings::default()
+        }
+    }
+    /// The retry policy of the website, with its `--site-retry` settings applied.
+    pub fn retry_policy(
+        &self,
+        site: crate::cli::AvailableWebsite,
+        site_retry: &[crate::retry::SiteRetryPolicy],
+    ) -> crate::retry::RetryPolicy {
+        let policy = crate::retry::RetryPolicy {
+            retries: self.retry.unwrap_or(0),
+            delay: std::time::Duration::from_millis(self.retry_delay.unwrap_or(0)),
+            max_delay: std::time::Duration::from_millis(self.retry_max_delay.unwrap_or(0)),
+            retry_on: self.retry_on.clone().unwrap_or_default(),
+        };
+        site_retry
+            .iter()
+            .filter(|x| x.site == site)
+            .fold(policy, |policy, x| x.apply(policy))
+    }
+    /// Settings of the website, with the ones it doesn't set taken from the global ones.
+    pub fn site(&self, site: crate::cli::AvailableWebsite) -> SiteSettings {
+        let site = self.sites.get(site.name()).cloned().unwrap_or_default();
+        SiteSettings {
+            proxy: site.proxy.or_else(|| self.proxy.clone()),
+            user_agent: site.user_agent.or_else(|| self.user_agent.clone()),
+            rate_limit: site.rate_limit.or(self.rate_limit),
+            max_concurrency: site.max_concurrency.or(self.max_concurrency),
+            ..site
+        }
+    }
+}
+impl SiteSettings {
+    fn merge(self, over: SiteSettings) -> SiteSettings {
+        SiteSettings {
+            proxy: over.proxy.or(self.proxy),
+            download_proxy: over.download_proxy.or(self.download_proxy),
+            download_direct: over.download_direct.or(self.download_direct),
+            direct: over.direct.or(self.direct),
+            sleep: over.sleep.or(self.sleep),
+            rate_limit: over.rate_limit.or(self.rate_limit),
+            max_concurrency: over.max_concurrency.or(self.max_concurrency),
+            user_agent: over.user_agent.or(self.user_agent),
+        }
+    }
+}
+fn read(location: &str) -> Result<File, Error> {
+    let content = std::fs::read_to_string(location)
+        .map_err(|x| Error::Config(format!("{}: {}", location, x)))?;
+    toml::from_str(&content).map_err(|x| Error::Config(format!("{}: {}", location, x)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Settings;
+    use crate::cli::{ApplicationMainEntry, AvailableWebsite};
+    use structopt::StructOpt;
+    const FILE: &str = r#"
+proxy = "http://127.0.0.1:8080"
+timeout = 30
+rate-limit = 2.0
+
+[sites.kkgal]
+download-proxy = "http://127.0.0.1:8118"
+download-direct = true
+sleep = 5
+
+[sites.liuli]
+proxy = "socks5h://127.0.0.1:1080"
+
+[profiles.fast]
+rate-limit = 10.0
+retry = 3
+
+[profiles.fast.sites.kkgal]
+download-direct = false
+sleep = 0
+"#;
+    /// Loads `file` with the given arguments before the subcommand.
+    fn load(name: &str, file: &str, arguments: &[&str]) -> Result<Settings, crate::error::Error> {
+        let location = std::env::temp_dir().join(format!("bishojo-test-{}.toml", name));
+        std::fs::write(&location, file).unwrap();
+        let location = location.to_str().unwrap().to_string();
+        let arguments = ApplicationMainEntry::from_iter(
+            ["bishojo", "--config", &location]
+                .iter()
+                .chain(arguments)
+                .chain(&["config", "show"]),
+        );
+        let settings = Settings::load(&arguments);
+        std::fs::remove_file(&location).unwrap();
+        settings
+    }
+    #[test]
+    fn applies_file_over_defaults() {
+        let settings = load("defaults", FILE, &[]).unwrap();
+        assert_eq!(settings.timeout, Some(30));
+        assert_eq!(settings.retry_delay, Some(1000));
+        assert_eq!(settings.retry, Some(0));
+        let kkgal = settings.site(AvailableWebsite::KKGal);
+        assert_eq!(kkgal.proxy.as_deref(), Some("http://127.0.0.1:8080"));
+        assert_eq!(
+            kkgal.download_proxy.as_deref(),
+            Some("http://127.0.0.1:8118")
+        );
+        assert_eq!(kkgal.download_direct, Some(true));
+        assert_eq!(kkgal.sleep, Some(5));
+        assert_eq!(kkgal.rate_limit, Some(2.0));
+        let liuli = settings.site(AvailableWebsite::Liuli);
+        assert_eq!(liuli.proxy.as_deref(), Some("socks5h://127.0.0.1:1080"));
+        assert!(liuli.user_agent.unwrap().starts_with("Mozilla/5.0"));
+    }
+    #[test]
+    fn applies_profile_then_command_line() {
+        let settings = load("profile", FILE, &["--profile", "fast", "--timeout", "5"]).unwrap();
+        assert_eq!(settings.timeout, Some(5));
+        assert_eq!(settings.retry, Some(3));
+        assert_eq!(settings.rate_limit, Some(10.0));
+        // Site settings are merged one by one
+        let kkgal = settings.site(AvailableWebsite::KKGal);
+        assert_eq!(kkgal.sleep, Some(0));
+        assert_eq!(kkgal.download_direct, Some(false));
+        assert_eq!(
+            kkgal.download_proxy.as_deref(),
+            Some("http://127.0.0.1:8118")
+        );
+        let settings = load("proxy", FILE, &["--proxy", "http://proxy:3128"]).unwrap();
+        assert_eq!(
+            settings.site(AvailableWebsite::KKGal).proxy.as_deref(),
+            Some("http://proxy:3128")
+        );
+        // A site's own proxy still wins over the global one
+        assert_eq!(
+            settings.site(AvailableWebsite::Liuli).proxy.as_deref(),
+            Some("socks5h://127.0.0.1:1080")
+        );
+    }
+    #[test]
+    fn rejects_invalid_settings() {
+        for (name, file, arguments) in [
+            ("missing-profile", FILE, &["--profile", "slow"][..]),
+            ("unknown-site", "[sites.other]\nsleep = 1", &[]),
+            ("proxy", "[sites.kkgal]\nproxy = \"http://[::1\"", &[]),
+            (
+                "download-proxy",
+                "[sites.kkgal]\ndownload-proxy = \"a b\"",
+                &[],
+            ),
+            ("rate-limit", "rate-limit = 0.0", &[]),
+            ("user-agent", "user-agent = \"a\\nb\"", &[]),
+            ("syntax", "proxy = ", &[]),
+        ] {
+            let error = load(name, file, arguments).err().expect(name);
+            assert_eq!(error.exit_code(), 2, "{}", name);
+        }
+    }
+}
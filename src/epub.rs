@@ -0,0 +1,543 @@
+use crate::html::escape;
+use crate::templates::is_safe_url;
+use crate::{storage, templates};
+use std::collections::{BTreeSet, HashMap};
+use std::io::Write;
+struct Chapter {
+    title: String,
+    /// 1 for a game, 2 for its sections
+    level: usize,
+    body: String,
+}
+/// An EPUB 3 book made of one or more games. It also carries an NCX table of contents for older
+/// readers.
+pub struct Book<'a> {
+    title: String,
+    identifier: String,
+    chapters: Vec<Chapter>,
+    /// Offline image file name => blob key
+    offline_images: &'a HashMap<String, &'a str>,
+    /// Offline images referenced by the chapters
+    images: BTreeSet<String>,
+}
+impl<'a> Book<'a> {
+    pub fn new(
+        title: String,
+        identifier: String,
+        offline_images: &'a HashMap<String, &'a str>,
+    ) -> Self {
+        Self {
+            title,
+            identifier,
+            chapters: Vec::new(),
+            offline_images,
+            images: BTreeSet::new(),
+        }
+    }
+    /// Adds a chapter for the game, with the titled paragraphs and the comments as sections.
+    pub fn add_game(&mut self, game: &templates::GamePage) {
+        let mut body = format!(
+            "<h1>{}</h1>\n<p>id: {}@{} | published at {} | {} viewed</p>\n<p>Tags: {}</p>\n",
+            escape(&game.name),
+            game.id,
+            escape(&game.website),
+            escape(&game.published),
+            game.viewed,
+            escape(
+                &game
+                    .tags
+                    .iter()
+                    .map(|x| x.name.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )
+        );
+        if game.files.len() != 0 {
+            body.push_str(&format!(
+                "<h2>Downloads</h2>\n<ul>\n{}\n</ul>\n",
+                game.files
+                    .iter()
+                    .map(|x| {
+                        // Links which could run scripts are left out, the name still tells which
+                        // file it was
+                        let name = if is_safe_url(&x.link) {
+                            format!("<a href=\"{}\">{}</a>", escape(&x.link), escape(&x.name))
+                        } else {
+                            escape(&x.name)
+                        };
+                        format!(
+                            "<li>{} {}</li>",
+                            name,
+                            escape(x.size.as_deref().unwrap_or(""))
+                        )
+                    })
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            ));
+        }
+        self.chapters.push(Chapter {
+            title: game.name.clone(),
+            level: 1,
+            body,
+        });
+        for paragraph in &game.paragraphs {
+            let content = self.content(&paragraph.content);
+            match &paragraph.title {
+                Some(title) => self.chapters.push(Chapter {
+                    title: title.clone(),
+                    level: 2,
+                    body: format!("<h2>{}</h2>\n{}", escape(title), content),
+                }),
+                // Untitled paragraphs continue the previous section
+                None => self.chapters.last_mut().unwrap().body.push_str(&content),
+            }
+        }
+        if game.comments.len() != 0 {
+            let comments: Vec<String> = game.comments.iter().map(|x| self.comment(x)).collect();
+            self.chapters.push(Chapter {
+                title: String::from("Comments"),
+                level: 2,
+                body: format!("<h2>Comments</h2>\n{}", comments.join("\n")),
+            });
+        }
+    }
+    pub fn is_empty(&self) -> bool {
+        self.chapters.len() == 0
+    }
+    fn content(&mut self, content: &[templates::Content]) -> String {
+        let mut rendered = String::new();
+        for i in content {
+            match &i.image {
+                Some(link) => match link
+                    .strip_prefix("imgs/")
+                    .filter(|x| self.offline_images.contains_key(*x))
+                {
+                    Some(file) => {
+                        self.images.insert(file.to_string());
+                        rendered.push_str(&format!(
+                            "<p><img src=\"imgs/{}\" alt=\"image\"/></p>\n",
+                            escape(file)
+                        ));
+                    }
+                    // Readers won't download anything, so only link to the image
+                    None if is_safe_url(link) => rendered
+                        .push_str(&format!("<p><a href=\"{}\">image</a></p>\n", escape(link))),
+                    None => (),
+                },
+                None => rendered.push_str(&format!(
+                    "<p>{}</p>\n",
+                    i.lines
+                        .iter()
+                        .map(|x| escape(x))
+                        .collect::<Vec<_>>()
+                        .join("<br/>")
+                )),
+            }
+        }
+        rendered
+    }
+    fn comment(&mut self, comment: &templates::Comment) -> String {
+        let content = self.content(&comment.content);
+        let replies: Vec<String> = comment.replies.iter().map(|x| self.comment(x)).collect();
+        format!(
+            "<blockquote>\n<p><b>{}</b> said on {}:</p>\n{}{}</blockquote>\n",
+            escape(&comment.author),
+            escape(&comment.date),
+            content,
+            replies.join("")
+        )
+    }
+    pub fn write(&self, location: &str, storage: &dyn storage::Storage) -> Result<(), String> {
+        let file = std::fs::File::create(location).map_err(|x| x.to_string())?;
+        let mut zip = zip::ZipWriter::new(file);
+        let stored =
+            zip::write::FileOptions::default().compression_method(zip::CompressionMethod::Stored);
+        let deflated =
+            zip::write::FileOptions::default().compression_method(zip::CompressionMethod::Deflated);
+        let mut add = |name: &str, data: &[u8], options| -> Result<(), String> {
+            zip.start_file(name, options).map_err(|x| x.to_string())?;
+            zip.write_all(data).map_err(|x| x.to_string())
+        };
+        // Has to be the first file, uncompressed
+        add("mimetype", b"application/epub+zip", stored)?;
+        add(
+            "META-INF/container.xml",
+            br#"<?xml version="1.0" encoding="utf-8"?>
+<container version="1.0" xmlns="urn:oasis:names:tc:opendocument:xmlns:container">
+    <rootfiles>
+        <rootfile full-path="OEBPS/content.opf" media-type="application/oebps-package+xml"/>
+    </rootfiles>
+</container>"#,
+            deflated,
+        )?;
+        add("OEBPS/content.opf", self.package().as_bytes(), deflated)?;
+        add("OEBPS/nav.xhtml", self.navigation().as_bytes(), deflated)?;
+        add("OEBPS/toc.ncx", self.ncx().as_bytes(), deflated)?;
+        for (position, chapter) in self.chapters.iter().enumerate() {
+            add(
+                &format!("OEBPS/chapter{}.xhtml", position),
+                xhtml(&chapter.title, &chapter.body).as_bytes(),
+                deflated,
+            )?;
+        }
+        for image in &self.images {
+            let key = self.offline_images[image];
+            let data = storage
+                .get_blob(key)
+                .map_err(|x| x.to_string())?
+                .ok_or(format!("Unable to find {} in database", key))?;
+            add(&format!("OEBPS/imgs/{}", image), &data, stored)?;
+        }
+        drop(add);
+        zip.finish().map_err(|x| x.to_string())?;
+        Ok(())
+    }
+    fn package(&self) -> String {
+        let mut manifest = vec![
+            String::from(
+                r#"<item id="nav" href="nav.xhtml" media-type="application/xhtml+xml" properties="nav"/>"#,
+            ),
+            String::from(
+                r#"<item id="ncx" href="toc.ncx" media-type="application/x-dtbncx+xml"/>"#,
+            ),
+        ];
+        let mut spine = Vec::new();
+        for position in 0..self.chapters.len() {
+            manifest.push(format!(
+                "<item id=\"chapter{}\" href=\"chapter{}.xhtml\" media-type=\"application/xhtml+xml\"/>",
+                position, position
+            ));
+            spine.push(format!("<itemref idref=\"chapter{}\"/>", position));
+        }
+        for (position, image) in self.images.iter().enumerate() {
+            manifest.push(format!(
+                "<item id=\"image{}\" href=\"imgs/{}\" media-type=\"{}\"/>",
+                position,
+                escape(image),
+                crate::images::from_file_name(image)
+            ));
+        }
+        format!(
+            r#"<?xml version="1.0" encoding="utf-8"?>
+<package xmlns="http://www.idpf.org/2007/opf" version="3.0" unique-identifier="identifier">
+    <metadata xmlns:dc="http://purl.org/dc/elements/1.1/">
+        <dc:identifier id="identifier">{}</dc:identifier>
+        <dc:title>{}</dc:title>
+        <dc:language>und</dc:language>
+        <meta property="dcterms:modified">{}</meta>
+    </metadata>
+    <manifest>
+        {}
+    </manifest>
+    <spine toc="ncx">
+        {}
+    </spine>
+</package>"#,
+            escape(&self.identifier),
+            escape(&self.title),
+            time::OffsetDateTime::now_utc().format("%FT%H:%M:%SZ"),
+            manifest.join("\n        "),
+            spine.join("\n        ")
+        )
+    }
+    fn navigation(&self) -> String {
+        let mut list = String::new();
+        let mut sections_open = false;
+        for (position, chapter) in self.chapters.iter().enumerate() {
+            let link = format!(
+                "<a href=\"chapter{}.xhtml\">{}</a>",
+                position,
+                escape(&chapter.title)
+            );
+            if chapter.level == 1 {
+                if sections_open {
+                    list.push_str("</ol>");
+                    sections_open = false;
+                }
+                if position != 0 {
+                    list.push_str("</li>\n");
+                }
+                list.push_str(&format!("<li>{}", link));
+            } else {
+                if !sections_open {
+                    list.push_str("\n<ol>\n");
+                    sections_open = true;
+                }
+                list.push_str(&format!("<li>{}</li>\n", link));
+            }
+        }
+        if sections_open {
+            list.push_str("</ol>");
+        }
+        if self.chapters.len() != 0 {
+            list.push_str("</li>");
+        }
+        xhtml(
+            &self.title,
+            &format!(
+                "<nav epub:type=\"toc\" id=\"toc\">\n<h1>{}</h1>\n<ol>\n{}\n</ol>\n</nav>",
+                escape(&self.title),
+                list
+            ),
+        )
+    }
+    fn ncx(&self) -> String {
+        let mut points = String::new();
+        for (position, chapter) in self.chapters.iter().enumerate() {
+            let point = format!(
+                "<navPoint id=\"point{}\" playOrder=\"{}\"><navLabel><text>{}</text></navLabel><content src=\"chapter{}.xhtml\"/>",
+                position,
+                position + 1,
+                escape(&chapter.title),
+                position
+            );
+            if chapter.level == 1 {
+                if position != 0 {
+                    points.push_str("</navPoint>\n");
+                }
+                points.push_str(&format!("{}\n", point));
+            } else {
+                points.push_str(&format!("{}</navPoint>\n", point));
+            }
+        }
+        if self.chapters.len() != 0 {
+            points.push_str("</navPoint>");
+        }
+        format!(
+            r#"<?xml version="1.0" encoding="utf-8"?>
+<ncx xmlns="http://www.daisy.org/z3986/2005/ncx/" version="2005-1">
+    <head>
+        <meta name="dtb:uid" content="{}"/>
+    </head>
+    <docTitle><text>{}</text></docTitle>
+    <navMap>
+{}
+    </navMap>
+</ncx>"#,
+            escape(&self.identifier),
+            escape(&self.title),
+            points
+        )
+    }
+}
+fn xhtml(title: &str, body: &str) -> String {
+    format!(
+        r#"<?xml version="1.0" encoding="utf-8"?>
+<!DOCTYPE html>
+<html xmlns="http://www.w3.org/1999/xhtml" xmlns:epub="http://www.idpf.org/2007/ops">
+<head>
+    <meta charset="utf-8"/>
+    <title>{}</title>
+</head>
+<body>
+{}
+</body>
+</html>"#,
+        escape(title),
+        body
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Book;
+    use crate::storage::Storage;
+    use crate::templates::{Comment, Content, File, GamePage, Link, Paragraph};
+    use std::collections::HashMap;
+    const PNG: &[u8] = b"\x89PNG\r\n\x1A\n\0\0\0\0IEND\xAE\x42\x60\x82";
+    fn text(line: &str) -> Content {
+        Content {
+            lines: vec![line.to_string()],
+            image: None,
+            thumbnail: None,
+        }
+    }
+    fn image(link: &str) -> Content {
+        Content {
+            lines: Vec::new(),
+            image: Some(link.to_string()),
+            thumbnail: None,
+        }
+    }
+    fn comment(author: &str, replies: Vec<Comment>) -> Comment {
+        Comment {
+            avatar: String::new(),
+            avatar_thumbnail: None,
+            author: author.to_string(),
+            date: String::from("2020-01-01 0:00"),
+            content: vec![text("Nice")],
+            replies,
+        }
+    }
+    fn page(name: &str, paragraphs: Vec<Paragraph>, comments: Vec<Comment>) -> GamePage {
+        GamePage {
+            root: String::new(),
+            name: name.to_string(),
+            id: 1,
+            website: String::from("kkgal"),
+            website_href: String::new(),
+            published: String::from("2020-01-01 0:00"),
+            viewed: 0,
+            tags: vec![Link {
+                name: String::from("Romance"),
+                href: String::new(),
+            }],
+            paragraphs,
+            files: vec![
+                File {
+                    name: String::from("Game.zip"),
+                    link: String::from("https://example.com/game.zip"),
+                    size: Some(String::from("1 GB")),
+                },
+                File {
+                    name: String::from("Evil.zip"),
+                    link: String::from("javascript:alert(1)"),
+                    size: None,
+                },
+            ],
+            comments,
+        }
+    }
+    /// A book of a game with an untitled and a titled paragraph and comments, then of a game
+    /// with nothing but its name.
+    fn book<'a>(offline_images: &'a HashMap<String, &'a str>) -> Book<'a> {
+        let mut book = Book::new(
+            String::from("Games & more"),
+            String::from("urn:uuid:1"),
+            offline_images,
+        );
+        book.add_game(&page(
+            "First <Game>",
+            vec![
+                Paragraph {
+                    title: None,
+                    content: vec![text("Introduction")],
+                },
+                Paragraph {
+                    title: Some(String::from("Story")),
+                    content: vec![
+                        image("imgs/offline.png"),
+                        image("https://example.com/online.png"),
+                        image("data:image/svg+xml,<svg onload=alert(1)>"),
+                    ],
+                },
+            ],
+            vec![comment("A", vec![comment("B", Vec::new())])],
+        ));
+        book.add_game(&GamePage {
+            files: Vec::new(),
+            ..page("Second", Vec::new(), Vec::new())
+        });
+        book
+    }
+    #[test]
+    fn makes_chapters_of_games_and_sections() {
+        let offline_images = vec![(String::from("offline.png"), "https://a/1.png")]
+            .into_iter()
+            .collect();
+        let book = book(&offline_images);
+        let chapters: Vec<_> = book
+            .chapters
+            .iter()
+            .map(|x| (x.title.as_str(), x.level))
+            .collect();
+        assert_eq!(
+            chapters,
+            [
+                ("First <Game>", 1),
+                ("Story", 2),
+                ("Comments", 2),
+                ("Second", 1)
+            ]
+        );
+        assert!(book.chapters[0]
+            .body
+            .contains("<h1>First &lt;Game&gt;</h1>"));
+        assert!(book.chapters[0].body.contains("<p>Introduction</p>"));
+        assert!(
+            book.chapters[2].body.contains("<b>A</b>")
+                && book.chapters[2].body.contains("<b>B</b>")
+        );
+        assert!(!book.chapters[3].body.contains("Downloads"));
+        assert_eq!(book.images.iter().collect::<Vec<_>>(), ["offline.png"]);
+    }
+    #[test]
+    fn leaves_out_unsafe_links() {
+        let offline_images = HashMap::new();
+        let book = book(&offline_images);
+        assert!(book.chapters[0].body.contains(
+            "<li><a href=\"https://example.com/game.zip\">Game.zip</a> 1 GB</li>\n<li>Evil.zip </li>"
+        ));
+        // Without offline copies, images are only linked to
+        assert_eq!(
+            book.chapters[1].body,
+            "<h2>Story</h2>\n<p><a href=\"imgs/offline.png\">image</a></p>\n<p><a href=\"https://example.com/online.png\">image</a></p>\n"
+        );
+        assert!(book
+            .chapters
+            .iter()
+            .all(|x| !x.body.contains("javascript:") && !x.body.contains("data:")));
+    }
+    #[test]
+    fn nests_sections_in_the_tables_of_contents() {
+        let offline_images = HashMap::new();
+        let book = book(&offline_images);
+        assert!(book.navigation().contains(
+            "<ol>\n<li><a href=\"chapter0.xhtml\">First &lt;Game&gt;</a>\n<ol>\n\
+             <li><a href=\"chapter1.xhtml\">Story</a></li>\n\
+             <li><a href=\"chapter2.xhtml\">Comments</a></li>\n</ol></li>\n\
+             <li><a href=\"chapter3.xhtml\">Second</a></li>\n</ol>"
+        ));
+        assert!(book.ncx().contains(
+            "<navPoint id=\"point0\" playOrder=\"1\"><navLabel><text>First &lt;Game&gt;</text></navLabel><content src=\"chapter0.xhtml\"/>\n\
+             <navPoint id=\"point1\" playOrder=\"2\"><navLabel><text>Story</text></navLabel><content src=\"chapter1.xhtml\"/></navPoint>\n\
+             <navPoint id=\"point2\" playOrder=\"3\"><navLabel><text>Comments</text></navLabel><content src=\"chapter2.xhtml\"/></navPoint>\n\
+             </navPoint>\n\
+             <navPoint id=\"point3\" playOrder=\"4\"><navLabel><text>Second</text></navLabel><content src=\"chapter3.xhtml\"/>\n\
+             </navPoint>"
+        ));
+    }
+    #[test]
+    fn lists_chapters_and_images_in_the_package() {
+        let offline_images = vec![(String::from("offline.png"), "https://a/1.png")]
+            .into_iter()
+            .collect();
+        let book = book(&offline_images);
+        let package = book.package();
+        assert!(package.contains("<dc:title>Games &amp; more</dc:title>"));
+        assert!(package.contains(
+            r#"<item id="nav" href="nav.xhtml" media-type="application/xhtml+xml" properties="nav"/>"#
+        ));
+        assert!(package
+            .contains(r#"<item id="ncx" href="toc.ncx" media-type="application/x-dtbncx+xml"/>"#));
+        assert!(package.contains(
+            r#"<item id="chapter3" href="chapter3.xhtml" media-type="application/xhtml+xml"/>"#
+        ));
+        assert!(package
+            .contains(r#"<item id="image0" href="imgs/offline.png" media-type="image/png"/>"#));
+        assert_eq!(package.matches("<itemref ").count(), 4);
+    }
+    #[test]
+    fn writes_the_mimetype_first() {
+        let mut storage = crate::storage::sqlite::SqliteStorage::open(":memory:", 0).unwrap();
+        storage.insert_blob("https://a/1.png", PNG).unwrap();
+        let offline_images = vec![(String::from("offline.png"), "https://a/1.png")]
+            .into_iter()
+            .collect();
+        let location = std::env::temp_dir().join("bishojo-test.epub");
+        let location = location.to_str().unwrap();
+        book(&offline_images).write(location, &storage).unwrap();
+        let mut archive = zip::ZipArchive::new(std::fs::File::open(location).unwrap()).unwrap();
+        assert_eq!(archive.by_index(0).unwrap().name(), "mimetype");
+        assert_eq!(
+            archive.by_index(0).unwrap().compression(),
+            zip::CompressionMethod::Stored
+        );
+        assert_eq!(
+            archive.by_name("OEBPS/imgs/offline.png").unwrap().size(),
+            PNG.len() as u64
+        );
+        assert!(archive.by_name("OEBPS/chapter3.xhtml").is_ok());
+        std::fs::remove_file(location).unwrap();
+    }
+}
@@ -0,0 +1,217 @@
+/// Why an operation failed, so that retries, reporting and the exit code can tell a network
+/// timeout from a page which changed layout.
+#[derive(Debug)]
+pub enum Error {
+    /// The request couldn't be sent or the response couldn't be read
+    Transport(isahc::Error),
+    /// The server answered with an error status or something else than what was asked for
+    Response(ResponseError),
+    /// A downloaded image is an error page, was cut short or is an SVG image
+    InvalidImage {
+        url: String,
+        /// Type of the image according to its first bytes, `None` if it isn't one
+        detected: Option<&'static str>,
+        /// Why the body isn't an image, e.g. the start of the error page
+        message: String,
+    },
+    /// The page isn't laid out as expected
+    Parse {
+        url: String,
+        /// Where parsing stopped in the page, e.g. `tag 120 of 845`
+        location: String,
+        message: String,
+    },
+    /// The website served an anti-bot challenge which couldn't be passed
+    Challenge {
+        url: String,
+        message: String,
+    },
+    /// The link can't be downloaded by this program, e.g. a magnet link
+    Unsupported(String),
+    Io(std::io::Error),
+    /// The database couldn't be read or written
+    Storage(String),
+    /// The config file couldn't be read or has invalid settings
+    Config(String),
+}
+impl Error {
+    pub fn parse(url: &str, location: String, message: String) -> Self {
+        Self::Parse {
+            url: url.to_string(),
+            location,
+            message,
+        }
+    }
+    /// Whether trying again may succeed.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Self::Transport(i) => !matches!(
+                i,
+                isahc::Error::InvalidHttpFormat(_)
+                    | isahc::Error::BadClientCertificate(_)
+                    | isahc::Error::InvalidCredentials
+                    | isahc::Error::TooManyRedirects
+            ),
+            Self::Response(i) => i.is_retryable(),
+            // Error pages and truncated bodies are usually served by overloaded servers
+            Self::InvalidImage { .. } => true,
+            // The challenge may be passed next time, the other errors won't go away
+            Self::Challenge { .. } => true,
+            Self::Parse { .. }
+            | Self::Unsupported(_)
+            | Self::Io(_)
+            | Self::Storage(_)
+            | Self::Config(_) => false,
+        }
+    }
+    pub fn retry_after(&self) -> Option<std::time::Duration> {
+        match self {
+            Self::Response(i) => i.retry_after,
+            _ => None,
+        }
+    }
+    /// Short name of the variant, used in reports.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::Transport(_) => "transport",
+            Self::Response(_) => "http",
+            Self::InvalidImage { .. } => "image",
+            Self::Parse { .. } => "parse",
+            Self::Challenge { .. } => "challenge",
+            Self::Unsupported(_) => "unsupported",
+            Self::Io(_) => "io",
+            Self::Storage(_) => "storage",
+            Self::Config(_) => "config",
+        }
+    }
+    /// See the help of the main command. Higher codes are for errors which are more likely to
+    /// need a look.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Self::Config(_) => 2,
+            Self::Transport(_) => 3,
+            Self::Response(_) => 4,
+            Self::InvalidImage { .. } => 5,
+            Self::Unsupported(_) => 6,
+            Self::Challenge { .. } => 7,
+            Self::Parse { .. } => 8,
+            Self::Io(_) => 9,
+            Self::Storage(_) => 10,
+        }
+    }
+}
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Transport(i) => write!(f, "{}", i),
+            Self::Response(i) => write!(f, "{}", i),
+            Self::InvalidImage {
+                url,
+                detected: Some(i),
+                ..
+            } => write!(f, "Truncated {} from {}", i, url),
+            Self::InvalidImage { url, message, .. } => write!(f, "{} from {}", message, url),
+            Self::Parse {
+                url,
+                location,
+                message,
+            } => write!(f, "{} at {} of {}", message, location, url),
+            Self::Challenge { url, message } => write!(f, "Challenge from {}: {}", url, message),
+            Self::Unsupported(i) => write!(f, "{}", i),
+            Self::Io(i) => write!(f, "{}", i),
+            Self::Storage(i) => write!(f, "{}", i),
+            Self::Config(i) => write!(f, "{}", i),
+        }
+    }
+}
+impl From<isahc::Error> for Error {
+    fn from(error: isahc::Error) -> Self {
+        Self::Transport(error)
+    }
+}
+impl From<isahc::http::Error> for Error {
+    fn from(error: isahc::http::Error) -> Self {
+        Self::Transport(error.into())
+    }
+}
+impl From<std::io::Error> for Error {
+    fn from(error: std::io::Error) -> Self {
+        Self::Io(error)
+    }
+}
+impl From<rusqlite::Error> for Error {
+    fn from(error: rusqlite::Error) -> Self {
+        Self::Storage(error.to_string())
+    }
+}
+/// Bytes of the body kept in a `ResponseError`
+pub const SNIPPET_LENGTH: u64 = 256;
+/// A response which isn't what was asked for: an error status, or a page served in place of the
+/// content with a success status.
+#[derive(Debug)]
+pub struct ResponseError {
+    pub status: isahc::http::StatusCode,
+    pub url: String,
+    pub content_type: Option<String>,
+    /// Start of the body, which usually tells what went wrong
+    pub snippet: String,
+    /// How long the server asked to wait before trying again
+    pub retry_after: Option<std::time::Duration>,
+}
+impl ResponseError {
+    /// Client errors other than timeouts and rate limiting won't go away by asking again.
+    pub fn is_retryable(&self) -> bool {
+        !self.status.is_client_error()
+            || self.status == isahc::http::StatusCode::REQUEST_TIMEOUT
+            || self.status == isahc::http::StatusCode::TOO_MANY_REQUESTS
+    }
+}
+impl std::fmt::Display for ResponseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.status.is_success() {
+            write!(
+                f,
+                "Unexpected {} response from {}",
+                self.content_type.as_deref().unwrap_or("untyped"),
+                self.url
+            )?;
+        } else {
+            write!(f, "HTTP {} from {}", self.status, self.url)?;
+        }
+        if self.snippet.len() != 0 {
+            write!(f, ": {}", self.snippet)?;
+        }
+        Ok(())
+    }
+}
+/// Counts the errors jobs gave up on, by kind, for the report at the end of a run.
+#[derive(Default)]
+pub struct Failures {
+    counts: std::collections::BTreeMap<&'static str, usize>,
+    exit_code: i32,
+}
+impl Failures {
+    pub fn record(&mut self, error: &Error) {
+        *self.counts.entry(error.kind()).or_default() += 1;
+        self.exit_code = self.exit_code.max(error.exit_code());
+    }
+    /// e.g. `3 failed (2 transport, 1 parse)`, or `None` if nothing failed.
+    pub fn summary(&self) -> Option<String> {
+        if self.counts.len() == 0 {
+            return None;
+        }
+        Some(format!(
+            "{} failed ({})",
+            self.counts.values().sum::<usize>(),
+            self.counts
+                .iter()
+                .map(|x| format!("{} {}", x.1, x.0))
+                .collect::<Vec<_>>()
+                .join(", ")
+        ))
+    }
+    /// The code of the most serious error recorded, 0 if there was none.
+    pub fn exit_code(&self) -> i32 {
+        self.exit_code
+    }
+}
@@ -0,0 +1,26 @@
+/// Escapes text for html and xhtml content and attribute values. Control characters are left
+/// out, since XML doesn't allow them.
+pub fn escape(text: &str) -> String {
+    text.chars()
+        .filter(|x| !x.is_control() || *x == '\n' || *x == '\t')
+        .collect::<String>()
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::escape;
+    #[test]
+    fn escapes_markup_and_drops_control_characters() {
+        assert_eq!(
+            escape("<a href=\"x\">Tom & Jerry's</a>"),
+            "&lt;a href=&quot;x&quot;&gt;Tom &amp; Jerry&#39;s&lt;/a&gt;"
+        );
+        assert_eq!(escape("a\u{0}b\u{1b}c\nd\te"), "abc\nd\te");
+        assert_eq!(escape("&amp;"), "&amp;amp;");
+    }
+}
@@ -0,0 +1,167 @@
+/// Type given to blobs which aren't a known image format.
+pub const UNKNOWN_MIME_TYPE: &str = "application/octet-stream";
+/// Thumbnails fit in a square of this many pixels.
+const THUMBNAIL_SIZE: u32 = 640;
+/// Detects the image type from the first bytes of the data.
+pub fn sniff(data: &[u8]) -> Option<&'static str> {
+    if data.starts_with(&[0xFF, 0xD8, 0xFF]) {
+        Some("image/jpeg")
+    } else if data.starts_with(b"\x89PNG\r\n\x1A\n") {
+        Some("image/png")
+    } else if data.starts_with(b"GIF87a") || data.starts_with(b"GIF89a") {
+        Some("image/gif")
+    } else if data.len() >= 12 && data.starts_with(b"RIFF") && &data[8..12] == b"WEBP" {
+        Some("image/webp")
+    } else if data.starts_with(b"BM") && data.len() >= 26 {
+        Some("image/bmp")
+    } else {
+        None
+    }
+}
+/// SVG images may carry scripts, so they are told apart from error pages only to be rejected.
+fn is_svg(data: &[u8]) -> bool {
+    let head = String::from_utf8_lossy(&data[..data.len().min(1024)]).to_lowercase();
+    let head = head.trim_start_matches('\u{FEFF}').trim_start();
+    (head.starts_with("<?xml") || head.starts_with("<svg") || head.starts_with("<!--"))
+        && head.contains("<svg")
+}
+/// Returns the type of the image, or why the data isn't a complete one. Error pages served in
+/// place of an image, truncated bodies and SVG images are all rejected.
+pub fn validate(data: &[u8]) -> Result<&'static str, String> {
+    if is_svg(data) {
+        return Err("Refused SVG image".to_string());
+    }
+    let mime_type = sniff(data).ok_or_else(|| {
+        format!(
+            "Not an image: {}",
+            String::from_utf8_lossy(&data[..data.len().min(64)])
+                .split_whitespace()
+                .collect::<Vec<_>>()
+                .join(" ")
+        )
+    })?;
+    let complete = match mime_type {
+        // Some encoders pad the file after the end of image marker
+        "image/jpeg" => {
+            let end = data.len() - data.iter().rev().take_while(|x| **x == 0).count();
+            data[..end].ends_with(&[0xFF, 0xD9])
+        }
+        "image/png" => data[data.len().saturating_sub(12)..]
+            .windows(4)
+            .any(|x| x == b"IEND"),
+        "image/gif" => data.ends_with(&[0x3B]),
+        "image/webp" => {
+            u32::from_le_bytes([data[4], data[5], data[6], data[7]]) as usize + 8 <= data.len()
+        }
+        _ => u32::from_le_bytes([data[2], data[3], data[4], data[5]]) as usize <= data.len(),
+    };
+    if complete {
+        Ok(mime_type)
+    } else {
+        Err(format!("Truncated {} of {} bytes", mime_type, data.len()))
+    }
+}
+pub fn extension(mime_type: &str) -> &'static str {
+    match mime_type {
+        "image/jpeg" => "jpg",
+        "image/png" => "png",
+        "image/gif" => "gif",
+        "image/webp" => "webp",
+        "image/bmp" => "bmp",
+        _ => "bin",
+    }
+}
+/// The type of an image exported under `name`, see `extension`.
+pub fn from_file_name(name: &str) -> &'static str {
+    match name.rsplit('.').next() {
+        Some("jpg") => "image/jpeg",
+        Some("png") => "image/png",
+        Some("gif") => "image/gif",
+        Some("webp") => "image/webp",
+        Some("bmp") => "image/bmp",
+        _ => UNKNOWN_MIME_TYPE,
+    }
+}
+/// Downscales a JPEG or PNG image to fit the thumbnail size, in the same format. Other images,
+/// such as animated GIFs, and small ones are returned as is.
+pub fn thumbnail(data: &[u8], mime_type: &str) -> Result<Vec<u8>, String> {
+    let format = match mime_type {
+        "image/jpeg" => image::ImageOutputFormat::Jpeg(85),
+        "image/png" => image::ImageOutputFormat::Png,
+        _ => return Ok(data.to_vec()),
+    };
+    use image::GenericImageView;
+    let decoded = image::load_from_memory(data).map_err(|x| x.to_string())?;
+    if decoded.width() <= THUMBNAIL_SIZE && decoded.height() <= THUMBNAIL_SIZE {
+        return Ok(data.to_vec());
+    }
+    let mut encoded = Vec::new();
+    decoded
+        .thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE)
+        .write_to(&mut encoded, format)
+        .map_err(|x| x.to_string())?;
+    Ok(encoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{sniff, validate};
+    fn webp(length: u32, body: &[u8]) -> Vec<u8> {
+        let mut data = b"RIFF".to_vec();
+        data.extend_from_slice(&length.to_le_bytes());
+        data.extend_from_slice(b"WEBPVP8 ");
+        data.extend_from_slice(body);
+        data
+    }
+    fn bmp(size: u32, length: usize) -> Vec<u8> {
+        let mut data = b"BM".to_vec();
+        data.extend_from_slice(&size.to_le_bytes());
+        data.resize(length, 0);
+        data
+    }
+    #[test]
+    fn sniffs_magic_bytes() {
+        assert_eq!(sniff(b"\xFF\xD8\xFF\xE0\0\x10JFIF"), Some("image/jpeg"));
+        assert_eq!(sniff(b"\x89PNG\r\n\x1A\n\0\0\0\x0DIHDR"), Some("image/png"));
+        assert_eq!(sniff(b"GIF87a\x01\0"), Some("image/gif"));
+        assert_eq!(sniff(b"GIF89a\x01\0"), Some("image/gif"));
+        assert_eq!(sniff(&webp(4, b"")), Some("image/webp"));
+        assert_eq!(sniff(&bmp(26, 26)), Some("image/bmp"));
+        assert_eq!(sniff(b"<svg xmlns=\"\"></svg>"), None);
+        assert_eq!(sniff(b"<!DOCTYPE html><html><svg></svg></html>"), None);
+        assert_eq!(sniff(b"RIFF\0\0\0\0WAVE"), None);
+        assert_eq!(sniff(b"BM"), None);
+        assert_eq!(sniff(b""), None);
+    }
+    #[test]
+    fn rejects_truncated_images() {
+        assert!(validate(b"\xFF\xD8\xFF\xE0data\xFF\xD9").is_ok());
+        assert!(validate(b"\xFF\xD8\xFF\xE0data\xFF\xD9\0\0").is_ok());
+        assert!(validate(b"\xFF\xD8\xFF\xE0data").is_err());
+        assert!(validate(b"\x89PNG\r\n\x1A\ndata\0\0\0\0IEND\xAE\x42\x60\x82").is_ok());
+        assert!(validate(b"\x89PNG\r\n\x1A\ndata").is_err());
+        assert!(validate(b"GIF89adata\x3B").is_ok());
+        assert!(validate(b"GIF89adata").is_err());
+        assert!(validate(&webp(12, b"data")).is_ok());
+        assert!(validate(&webp(16, b"data")).is_err());
+        assert!(validate(&bmp(40, 40)).is_ok());
+        assert!(validate(&bmp(40, 30)).is_err());
+    }
+    #[test]
+    fn rejects_svg_images() {
+        for data in &[
+            &b"<svg onload=\"alert(1)\"><rect/></svg>"[..],
+            b"\xEF\xBB\xBF <?xml version=\"1.0\"?>\n<svg xmlns=\"\"></svg>",
+            b"<!-- Generator -->\n<svg></svg>",
+        ] {
+            assert_eq!(validate(data).unwrap_err(), "Refused SVG image");
+        }
+    }
+    #[test]
+    fn rejects_error_pages() {
+        assert_eq!(
+            validate(b"<html>\n  <title>404   Not Found</title>").unwrap_err(),
+            "Not an image: <html> <title>404 Not Found</title>"
+        );
+    }
+}
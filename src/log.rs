@@ -24,6 +24,7 @@ impl std::fmt::Display for LoggingLevel {
 ///
 /// This is used instead of traditional logging library because it's easier and more simple.
 /// Also, we don't need verbose output logs from libraries that we depends on.
+#[derive(Clone)]
 pub struct LoggingClient {
     start_time: std::sync::Arc<std::time::Instant>,
 }
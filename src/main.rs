@@ -1,8 +1,23 @@
 use structopt::StructOpt;
 mod cli;
+mod client;
+mod config;
+mod epub;
+mod error;
+mod html;
+mod images;
 mod log;
+mod manifest;
+mod markdown;
+mod retry;
 mod saved;
+mod search;
+mod server;
+mod storage;
+mod tables;
+mod templates;
 mod websites;
+use futures::future::FutureExt;
 use futures::stream::StreamExt;
 use isahc::config::Configurable;
 use std::str::FromStr;
@@ -14,104 +29,282 @@ async fn main() {
 async fn _main() {
     let arguments: cli::ApplicationMainEntry = cli::ApplicationMainEntry::from_args();
     let logging_client = log::LoggingClient::new();
-    logging_client.log(
-        log::LoggingLevel::Warning,
-        &format!(
-            "{} ver {} started.",
-            std::env!("CARGO_PKG_NAME"),
-            std::env!("CARGO_PKG_VERSION")
-        ),
-    );
-    let http_client = isahc::HttpClientBuilder::new()
-        .connect_timeout(std::time::Duration::from_secs(arguments.timeout))
-        .redirect_policy(isahc::config::RedirectPolicy::Limit(10))
-        .auto_referer()
-        .tcp_nodelay()
-        .proxy(arguments.proxy.map(|x| x.parse().unwrap()))
-        .max_connections(arguments.thread_limit.unwrap_or(0))
-        .default_header(
-            isahc::http::header::USER_AGENT,
-            "Mozilla/5.0 (Windows NT 10.0; Win64; x64; rv:53.0) Gecko/20100101 Firefox/53.0",
-        )
-        .build()
-        .unwrap();
-    let database = saved::load(
-        &arguments.json_database_location,
-        &arguments.binary_database_location,
-    );
-    let mut database = (
-        match database.0 {
-            Ok(i) => i,
-            Err(i) => {
+    let settings = match config::Settings::load(&arguments) {
+        Ok(i) => i,
+        Err(i) => {
+            logging_client.log(
+                log::LoggingLevel::Warning,
+                &format!("Unable to load config: {}", i),
+            );
+            std::process::exit(i.exit_code());
+        }
+    };
+    let subcommand = match &arguments.subcommand {
+        cli::ApplicationSubCommand::Config(cli::ConfigSubCommand::Show) => {
+            let effective = config::Settings {
+                sites: cli::AvailableWebsite::ALL
+                    .iter()
+                    .map(|x| (x.name().to_string(), settings.site(*x)))
+                    .collect(),
+                ..settings
+            };
+            print!("{}", toml::to_string(&effective).unwrap());
+            return;
+        }
+        cli::ApplicationSubCommand::Migrate => {
+            log_start(&logging_client);
+            if let Err(i) = migrate(&arguments, &logging_client) {
                 logging_client.log(
                     log::LoggingLevel::Warning,
-                    &format!("Unable to load text database: {}. Using new database.", i),
+                    &format!("Error while migrating: {}", i),
                 );
-                Vec::new()
+                std::process::exit(i.exit_code());
             }
-        },
-        match database.1 {
-            Ok(i) => i,
-            Err(i) => {
+            return;
+        }
+        cli::ApplicationSubCommand::Restore { timestamp } => {
+            log_start(&logging_client);
+            if let Err(i) = restore(&arguments, timestamp.as_deref(), &logging_client) {
                 logging_client.log(
                     log::LoggingLevel::Warning,
-                    &format!("Unable to load binary database: {}. Using new database.", i),
+                    &format!("Error while restoring: {}", i),
                 );
-                saved::GameBinaryDatabase(std::collections::HashMap::new())
+                std::process::exit(i.exit_code());
             }
-        },
+            return;
+        }
+        cli::ApplicationSubCommand::Database(i) => {
+            log_start(&logging_client);
+            i.clone()
+        }
+    };
+    let mut storage = match arguments.storage.to_struct(&arguments, &logging_client) {
+        Ok(i) => i,
+        Err(i) => {
+            logging_client.log(
+                log::LoggingLevel::Warning,
+                &format!("Unable to open database: {}", i),
+            );
+            std::process::exit(i.exit_code());
+        }
+    };
+    let mut database = match storage.load_games() {
+        Ok(i) => i,
+        Err(i) => {
+            logging_client.log(
+                log::LoggingLevel::Warning,
+                &format!("Unable to load text database: {}. Using new database.", i),
+            );
+            Vec::new()
+        }
+    };
+    let mut blob_index = match storage.blob_index() {
+        Ok(i) => i,
+        Err(i) => {
+            logging_client.log(
+                log::LoggingLevel::Warning,
+                &format!("Unable to load binary database: {}. Using new database.", i),
+            );
+            std::collections::HashMap::new()
+        }
+    };
+    let mut checkpoint = storage::Checkpoint::new(
+        arguments.checkpoint_items,
+        std::time::Duration::from_secs(arguments.checkpoint_seconds),
     );
-    let retry = arguments.retry.map(|x| x + 1).unwrap_or(1);
-    match arguments.subcommand {
-        cli::ApplicationSubCommand::FetchMetadata {
+    let mut failures = error::Failures::default();
+    let limiter = std::sync::Arc::new(client::Limiter::default());
+    let mut shutdown = Shutdown::listen(
+        std::time::Duration::from_secs(arguments.shutdown_grace_seconds),
+        &logging_client,
+    );
+    let site_retry = &arguments.retry.site_retry;
+    match subcommand {
+        cli::DatabaseSubCommand::FetchMetadata {
             site: i,
             start_page: j,
             end_page: k,
             overwrite,
         } => {
-            let structure = i.to_struct();
-            let immutable_database = database.0.clone();
-            let mut job_queue: futures::stream::FuturesUnordered<_> = (j..=k)
+            let clients = websites(std::iter::once(i), &settings, &limiter, &logging_client);
+            let (structure, http_client) = &clients[&i];
+            let immutable_database = database.clone();
+            let policy = settings.retry_policy(i, site_retry);
+            let (structure, immutable_database, http_client_ref, logging_client_ref) = (
+                &structure,
+                &immutable_database,
+                &http_client,
+                &logging_client,
+            );
+            let job_queue: Vec<_> = (j..=k)
                 .into_iter()
                 .map(|x| {
                     exec_future_and_return_vars(
                         x,
                         exec_future_with_retry(
-                            (0..retry)
-                                .into_iter()
-                                .map(|_| {
-                                    structure.fetch_metadata(
-                                        x,
-                                        overwrite,
-                                        &immutable_database,
-                                        &http_client,
-                                        &logging_client,
-                                    )
-                                })
-                                .collect(),
+                            policy.clone(),
+                            move || {
+                                structure.fetch_metadata(
+                                    x,
+                                    overwrite,
+                                    immutable_database,
+                                    http_client_ref,
+                                    logging_client_ref,
+                                )
+                            },
+                            &logging_client,
                         ),
                     )
                 })
                 .collect();
-            while let Some(i) = job_queue.next().await {
+            let mut job_queue = JobQueue::new(job_queue, settings.thread_limit.unwrap_or(0));
+            while let Some(i) = job_queue.next(&mut shutdown).await {
                 match i.1 {
-                    Ok(mut j) => {
-                        database.0.append(&mut j);
+                    Ok(j) => {
+                        let merged: Vec<_> = j
+                            .into_iter()
+                            .map(|x| saved::upsert(&mut database, x).1.clone())
+                            .collect();
+                        if let Err(k) = storage.upsert_games(&merged) {
+                            failures.record(&k);
+                            logging_client.log(
+                                log::LoggingLevel::Warning,
+                                &format!("Error while storing metadata from page {}: {}", i.0, k),
+                            );
+                        }
+                        record_checkpoint(&mut checkpoint, storage.as_mut(), &logging_client);
                         logging_client.log(
                             log::LoggingLevel::StatusReport,
                             &format!("Downloaded metadata from page {}", i.0),
                         );
                     }
-                    Err(j) => logging_client.log(
-                        log::LoggingLevel::Warning,
-                        &format!("Error while downloading metadata in page {}: {}", i.0, j),
-                    ),
+                    Err(j) => {
+                        failures.record(&j);
+                        logging_client.log(
+                            log::LoggingLevel::Warning,
+                            &format!("Error while downloading metadata in page {}: {}", i.0, j),
+                        );
+                    }
                 }
             }
         }
-        cli::ApplicationSubCommand::DownloadUserAvatars { site, overwrite } => {
+        cli::DatabaseSubCommand::Sync { site, overwrite } => {
+            let clients = websites(std::iter::once(site), &settings, &limiter, &logging_client);
+            let (structure, http_client) = &clients[&site];
+            let policy = settings.retry_policy(site, site_retry);
+            let mut new_count = 0;
+            let mut updated_count = 0;
+            let mut unchanged_count = 0;
+            let mut page = 1;
+            loop {
+                let links = match shutdown
+                    .finish(exec_future_with_retry(
+                        policy.clone(),
+                        || structure.fetch_index(page, &http_client, &logging_client),
+                        &logging_client,
+                    ))
+                    .await
+                {
+                    Some(Ok(i)) => i,
+                    None => break,
+                    Some(Err(i)) => {
+                        failures.record(&i);
+                        logging_client.log(
+                            log::LoggingLevel::Warning,
+                            &format!("Error while downloading index page {}: {}", page, i),
+                        );
+                        break;
+                    }
+                };
+                if links.len() == 0 {
+                    // More likely a new layout or a soft block than the end of the index
+                    let error = error::Error::parse(
+                        site.name(),
+                        format!("index page {}", page),
+                        String::from("No game links found"),
+                    );
+                    failures.record(&error);
+                    logging_client.log(log::LoggingLevel::Warning, &format!("{}, stopping", error));
+                    break;
+                }
+                if links.iter().all(|x| {
+                    database
+                        .iter()
+                        .any(|y| y.id == structure.game_id(x) && y.website == site.name())
+                }) {
+                    logging_client.log(
+                        log::LoggingLevel::Message,
+                        &format!("Page {} only lists known games, stopping", page),
+                    );
+                    break;
+                }
+                let fetched = match shutdown
+                    .finish(exec_future_with_retry(
+                        policy.clone(),
+                        || {
+                            structure.fetch_games(
+                                links.clone(),
+                                overwrite,
+                                &database,
+                                &http_client,
+                                &logging_client,
+                            )
+                        },
+                        &logging_client,
+                    ))
+                    .await
+                {
+                    Some(i) => i,
+                    None => break,
+                };
+                match fetched {
+                    Ok(j) => {
+                        let mut merged = Vec::with_capacity(j.len());
+                        for game in j {
+                            let (upserted, game) = saved::upsert(&mut database, game);
+                            match upserted {
+                                saved::Upserted::Inserted => new_count += 1,
+                                saved::Upserted::Changed => updated_count += 1,
+                                saved::Upserted::Unchanged => unchanged_count += 1,
+                            }
+                            merged.push(game.clone());
+                        }
+                        if let Err(k) = storage.upsert_games(&merged) {
+                            failures.record(&k);
+                            logging_client.log(
+                                log::LoggingLevel::Warning,
+                                &format!("Error while storing metadata from page {}: {}", page, k),
+                            );
+                        }
+                        record_checkpoint(&mut checkpoint, storage.as_mut(), &logging_client);
+                        logging_client.log(
+                            log::LoggingLevel::StatusReport,
+                            &format!("Downloaded metadata from page {}", page),
+                        );
+                    }
+                    Err(j) => {
+                        failures.record(&j);
+                        logging_client.log(
+                            log::LoggingLevel::Warning,
+                            &format!("Error while downloading metadata in page {}: {}", page, j),
+                        );
+                    }
+                }
+                if shutdown.requested() {
+                    break;
+                }
+                page += 1;
+            }
+            logging_client.log(
+                log::LoggingLevel::Message,
+                &format!(
+                    "Sync finished: {} new games, {} updated games, {} unchanged games",
+                    new_count, updated_count, unchanged_count
+                ),
+            );
+        }
+        cli::DatabaseSubCommand::DownloadUserAvatars { site, overwrite } => {
             let avatars: Vec<(String, cli::AvailableWebsite)> = database
-                .0
                 .iter()
                 .filter(|x| {
                     if let Some(i) = &site {
@@ -133,53 +326,69 @@ async fn _main() {
                         .collect::<Vec<(String, cli::AvailableWebsite)>>()
                 })
                 .flatten()
-                .filter(|x| !(!overwrite && database.1 .0.get(&x.0).is_some()))
+                .filter(|x| !(!overwrite && blob_index.get(&x.0).map_or(false, |x| x.is_image())))
                 .collect();
             logging_client.log(
                 log::LoggingLevel::Message,
                 &format!("Will download {} avatars", avatars.len()),
             );
-            let mut clients = std::collections::HashMap::new();
-            avatars.iter().for_each(|x| {
-                clients.insert(x.1, x.1.to_struct());
-            });
+            let clients = websites(
+                avatars.iter().map(|x| x.1),
+                &settings,
+                &limiter,
+                &logging_client,
+            );
+            let (clients, settings, logging_client_ref) = (&clients, &settings, &logging_client);
             let job_queue: Vec<_> = avatars
                 .into_iter()
                 .map(|x| {
                     exec_future_and_return_vars(
                         x.0.clone(),
                         exec_future_with_retry(
-                            (0..retry)
-                                .into_iter()
-                                .map(|_| {
-                                    clients.get(&x.1).unwrap().download_user_avatars(
+                            settings.retry_policy(x.1, site_retry),
+                            move || {
+                                let (structure, http_client) = clients.get(&x.1).unwrap();
+                                validated_image(
+                                    x.0.clone(),
+                                    structure.download_user_avatars(
                                         x.0.clone(),
-                                        &http_client,
-                                        &logging_client,
-                                    )
-                                })
-                                .collect(),
+                                        http_client,
+                                        logging_client_ref,
+                                    ),
+                                )
+                            },
+                            &logging_client,
                         ),
                     )
                 })
                 .collect();
-            pool_future::VectorFuturePool::new(job_queue, arguments.thread_limit.unwrap_or(3))
-                .execute_till_complete()
-                .await
-                .into_iter()
-                .for_each(|x| {
-                    match x.1 {
-                        Ok(i) => {
-                            database.1 .0.insert(x.0, serde_bytes::ByteBuf::from(i));
+            let mut job_queue = JobQueue::new(job_queue, settings.thread_limit.unwrap_or(3));
+            while let Some(x) = job_queue.next(&mut shutdown).await {
+                match x.1 {
+                    Ok(i) => match storage.insert_blob(&x.0, &i) {
+                        Ok(blob) => {
+                            blob_index.insert(x.0, blob);
+                            record_checkpoint(&mut checkpoint, storage.as_mut(), &logging_client);
                         }
-                        Err(i) => logging_client.log(
+                        Err(j) => {
+                            failures.record(&j);
+                            logging_client.log(
+                                log::LoggingLevel::Warning,
+                                &format!("Error while storing avatar {}: {}", x.0, j),
+                            );
+                        }
+                    },
+                    Err(i) => {
+                        failures.record(&i);
+                        logging_client.log(
                             log::LoggingLevel::Warning,
                             &format!("Error while downloading avatar {}: {}", x.0, i),
-                        ),
-                    };
-                });
+                        );
+                    }
+                };
+            }
         }
-        cli::ApplicationSubCommand::DownloadImages { game_id, overwrite } => {
+        cli::DatabaseSubCommand::DownloadImages { game_id, overwrite } => {
             let id_hashmap = match game_id.len() {
                 0 => None,
                 _ => {
@@ -192,7 +401,6 @@ async fn _main() {
             };
             let screenshots: Vec<(String, cli::AvailableWebsite, &saved::GameTextInformation)> =
                 database
-                    .0
                     .iter()
                     .filter(|x| {
                         if let Some(i) = &id_hashmap {
@@ -222,47 +430,64 @@ async fn _main() {
                             None
                         }
                     })
-                    .filter(|x| !(database.1 .0.get(&x.0).is_some() && !overwrite))
+                    .filter(|x| {
+                        !(blob_index.get(&x.0).map_or(false, |x| x.is_image()) && !overwrite)
+                    })
                     .collect();
             logging_client.log(
                 log::LoggingLevel::Message,
                 &format!("Will download {} screenshots", screenshots.len()),
             );
             let screenshot_len = screenshots.len();
-            let mut clients = std::collections::HashMap::new();
-            screenshots.iter().for_each(|x| {
-                clients.insert(x.1, x.1.to_struct());
-            });
-            let mut job_queue: futures::stream::FuturesUnordered<_> = screenshots
+            let clients = websites(
+                screenshots.iter().map(|x| x.1),
+                &settings,
+                &limiter,
+                &logging_client,
+            );
+            let (clients, settings, logging_client_ref) = (&clients, &settings, &logging_client);
+            let job_queue: Vec<_> = screenshots
                 .into_iter()
                 .map(|x| {
                     exec_future_and_return_vars(
                         x.0.clone(),
                         exec_future_with_retry(
-                            (0..retry)
-                                .into_iter()
-                                .map(|_| {
-                                    clients.get(&x.1).unwrap().download_screenshot(
+                            settings.retry_policy(x.1, site_retry),
+                            move || {
+                                let (structure, http_client) = clients.get(&x.1).unwrap();
+                                validated_image(
+                                    x.0.clone(),
+                                    structure.download_screenshot(
                                         x.0.clone(),
                                         x.2,
-                                        &http_client,
-                                        &logging_client,
-                                    )
-                                })
-                                .collect(),
+                                        http_client,
+                                        logging_client_ref,
+                                    ),
+                                )
+                            },
+                            &logging_client,
                         ),
                     )
                 })
                 .collect();
+            let mut job_queue = JobQueue::new(job_queue, settings.thread_limit.unwrap_or(0));
             let mut finished_count = 0;
-            while let Some(i) = job_queue.next().await {
+            while let Some(i) = job_queue.next(&mut shutdown).await {
                 finished_count += 1;
                 match i.1 {
                     Ok(j) => {
-                        database
-                            .1
-                             .0
-                            .insert(i.0.clone(), serde_bytes::ByteBuf::from(j));
+                        match storage.insert_blob(&i.0, &j) {
+                            Ok(blob) => blob_index.insert(i.0.clone(), blob),
+                            Err(k) => {
+                                failures.record(&k);
+                                logging_client.log(
+                                    log::LoggingLevel::Warning,
+                                    &format!("Error while storing screenshot {}: {}", i.0, k),
+                                );
+                                continue;
+                            }
+                        };
+                        record_checkpoint(&mut checkpoint, storage.as_mut(), &logging_client);
                         logging_client.log(
                             log::LoggingLevel::StatusReport,
                             &format!(
@@ -272,6 +497,7 @@ async fn _main() {
                         );
                     }
                     Err(j) => {
+                        failures.record(&j);
                         logging_client.log(
                             log::LoggingLevel::Warning,
                             &format!(
@@ -283,7 +509,7 @@ async fn _main() {
                 }
             }
         }
-        cli::ApplicationSubCommand::DownloadGame {
+        cli::DatabaseSubCommand::DownloadGame {
             game_id,
             no_overwrite,
             download_path,
@@ -292,7 +518,7 @@ async fn _main() {
             let mut id_hashmap = std::collections::HashMap::new();
             if game_id.len() == 0 {
                 logging_client.log(log::LoggingLevel::Warning, "Downloading all games");
-                database.0.iter().for_each(|x| {
+                database.iter().for_each(|x| {
                     id_hashmap.insert(x.id, ());
                 });
             }
@@ -309,7 +535,6 @@ async fn _main() {
                 String,
                 &saved::GameTextInformation,
             )> = database
-                .0
                 .iter()
                 .filter(|x| id_hashmap.get(&x.id).is_some())
                 .map(|x| {
@@ -348,173 +573,805 @@ async fn _main() {
                     std::fs::create_dir(&format!("{}{}", download_path, x.1)).unwrap()
                 }
             });
-            let mut clients = std::collections::HashMap::new();
-            games.iter().for_each(|x| {
-                let website = cli::AvailableWebsite::from_str(&x.2.website).unwrap();
-                clients.insert(website.to_owned(), website.to_struct());
-            });
+            let clients = websites(
+                games
+                    .iter()
+                    .map(|x| cli::AvailableWebsite::from_str(&x.2.website).unwrap()),
+                &settings,
+                &limiter,
+                &logging_client,
+            );
+            let (clients, settings, download_path, logging_client_ref) =
+                (&clients, &settings, &download_path, &logging_client);
             let job_queue: Vec<_> = games
                 .into_iter()
                 .map(|x| {
                     exec_future_and_return_vars(
                         (x.0 .0.clone(), x.1.clone()),
                         exec_future_with_retry(
-                            (0..retry)
-                                .into_iter()
-                                .map(|_| {
-                                    clients
-                                        .get(
-                                            &cli::AvailableWebsite::from_str(&x.2.website).unwrap(),
-                                        )
-                                        .unwrap()
-                                        .download_http_game(
-                                            x.0 .1 .0.clone(),
-                                            x.2,
-                                            format!("{}{}/{}", download_path, x.1, x.0 .0),
-                                            &http_client,
-                                            &logging_client,
-                                        )
-                                })
-                                .collect(),
+                            settings.retry_policy(
+                                cli::AvailableWebsite::from_str(&x.2.website).unwrap(),
+                                site_retry,
+                            ),
+                            move || {
+                                let (structure, http_client) = clients
+                                    .get(&cli::AvailableWebsite::from_str(&x.2.website).unwrap())
+                                    .unwrap();
+                                structure.download_http_game(
+                                    x.0 .1 .0.clone(),
+                                    x.2,
+                                    format!("{}{}/{}", download_path, x.1, x.0 .0),
+                                    http_client,
+                                    logging_client_ref,
+                                )
+                            },
+                            &logging_client,
                         ),
                     )
                 })
                 .collect();
-            pool_future::VectorFuturePool::new(job_queue, arguments.thread_limit.unwrap_or(50))
-                .execute_till_complete()
-                .await;
+            let mut job_queue = JobQueue::new(job_queue, settings.thread_limit.unwrap_or(50));
+            while let Some(i) = job_queue.next(&mut shutdown).await {
+                match i.1 {
+                    Ok(_) => logging_client.log(
+                        log::LoggingLevel::StatusReport,
+                        &format!("Downloaded {}/{}", (i.0).1, (i.0).0),
+                    ),
+                    Err(j) => {
+                        failures.record(&j);
+                        logging_client.log(
+                            log::LoggingLevel::Warning,
+                            &format!("Error while downloading {}/{}: {}", (i.0).1, (i.0).0, j),
+                        );
+                    }
+                }
+            }
             if let Some(i) = save_unparsable_games_list {
                 logging_client.log(log::LoggingLevel::Message, "Saving unparsable game list...");
                 std::fs::write(i, unparsable_games.join("\n")).unwrap();
             }
         }
-        cli::ApplicationSubCommand::Export {
+        cli::DatabaseSubCommand::Dedupe => {
+            let game_count = database.len();
+            database = saved::dedupe(database);
+            if let Err(i) = storage.replace_games(&database) {
+                logging_client.log(
+                    log::LoggingLevel::Warning,
+                    &format!(
+                        "Unable to store merged games: {}. The database is left untouched.",
+                        i
+                    ),
+                );
+                std::process::exit(i.exit_code());
+            }
+            logging_client.log(
+                log::LoggingLevel::Message,
+                &format!("Merged {} duplicated games", game_count - database.len()),
+            );
+        }
+        cli::DatabaseSubCommand::History { game_id } => {
+            let games: Vec<_> = database.iter().filter(|x| x.id == game_id).collect();
+            if games.len() == 0 {
+                logging_client.log(
+                    log::LoggingLevel::Warning,
+                    &format!("Unable to find game {}", game_id),
+                );
+            }
+            for game in games {
+                print_history(game, &logging_client);
+            }
+        }
+        cli::DatabaseSubCommand::Search {
+            query,
+            filter,
+            limit,
+        } => {
+            let index = search::SearchIndex::build(&database);
+            let results: Vec<_> = index
+                .search(&query)
+                .into_iter()
+                .map(|(position, score)| (&database[position], score))
+                .filter(|(game, _)| filter.matches(game))
+                .collect();
+            logging_client.log(
+                log::LoggingLevel::Message,
+                &format!("{} games found", results.len()),
+            );
+            for (game, score) in results.into_iter().take(limit) {
+                logging_client.log(
+                    log::LoggingLevel::Message,
+                    &format!(
+                        "{:.2} {}@{} {} ({}, viewed {}) [{}]",
+                        score,
+                        game.id,
+                        game.website,
+                        game.name,
+                        game.published.date(),
+                        game.viewed,
+                        game.tags.join(", ")
+                    ),
+                );
+            }
+        }
+        cli::DatabaseSubCommand::Serve {
+            address,
+            template_directory,
+        } => {
+            let templates = match templates::Templates::load(template_directory.as_deref()) {
+                Ok(i) => i,
+                Err(i) => {
+                    logging_client.log(
+                        log::LoggingLevel::Warning,
+                        &format!("Error while loading templates: {}", i),
+                    );
+                    std::process::exit(1);
+                }
+            };
+            let server = server::Server::new(&database, storage.as_ref(), &blob_index, &templates);
+            if let Err(i) = tokio::task::block_in_place(|| {
+                server.run(&address, || shutdown.requested(), &logging_client)
+            }) {
+                logging_client.log(
+                    log::LoggingLevel::Warning,
+                    &format!("Error while serving: {}", i),
+                );
+            }
+        }
+        cli::DatabaseSubCommand::Export {
+            filter,
             markdown_location,
             html_location,
             prefer_online,
+            template_directory,
+            front_matter,
+            thumbnails,
+            epub_location,
+            epub_tag,
+            tables_location,
+            tables_format,
         } => {
+            let selected: saved::GameTextDatabase = database
+                .iter()
+                .filter(|x| filter.matches(x))
+                .cloned()
+                .collect();
+            logging_client.log(
+                log::LoggingLevel::Message,
+                &format!("{} of {} games selected", selected.len(), database.len()),
+            );
+            // Only the images shown by the selected pages are exported
+            let referenced_images: std::collections::HashMap<String, storage::Blob> = selected
+                .iter()
+                .flat_map(|x| x.get_images())
+                .filter_map(|x| {
+                    let blob = blob_index.get(&x).filter(|x| x.is_image())?.clone();
+                    Some((x, blob))
+                })
+                .collect();
             if let Some(i) = html_location {
+                let templates = match templates::Templates::load(template_directory.as_deref()) {
+                    Ok(i) => i,
+                    Err(i) => {
+                        logging_client.log(
+                            log::LoggingLevel::Warning,
+                            &format!("Error while loading templates: {}", i),
+                        );
+                        std::process::exit(1);
+                    }
+                };
                 if !std::path::Path::new(&i).is_dir() {
                     std::fs::create_dir_all(format!("{}/imgs", i)).unwrap();
                 }
+                std::fs::create_dir_all(format!("{}/tags", i)).unwrap();
+                std::fs::create_dir_all(format!("{}/sites", i)).unwrap();
+                let thumbnails = thumbnails && !prefer_online;
+                if thumbnails {
+                    std::fs::create_dir_all(format!("{}/imgs/thumbs", i)).unwrap();
+                }
                 let generated_pages = html_generator(
-                    &database.0,
+                    &selected,
                     if prefer_online {
                         None
                     } else {
-                        Some(&database.1)
+                        Some(&referenced_images)
                     },
-                );
-                logging_client.log(log::LoggingLevel::Message, "Html pages exported.");
-                let mut job_vec = Vec::new();
-                for page in generated_pages {
-                    job_vec.push(exec_future_and_return_vars(
-                        (true, page.0.clone()),
-                        tokio::fs::write(format!("{}/{}.html", i, page.0), page.1.into_bytes()),
-                    ));
-                }
-                for data in &database.1 .0 {
-                    let data: (&String, &serde_bytes::ByteBuf) = data;
-                    let file_dot = data.0.rfind('.').unwrap_or(data.0.len() - 1);
-                    job_vec.push(exec_future_and_return_vars(
-                        (false, data.0.clone()),
-                        tokio::fs::write(
-                            format!(
-                                "{}/imgs/{}.{}",
-                                i,
-                                hash_for_filename(&data.0[..file_dot]),
-                                &data.0[file_dot + 1..]
-                            ),
-                            data.1.to_vec(),
-                        ),
-                    ));
-                }
-                // I don't think tokio fs write need to retry.
-                let mut job_queue: futures::stream::FuturesUnordered<_> =
-                    job_vec.into_iter().collect();
-                while let Some(i) = job_queue.next().await {
-                    if let Err(j) = i.1 {
+                    thumbnails,
+                    &templates,
+                )
+                .and_then(|mut x| {
+                    x.extend(html_listing_generator(&selected, &templates)?);
+                    Ok(x)
+                });
+                let generated_pages = match generated_pages {
+                    Ok(i) => i,
+                    Err(i) => {
                         logging_client.log(
                             log::LoggingLevel::Warning,
-                            &format!("Error while writing {} to disk: {}", i.0 .1, j.to_string()),
-                        );
-                    } else {
-                        logging_client.log(
-                            log::LoggingLevel::StatusReport,
-                            &format!(
-                                "{} {} written.",
-                                i.0 .1,
-                                if i.0 .0 { "html" } else { "image" }
-                            ),
+                            &format!("Error while rendering html pages: {}", i),
                         );
+                        std::process::exit(1);
                     }
+                };
+                logging_client.log(log::LoggingLevel::Message, "Html pages exported.");
+                let mut manifest =
+                    write_pages(&i, "html", "html", generated_pages, &logging_client).await;
+                if !prefer_online {
+                    export_blobs(
+                        &i,
+                        storage.as_ref(),
+                        &selected,
+                        &referenced_images,
+                        thumbnails,
+                        &mut manifest,
+                        &logging_client,
+                    )
+                    .await;
                 }
+                finish_manifest(manifest, &database, &selected, &logging_client);
             }
             if let Some(i) = markdown_location {
                 if !std::path::Path::new(&i).is_dir() {
                     std::fs::create_dir_all(format!("{}/imgs", i)).unwrap();
                 }
                 let generated_pages = markdown_generator(
-                    &database.0,
+                    &selected,
                     if prefer_online {
                         None
                     } else {
-                        Some(&database.1)
+                        Some(&referenced_images)
                     },
+                    front_matter,
                 );
                 logging_client.log(log::LoggingLevel::Message, "Markdown pages exported.");
-                let mut job_vec = Vec::new();
-                for page in generated_pages {
-                    job_vec.push(exec_future_and_return_vars(
-                        (true, page.0.clone()),
-                        tokio::fs::write(format!("{}/{}.md", i, page.0), page.1.into_bytes()),
-                    ));
+                let mut manifest =
+                    write_pages(&i, "markdown", "md", generated_pages, &logging_client).await;
+                if !prefer_online {
+                    export_blobs(
+                        &i,
+                        storage.as_ref(),
+                        &selected,
+                        &referenced_images,
+                        false,
+                        &mut manifest,
+                        &logging_client,
+                    )
+                    .await;
                 }
-                for data in &database.1 .0 {
-                    let data: (&String, &serde_bytes::ByteBuf) = data;
-                    let file_dot = data.0.rfind('.').unwrap_or(data.0.len() - 1);
-                    job_vec.push(exec_future_and_return_vars(
-                        (false, data.0.clone()),
-                        tokio::fs::write(
-                            format!(
-                                "{}/imgs/{}.{}",
-                                i,
-                                hash_for_filename(&data.0[..file_dot]),
-                                &data.0[file_dot + 1..]
+                finish_manifest(manifest, &database, &selected, &logging_client);
+            }
+            if let Some(i) = epub_location {
+                std::fs::create_dir_all(&i).unwrap();
+                let offline_images = offline_file_names(&referenced_images);
+                let mut books = Vec::new();
+                if epub_tag.len() == 0 {
+                    for game in &selected {
+                        books.push((
+                            to_legal_name(&game.name, game.id),
+                            epub::Book::new(
+                                game.name.clone(),
+                                format!("urn:bishojo:{}:{}", game.website, game.id),
+                                &offline_images,
                             ),
-                            data.1.to_vec(),
+                            vec![game],
+                        ));
+                    }
+                } else {
+                    let title = epub_tag.join(", ");
+                    books.push((
+                        listing_file_name(&title),
+                        epub::Book::new(
+                            title.clone(),
+                            format!("urn:bishojo:tags:{}", url_encode(&title)),
+                            &offline_images,
                         ),
+                        selected
+                            .iter()
+                            .filter(|x| epub_tag.iter().all(|y| x.tags.contains(y)))
+                            .collect(),
                     ));
                 }
-                // I don't think tokio fs write need to retry.
-                let mut job_queue: futures::stream::FuturesUnordered<_> =
-                    job_vec.into_iter().collect();
-                while let Some(i) = job_queue.next().await {
-                    if let Err(j) = i.1 {
+                for (name, mut book, games) in books {
+                    for game in games {
+                        book.add_game(&game_page(game, Some(&referenced_images), false));
+                    }
+                    if book.is_empty() {
                         logging_client.log(
                             log::LoggingLevel::Warning,
-                            &format!("Error while writing {} to disk: {}", i.0 .1, j.to_string()),
+                            &format!("No games to put in {}.epub", name),
                         );
-                    } else {
-                        logging_client.log(
+                        continue;
+                    }
+                    match book.write(&format!("{}/{}.epub", i, name), storage.as_ref()) {
+                        Ok(_) => logging_client.log(
                             log::LoggingLevel::StatusReport,
-                            &format!(
-                                "{} {} written.",
-                                i.0 .1,
-                                if i.0 .0 { "markdown" } else { "image" }
-                            ),
-                        );
+                            &format!("{} epub written.", name),
+                        ),
+                        Err(j) => logging_client.log(
+                            log::LoggingLevel::Warning,
+                            &format!("Error while writing {}.epub: {}", name, j),
+                        ),
                     }
                 }
+                logging_client.log(log::LoggingLevel::Message, "Epub books exported.");
+            }
+            if let Some(i) = tables_location {
+                std::fs::create_dir_all(&i).unwrap();
+                match tables_format.write(&tables::Tables::build(&selected), &i) {
+                    Ok(_) => logging_client.log(log::LoggingLevel::Message, "Tables exported."),
+                    Err(j) => logging_client.log(
+                        log::LoggingLevel::Warning,
+                        &format!("Error while exporting tables: {}", j),
+                    ),
+                }
             }
         }
     }
-    saved::save(
-        (&database.0, &arguments.json_database_location),
-        (&database.1, &arguments.binary_database_location),
-    )
-    .unwrap();
-    logging_client.log(log::LoggingLevel::Warning, "Database synced.")
+    if let Err(i) = storage.flush() {
+        logging_client.log(
+            log::LoggingLevel::Warning,
+            &format!(
+                "Unable to save database: {}. The previous database is left untouched.",
+                i
+            ),
+        );
+        std::process::exit(i.exit_code());
+    }
+    logging_client.log(log::LoggingLevel::Warning, "Database synced.");
+    if shutdown.requested() {
+        std::process::exit(130)
+    }
+    if let Some(i) = failures.summary() {
+        logging_client.log(log::LoggingLevel::Warning, &i);
+        std::process::exit(failures.exit_code())
+    }
+}
+/// Logs the version, and the environment variables which are no longer read.
+fn log_start(logging_client: &log::LoggingClient) {
+    logging_client.log(
+        log::LoggingLevel::Warning,
+        &format!(
+            "{} ver {} started.",
+            std::env!("CARGO_PKG_NAME"),
+            std::env!("CARGO_PKG_VERSION")
+        ),
+    );
+    for (variable, setting) in &config::OBSOLETE_VARIABLES {
+        if std::env::var_os(variable).is_some() {
+            logging_client.log(
+                log::LoggingLevel::Warning,
+                &format!(
+                    "{} is ignored, set {} under [sites.kkgal] in the config file instead",
+                    variable, setting
+                ),
+            );
+        }
+    }
+}
+fn record_checkpoint(
+    checkpoint: &mut storage::Checkpoint,
+    storage: &mut dyn storage::Storage,
+    logging_client: &log::LoggingClient,
+) {
+    match checkpoint.record(storage) {
+        Ok(true) => logging_client.log(log::LoggingLevel::StatusReport, "Checkpoint saved."),
+        Ok(false) => (),
+        Err(i) => logging_client.log(
+            log::LoggingLevel::Warning,
+            &format!("Unable to save checkpoint: {}", i),
+        ),
+    }
+}
+/// Resolves on SIGINT or, on unix, SIGTERM.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        let mut terminate =
+            tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()).unwrap();
+        futures::select! {
+            _ = Box::pin(tokio::signal::ctrl_c()).fuse() => (),
+            _ = Box::pin(terminate.recv()).fuse() => (),
+        }
+    }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await.unwrap();
+}
+/// Tracks shutdown signals. The first one asks jobs to wind down, they then have `grace` to
+/// finish before being dropped. The second one exits immediately without saving.
+struct Shutdown {
+    receiver: tokio::sync::watch::Receiver<bool>,
+    grace: std::time::Duration,
+    deadline: Option<tokio::time::Instant>,
+}
+impl Shutdown {
+    fn listen(grace: std::time::Duration, logging_client: &log::LoggingClient) -> Self {
+        let (sender, receiver) = tokio::sync::watch::channel(false);
+        let logging_client = logging_client.clone();
+        tokio::spawn(async move {
+            shutdown_signal().await;
+            logging_client.log(
+                log::LoggingLevel::Warning,
+                "Interrupted, finishing running jobs before saving. Interrupt again to abort.",
+            );
+            let _ = sender.send(true);
+            shutdown_signal().await;
+            logging_client.log(log::LoggingLevel::Warning, "Aborted.");
+            std::process::exit(130)
+        });
+        Self {
+            receiver,
+            grace,
+            deadline: None,
+        }
+    }
+    fn requested(&self) -> bool {
+        *self.receiver.borrow()
+    }
+    async fn wait(&mut self) {
+        while !self.requested() {
+            if self.receiver.changed().await.is_err() {
+                futures::future::pending::<()>().await;
+            }
+        }
+    }
+    /// Awaits `future`, unless it's still running when the grace period after a shutdown
+    /// request is over.
+    async fn finish<F: std::future::Future>(&mut self, future: F) -> Option<F::Output> {
+        let mut future = Box::pin(future).fuse();
+        if !self.requested() {
+            futures::select! {
+                i = future => return Some(i),
+                _ = Box::pin(self.wait()).fuse() => (),
+            }
+        }
+        let grace = self.grace;
+        let deadline = *self
+            .deadline
+            .get_or_insert_with(|| tokio::time::Instant::now() + grace);
+        tokio::time::timeout_at(deadline, future).await.ok()
+    }
+}
+/// Runs jobs with at most `limit` (0 for unlimited) of them at the same time, yielding their
+/// results as they finish. No new job is started once shutdown has been requested.
+struct JobQueue<F: std::future::Future> {
+    pending: std::vec::IntoIter<F>,
+    running: futures::stream::FuturesUnordered<F>,
+    limit: usize,
+}
+impl<F: std::future::Future> JobQueue<F> {
+    fn new(jobs: Vec<F>, limit: usize) -> Self {
+        Self {
+            pending: jobs.into_iter(),
+            running: futures::stream::FuturesUnordered::new(),
+            limit: if limit == 0 { usize::MAX } else { limit },
+        }
+    }
+    async fn next(&mut self, shutdown: &mut Shutdown) -> Option<F::Output> {
+        if !shutdown.requested() {
+            while self.running.len() < self.limit {
+                match self.pending.next() {
+                    Some(i) => self.running.push(i),
+                    None => break,
+                }
+            }
+        }
+        if self.running.is_empty() {
+            return None;
+        }
+        shutdown.finish(self.running.next()).await.flatten()
+    }
+}
+fn restore(
+    arguments: &cli::ApplicationMainEntry,
+    timestamp: Option<&str>,
+    logging_client: &log::LoggingClient,
+) -> Result<(), error::Error> {
+    let files = arguments.storage.files(arguments);
+    match timestamp {
+        Some(timestamp) => {
+            // Don't restore half of a backup set
+            for file in &files {
+                if !saved::list_backups(file)?.iter().any(|x| x.0 == timestamp) {
+                    return Err(error::Error::Storage(format!(
+                        "No backup of {} taken at {}",
+                        file, timestamp
+                    )));
+                }
+            }
+            for file in files {
+                saved::restore_backup(file, timestamp, arguments.backup_count)?;
+                logging_client.log(
+                    log::LoggingLevel::Message,
+                    &format!("Restored {} from backup {}", file, timestamp),
+                );
+            }
+        }
+        None => {
+            let mut backups: std::collections::BTreeMap<String, Vec<&str>> =
+                std::collections::BTreeMap::new();
+            for file in files {
+                for backup in saved::list_backups(file)? {
+                    backups.entry(backup.0).or_default().push(file);
+                }
+            }
+            if backups.len() == 0 {
+                logging_client.log(log::LoggingLevel::Message, "No backups found.");
+            }
+            for backup in backups.iter().rev() {
+                logging_client.log(
+                    log::LoggingLevel::Message,
+                    &format!("{}: {}", backup.0, backup.1.join(", ")),
+                );
+            }
+        }
+    }
+    Ok(())
+}
+fn migrate(
+    arguments: &cli::ApplicationMainEntry,
+    logging_client: &log::LoggingClient,
+) -> Result<(), error::Error> {
+    let database = saved::load(
+        &arguments.json_database_location,
+        &arguments.binary_database_location,
+    );
+    let text_database = database.0?;
+    let binary_database = database.1?;
+    let mut sqlite_storage = storage::sqlite::SqliteStorage::open(
+        &arguments.sqlite_database_location,
+        arguments.backup_count,
+    )?;
+    let sqlite_storage: &mut dyn storage::Storage = &mut sqlite_storage;
+    sqlite_storage.upsert_games(&text_database)?;
+    for (key, hash) in &binary_database.urls {
+        if let Some(data) = binary_database.blobs.get(hash) {
+            sqlite_storage.insert_blob(key, data)?;
+        }
+    }
+    sqlite_storage.flush()?;
+    logging_client.log(
+        log::LoggingLevel::Message,
+        &format!(
+            "Imported {} games and {} blobs into {}",
+            text_database.len(),
+            binary_database.urls.len(),
+            arguments.sqlite_database_location
+        ),
+    );
+    Ok(())
+}
+/// Writes the `(name, content, games)` pages rendered for a `kind` of export to
+/// `{location}/{name}.{extension}`, skipping those the manifest of `kind` says are unchanged.
+/// Returns the manifest, for the caller to record the blobs and finish.
+async fn write_pages(
+    location: &str,
+    kind: &str,
+    extension: &str,
+    pages: Vec<(String, String, Vec<manifest::GameKey>)>,
+    logging_client: &log::LoggingClient,
+) -> manifest::Manifest {
+    let mut manifest = manifest::Manifest::load(location, kind);
+    let mut job_vec = Vec::new();
+    for page in pages {
+        let file = format!("{}.{}", page.0, extension);
+        let hash = seahash::hash(page.1.as_bytes());
+        if manifest.unchanged(&file, hash) {
+            manifest.record(&file, hash, page.2);
+            continue;
+        }
+        job_vec.push(exec_future_and_return_vars(
+            (file.clone(), hash, page.2),
+            tokio::fs::write(format!("{}/{}", location, file), page.1.into_bytes()),
+        ));
+    }
+    // I don't think tokio fs write need to retry.
+    let mut job_queue: futures::stream::FuturesUnordered<_> = job_vec.into_iter().collect();
+    while let Some(i) = job_queue.next().await {
+        let (file, hash, games) = i.0;
+        if let Err(j) = i.1 {
+            manifest.record_failure(&file);
+            logging_client.log(
+                log::LoggingLevel::Warning,
+                &format!("Error while writing {} to disk: {}", file, j.to_string()),
+            );
+        } else {
+            manifest.record(&file, hash, games);
+            logging_client.log(
+                log::LoggingLevel::StatusReport,
+                &format!("{} {} written.", file, kind),
+            );
+        }
+    }
+    manifest
+}
+/// Removes the files left over from the previous export and saves the manifest.
+fn finish_manifest(
+    manifest: manifest::Manifest,
+    database: &saved::GameTextDatabase,
+    selected: &saved::GameTextDatabase,
+    logging_client: &log::LoggingClient,
+) {
+    match manifest.finish(database, selected) {
+        Ok(removed) => {
+            for file in &removed {
+                logging_client.log(
+                    log::LoggingLevel::StatusReport,
+                    &format!("{} removed.", file),
+                );
+            }
+            if removed.len() != 0 {
+                logging_client.log(
+                    log::LoggingLevel::Message,
+                    &format!("{} stale files removed.", removed.len()),
+                );
+            }
+        }
+        Err(i) => logging_client.log(
+            log::LoggingLevel::Warning,
+            &format!("Error while updating the export manifest: {}", i),
+        ),
+    }
+}
+/// Writes the blobs to `{location}/imgs`, and their thumbnails to `{location}/imgs/thumbs`,
+/// reading them one at a time so that the binary database never has to be loaded in memory as a
+/// whole. Blobs shared by several keys are written once.
+async fn export_blobs(
+    location: &str,
+    storage: &dyn storage::Storage,
+    database: &saved::GameTextDatabase,
+    blob_index: &std::collections::HashMap<String, storage::Blob>,
+    thumbnails: bool,
+    manifest: &mut manifest::Manifest,
+    logging_client: &log::LoggingClient,
+) {
+    let mut games: std::collections::HashMap<String, Vec<manifest::GameKey>> =
+        std::collections::HashMap::new();
+    for game in database {
+        for image in game.get_images() {
+            if let Some(i) = blob_index.get(&image) {
+                games
+                    .entry(offline_file_name(i))
+                    .or_default()
+                    .push(manifest::game_key(game));
+            }
+        }
+    }
+    for (file_name, key) in offline_file_names(blob_index) {
+        let data = match storage.get_blob(key) {
+            Ok(Some(i)) => i,
+            Ok(None) => continue,
+            Err(i) => {
+                logging_client.log(
+                    log::LoggingLevel::Warning,
+                    &format!("Error while reading {} from database: {}", key, i),
+                );
+                continue;
+            }
+        };
+        // A thumbnail only depends on its image, so both are skipped when the image is unchanged
+        let hash = seahash::hash(&data);
+        let mut files = vec![(format!("imgs/{}", file_name), false)];
+        if thumbnails {
+            files.push((format!("imgs/thumbs/{}", file_name), true));
+        }
+        let games = games.remove(&file_name).unwrap_or_default();
+        for (file, thumbnail) in files {
+            if manifest.unchanged(&file, hash) {
+                manifest.record(&file, hash, games.clone());
+                continue;
+            }
+            let content = if thumbnail {
+                images::thumbnail(&data, &blob_index[key].mime_type).unwrap_or_else(|i| {
+                    logging_client.log(
+                        log::LoggingLevel::Warning,
+                        &format!("Unable to make a thumbnail of {}, copying it: {}", key, i),
+                    );
+                    data.clone()
+                })
+            } else {
+                data.clone()
+            };
+            // I don't think tokio fs write need to retry.
+            match tokio::fs::write(format!("{}/{}", location, file), content).await {
+                Ok(_) => {
+                    manifest.record(&file, hash, games.clone());
+                    logging_client.log(
+                        log::LoggingLevel::StatusReport,
+                        &format!("{} written.", file),
+                    )
+                }
+                Err(i) => {
+                    manifest.record_failure(&file);
+                    logging_client.log(
+                        log::LoggingLevel::Warning,
+                        &format!("Error while writing {} to disk: {}", file, i.to_string()),
+                    )
+                }
+            }
+        }
+    }
+}
+/// Fails unless the download is a complete image, so that error pages and truncated bodies are
+/// retried instead of stored.
+async fn validated_image<U: std::future::Future<Output = Result<Vec<u8>, error::Error>>>(
+    url: String,
+    download: U,
+) -> Result<Vec<u8>, error::Error> {
+    let data = download.await?;
+    images::validate(&data).map_err(|message| error::Error::InvalidImage {
+        url,
+        detected: images::sniff(&data),
+        message,
+    })?;
+    Ok(data)
+}
+/// The websites with their settings and HTTP clients, see `website`. Exits if one of them can't
+/// be set up.
+fn websites(
+    sites: impl Iterator<Item = cli::AvailableWebsite>,
+    settings: &config::Settings,
+    limiter: &std::sync::Arc<client::Limiter>,
+    logging_client: &log::LoggingClient,
+) -> std::collections::HashMap<
+    cli::AvailableWebsite,
+    (Box<dyn websites::GalgameWebsite>, client::HttpClient),
+> {
+    let mut clients = std::collections::HashMap::new();
+    for site in sites {
+        if clients.contains_key(&site) {
+            continue;
+        }
+        match website(site, settings, limiter) {
+            Ok(i) => {
+                clients.insert(site, i);
+            }
+            Err(i) => {
+                logging_client.log(
+                    log::LoggingLevel::Warning,
+                    &format!("Unable to set up {}: {}", site.name(), i),
+                );
+                std::process::exit(i.exit_code());
+            }
+        }
+    }
+    clients
+}
+/// The website with its settings, and an HTTP client with its proxy, user agent and limits.
+fn website(
+    site: cli::AvailableWebsite,
+    settings: &config::Settings,
+    limiter: &std::sync::Arc<client::Limiter>,
+) -> Result<(Box<dyn websites::GalgameWebsite>, client::HttpClient), error::Error> {
+    let site_settings = settings.site(site);
+    let proxy = match &site_settings.proxy {
+        Some(i) => Some(
+            i.parse()
+                .map_err(|x| error::Error::Config(format!("proxy: {}", x)))?,
+        ),
+        None => None,
+    };
+    let http_client = client::HttpClient::new(
+        isahc::HttpClientBuilder::new()
+            .connect_timeout(std::time::Duration::from_secs(
+                settings.timeout.unwrap_or(20),
+            ))
+            .redirect_policy(isahc::config::RedirectPolicy::Limit(10))
+            .auto_referer()
+            .tcp_nodelay()
+            .proxy(proxy)
+            .max_connections(settings.thread_limit.unwrap_or(0))
+            .default_header(
+                isahc::http::header::USER_AGENT,
+                site_settings.user_agent.as_deref().unwrap_or_default(),
+            )
+            .build()
+            .map_err(|x| error::Error::Config(x.to_string()))?,
+        client::Limits {
+            requests_per_second: site_settings.rate_limit,
+            max_concurrency: site_settings.max_concurrency,
+        },
+        limiter.clone(),
+    );
+    Ok((site.to_struct(site_settings), http_client))
 }
 async fn exec_future_and_return_vars<T, U: std::future::Future>(
     vars: T,
@@ -522,42 +1379,58 @@ async fn exec_future_and_return_vars<T, U: std::future::Future>(
 ) -> (T, U::Output) {
     (vars, function.await)
 }
-async fn exec_future_with_retry<
-    V,
-    W: std::fmt::Display,
-    U: std::future::Future<Output = Result<V, W>>,
->(
-    functions: Vec<U>,
-) -> Result<V, String> {
-    let mut error_messages = Vec::with_capacity(functions.len());
-    for i in functions {
-        match i.await {
+/// Runs the request until it succeeds, or fails with an error which the policy won't retry, such
+/// as a 404 or a parse error. Returns the last error.
+async fn exec_future_with_retry<V, U: std::future::Future<Output = Result<V, error::Error>>>(
+    policy: retry::RetryPolicy,
+    function: impl Fn() -> U,
+    logging_client: &log::LoggingClient,
+) -> Result<V, error::Error> {
+    let mut retry = 0;
+    loop {
+        match function().await {
             Ok(i) => return Ok(i),
-            Err(i) => error_messages.push(i.to_string()),
+            Err(i) if policy.should_retry(retry, &i) => {
+                let delay = policy.delay(retry, &i);
+                logging_client.log(
+                    log::LoggingLevel::StatusReport,
+                    &format!("Retrying in {:.1}s: {}", delay.as_secs_f64(), i),
+                );
+                tokio::time::sleep(delay).await;
+                retry += 1;
+            }
+            Err(i) => return Err(i),
         }
     }
-    error_messages.dedup();
-    Err(error_messages.join(", "))
 }
 fn find_offline_data_or_use_remove(
     x: String,
-    offline_data: Option<&saved::GameBinaryDatabase>,
+    offline_data: Option<&std::collections::HashMap<String, storage::Blob>>,
 ) -> String {
-    if let Some(data) = offline_data {
-        if data.0.get(&x).is_some() {
-            let suffix_position = x.rfind('.').unwrap_or(x.len() - 1);
-            format!(
-                "imgs/{}.{}",
-                hash_for_filename(&x[..suffix_position]),
-                &x[suffix_position + 1..]
-            )
-        } else {
-            x
-        }
-    } else {
-        x
+    // Blobs which aren't images, such as error pages stored by older versions, are left online
+    match offline_data
+        .and_then(|data| data.get(&x))
+        .filter(|x| x.is_image())
+    {
+        Some(blob) => format!("imgs/{}", offline_file_name(blob)),
+        None => x,
     }
 }
+/// Offline images are named after their content hash, so that different images never share a
+/// file, with the extension of their detected type.
+fn offline_file_name(blob: &storage::Blob) -> String {
+    format!("{}.{}", blob.hash, images::extension(&blob.mime_type))
+}
+/// Maps the file names given to offline images by `find_offline_data_or_use_remove` back to their
+/// blob keys.
+fn offline_file_names(
+    blob_index: &std::collections::HashMap<String, storage::Blob>,
+) -> std::collections::HashMap<String, &str> {
+    blob_index
+        .iter()
+        .map(|x| (offline_file_name(x.1), x.0.as_str()))
+        .collect()
+}
 fn to_legal_name(name: &str, id: u64) -> String {
     format!(
         "{}_{}",
@@ -565,268 +1438,458 @@ fn to_legal_name(name: &str, id: u64) -> String {
         id.to_string()
     )
 }
+/// Pages are returned with the games they show, see `manifest::Manifest::record`.
 fn html_generator(
     database: &saved::GameTextDatabase,
-    offline_data: Option<&saved::GameBinaryDatabase>,
-) -> Vec<(String, String)> {
-    let mut constructed = Vec::new();
-    for game in database {
-        let mut game_detail = format!(
-            r#"<!DOCTYPE html>
-<html>
-    <head>
-        <meta charset="utf-8">
-        <title>{}</title>
-        <style type="text/css">
-            *
-            {{
-                text-align: center !important;
-                margin: auto !important;
-            }}
-            body {{
-                padding: 0 100px;
-            }}
-        </style>
-    </head>
-    </head>
-    <body>
-        <h1>Galgame</h1>
-        <p><small>id: {}@{} | published at {} | {} viewed</small></p>
-        <p>Tags: {}</p>
-        <br>
-        "#,
-            game.name,
-            game.id.to_string(),
-            game.website,
-            game.published.to_string(),
-            game.viewed,
-            game.tags.join(", ")
-        );
-        for paragraph in &game.paragraphs {
-            let mut content_rendered = String::new();
-            for content in &paragraph.1 {
-                content_rendered.push_str(&match content {
-                    saved::ParagraphContent::Text(i) => format!("<p>{}</p>", i),
-                    saved::ParagraphContent::Image(i) => format!(
-                        "<img src={} width=\"50%\">",
-                        find_offline_data_or_use_remove(i.to_string(), offline_data)
-                    ),
-                });
-                content_rendered.push('\n');
-            }
-            game_detail.push_str(&format!(
-                "{}\n{}",
-                paragraph
-                    .0
-                    .as_ref()
-                    .map(|x| format!("<h3>{}</h3>", x))
-                    .unwrap_or(String::new()),
-                content_rendered.replace('\n', "\n        ")
-            ));
-            game_detail.push_str("<br>");
-        }
-        game_detail.push_str(
-            &format!(
-                "        <h3>Downloads</h3>\n<ul>\n    {}\n</ul>\n<br>",
-                game.files
-                    .iter()
-                    .map(|x| format!(
-                        "<li><a href=\"{}\" download=\"{}\">{}</a> {}</li>",
-                        x.1 .0,
-                        x.0,
-                        x.0,
-                        if let Some(i) = x.1 .1 {
-                            byte_unit::Byte::from_bytes(i)
-                                .get_appropriate_unit(true)
-                                .to_string()
-                        } else {
-                            String::new()
-                        }
-                    ))
-                    .collect::<Vec<_>>()
-                    .join("\n")
-            )
-            .replace('\n', "\n        "),
-        );
-        fn comments_constructor(
-            comment: &saved::Comment,
-            offline_data: Option<&saved::GameBinaryDatabase>,
-        ) -> String {
-            let avatar_link = if let Some(data) = offline_data {
-                if data.0.get(&comment.user_avatar).is_some() {
-                    let avatar_suffix_location = comment
-                        .user_avatar
-                        .rfind('.')
-                        .unwrap_or(comment.user_avatar.len() - 1);
-                    format!(
-                        "imgs/{}.{}",
-                        hash_for_filename(&comment.user_avatar[..avatar_suffix_location]),
-                        &comment.user_avatar[avatar_suffix_location + 1..]
-                    )
-                } else {
-                    comment.user_avatar.clone()
-                }
-            } else {
-                comment.user_avatar.clone()
-            };
-            let mut content = String::new();
-            for i in &comment.content {
-                match i {
-                    saved::ParagraphContent::Text(i) => content.push_str(&format!("<p>{}</p>", i)),
-                    saved::ParagraphContent::Image(i) => content.push_str(&format!(
-                        "<img src={} width=\"40%\">",
-                        find_offline_data_or_use_remove(i.to_string(), offline_data)
-                    )),
+    offline_data: Option<&std::collections::HashMap<String, storage::Blob>>,
+    thumbnails: bool,
+    templates: &templates::Templates,
+) -> Result<Vec<(String, String, Vec<manifest::GameKey>)>, String> {
+    database
+        .iter()
+        .map(|game| {
+            Ok((
+                to_legal_name(&game.name, game.id),
+                html_page(game, offline_data, thumbnails, templates)?,
+                vec![manifest::game_key(game)],
+            ))
+        })
+        .collect()
+}
+fn html_page(
+    game: &saved::GameTextInformation,
+    offline_data: Option<&std::collections::HashMap<String, storage::Blob>>,
+    thumbnails: bool,
+    templates: &templates::Templates,
+) -> Result<String, String> {
+    templates.game(&game_page(game, offline_data, thumbnails))
+}
+/// With `thumbnails`, offline images are shown through the thumbnails written by `export_blobs`.
+fn game_page(
+    game: &saved::GameTextInformation,
+    offline_data: Option<&std::collections::HashMap<String, storage::Blob>>,
+    thumbnails: bool,
+) -> templates::GamePage {
+    let thumbnail = |link: &str| {
+        link.strip_prefix("imgs/")
+            .filter(|_| thumbnails)
+            .map(|x| format!("imgs/thumbs/{}", x))
+    };
+    fn content_constructor(
+        content: &[saved::ParagraphContent],
+        offline_data: Option<&std::collections::HashMap<String, storage::Blob>>,
+        thumbnail: &dyn Fn(&str) -> Option<String>,
+    ) -> Vec<templates::Content> {
+        content
+            .iter()
+            .map(|x| match x {
+                saved::ParagraphContent::Text(i) => templates::Content {
+                    lines: i.lines().map(String::from).collect(),
+                    image: None,
+                    thumbnail: None,
+                },
+                saved::ParagraphContent::Image(i) => {
+                    let image = find_offline_data_or_use_remove(i.to_string(), offline_data);
+                    templates::Content {
+                        lines: Vec::new(),
+                        thumbnail: thumbnail(&image),
+                        image: Some(image),
+                    }
                 }
-                content.push('\n');
-            }
-            let formatted = format!(
-                "\n<article> <img src=\"{}\" width=\"10%\"> {} said on {}:\n<p>{}</p>\n{}</article>",
-                avatar_link,
-                comment.author,
-                comment.date.to_string(),
-                content,
-                comment
-                    .replies
-                    .iter()
-                    .map(|x| comments_constructor(x, offline_data))
-                    .collect::<Vec<_>>()
-                    .join("\n")
-            );
-            formatted
-            //formatted.replace("\n", "\n")
-        };
-        game_detail.push_str(&format!(
-            "<h3>Comments</h3>\n{}",
-            game.comments
+            })
+            .collect()
+    }
+    fn comments_constructor(
+        comment: &saved::Comment,
+        offline_data: Option<&std::collections::HashMap<String, storage::Blob>>,
+        thumbnail: &dyn Fn(&str) -> Option<String>,
+    ) -> templates::Comment {
+        let avatar = find_offline_data_or_use_remove(comment.user_avatar.clone(), offline_data);
+        templates::Comment {
+            avatar_thumbnail: thumbnail(&avatar),
+            avatar,
+            author: comment.author.clone(),
+            date: comment.date.to_string(),
+            content: content_constructor(&comment.content, offline_data, thumbnail),
+            replies: comment
+                .replies
                 .iter()
-                .map(|x| comments_constructor(x, offline_data))
-                .collect::<Vec<_>>()
-                .join("\n")
+                .map(|x| comments_constructor(x, offline_data, thumbnail))
+                .collect(),
+        }
+    }
+    templates::GamePage {
+        root: String::new(),
+        name: game.name.clone(),
+        id: game.id,
+        website: game.website.clone(),
+        website_href: format!(
+            "sites/{}.html",
+            url_encode(&listing_file_name(&game.website))
+        ),
+        published: game.published.to_string(),
+        viewed: game.viewed,
+        tags: game
+            .tags
+            .iter()
+            .map(|x| templates::Link {
+                name: x.clone(),
+                href: format!("tags/{}.html", url_encode(&listing_file_name(x))),
+            })
+            .collect(),
+        paragraphs: game
+            .paragraphs
+            .iter()
+            .map(|x| templates::Paragraph {
+                title: x.0.clone(),
+                content: content_constructor(&x.1, offline_data, &thumbnail),
+            })
+            .collect(),
+        files: game
+            .files
+            .iter()
+            .map(|x| templates::File {
+                name: x.0.clone(),
+                link: x.1 .0.clone(),
+                size: x.1 .1.map(|i| {
+                    byte_unit::Byte::from_bytes(i)
+                        .get_appropriate_unit(true)
+                        .to_string()
+                }),
+            })
+            .collect(),
+        comments: game
+            .comments
+            .iter()
+            .map(|x| comments_constructor(x, offline_data, &thumbnail))
+            .collect(),
+    }
+}
+/// Index pages in every sort order, plus one page per tag and per website.
+fn html_listing_generator(
+    database: &saved::GameTextDatabase,
+    templates: &templates::Templates,
+) -> Result<Vec<(String, String, Vec<manifest::GameKey>)>, String> {
+    fn game_keys(games: &[&saved::GameTextInformation]) -> Vec<manifest::GameKey> {
+        games.iter().map(|x| manifest::game_key(x)).collect()
+    }
+    fn listing_page(
+        root: &str,
+        title: String,
+        sites: Vec<templates::Link>,
+        sort: Option<&str>,
+        games: &[&saved::GameTextInformation],
+        templates: &templates::Templates,
+    ) -> Result<String, String> {
+        templates.listing(&templates::ListingPage {
+            root: root.to_string(),
+            title,
+            search: None,
+            query: None,
+            sites,
+            sort: sort.map(String::from),
+            game_count: games.len(),
+            games: games
+                .iter()
+                .map(|x| templates::ListedGame {
+                    href: format!("{}{}.html", root, url_encode(&to_legal_name(&x.name, x.id))),
+                    name: x.name.clone(),
+                    id: x.id,
+                    website: x.website.clone(),
+                    published: x.published.to_string(),
+                    viewed: x.viewed,
+                })
+                .collect(),
+            pages: None,
+        })
+    }
+    let mut newest: Vec<&saved::GameTextInformation> = database.iter().collect();
+    newest.sort_by(|x, y| y.published.cmp(&x.published));
+    let mut most_viewed = newest.clone();
+    most_viewed.sort_by(|x, y| y.viewed.cmp(&x.viewed));
+    let mut by_name = newest.clone();
+    by_name.sort_by(|x, y| x.name.cmp(&y.name));
+    let mut tags: std::collections::BTreeMap<&str, Vec<&saved::GameTextInformation>> =
+        std::collections::BTreeMap::new();
+    let mut sites: std::collections::BTreeMap<&str, Vec<&saved::GameTextInformation>> =
+        std::collections::BTreeMap::new();
+    for game in &newest {
+        for tag in &game.tags {
+            tags.entry(tag).or_default().push(game);
+        }
+        sites.entry(&game.website).or_default().push(game);
+    }
+    let mut constructed = Vec::new();
+    for (file, order, games) in [
+        ("index", "published", &newest),
+        ("index_viewed", "viewed", &most_viewed),
+        ("index_name", "name", &by_name),
+    ]
+    .iter()
+    {
+        let site_links = sites
+            .keys()
+            .map(|x| templates::Link {
+                name: x.to_string(),
+                href: format!("sites/{}.html", url_encode(&listing_file_name(x))),
+            })
+            .collect();
+        constructed.push((
+            file.to_string(),
+            listing_page(
+                "",
+                String::from("Galgames"),
+                site_links,
+                Some(order),
+                games,
+                templates,
+            )?,
+            game_keys(games),
         ));
-        constructed.push((to_legal_name(&game.name, game.id), game_detail))
     }
-    constructed
+    constructed.push((
+        String::from("tags"),
+        templates.tags(&templates::TagsPage {
+            root: String::new(),
+            tags: tags
+                .iter()
+                .map(|(tag, games)| templates::ListedTag {
+                    name: tag.to_string(),
+                    href: format!("tags/{}.html", url_encode(&listing_file_name(tag))),
+                    count: games.len(),
+                })
+                .collect(),
+        })?,
+        game_keys(&newest),
+    ));
+    for (tag, games) in &tags {
+        constructed.push((
+            format!("tags/{}", listing_file_name(tag)),
+            listing_page(
+                "../",
+                format!("Tag: {}", tag),
+                Vec::new(),
+                None,
+                games,
+                templates,
+            )?,
+            game_keys(games),
+        ));
+    }
+    for (website, games) in &sites {
+        constructed.push((
+            format!("sites/{}", listing_file_name(website)),
+            listing_page(
+                "../",
+                format!("Website: {}", website),
+                Vec::new(),
+                None,
+                games,
+                templates,
+            )?,
+            game_keys(games),
+        ));
+    }
+    Ok(constructed)
+}
+fn listing_file_name(name: &str) -> String {
+    name.replace(|x: char| (x == '/') | (x == '\u{0000}'), "_")
+}
+fn url_encode(text: &str) -> String {
+    const UNRESERVED: percent_encoding::AsciiSet = percent_encoding::NON_ALPHANUMERIC
+        .remove(b'-')
+        .remove(b'.')
+        .remove(b'_')
+        .remove(b'~');
+    percent_encoding::utf8_percent_encode(text, &UNRESERVED).to_string()
 }
 fn markdown_generator(
     database: &saved::GameTextDatabase,
-    offline_data: Option<&saved::GameBinaryDatabase>,
-) -> Vec<(String, String)> {
+    offline_data: Option<&std::collections::HashMap<String, storage::Blob>>,
+    front_matter: bool,
+) -> Vec<(String, String, Vec<manifest::GameKey>)> {
+    fn content_constructor(
+        writer: &mut markdown::Writer,
+        content: &[saved::ParagraphContent],
+        offline_data: Option<&std::collections::HashMap<String, storage::Blob>>,
+    ) {
+        for i in content {
+            match i {
+                saved::ParagraphContent::Text(i) => writer.text(i),
+                saved::ParagraphContent::Image(i) => writer.image(
+                    "image",
+                    &find_offline_data_or_use_remove(i.to_string(), offline_data),
+                ),
+            }
+        }
+    }
+    fn comments_constructor(
+        writer: &mut markdown::Writer,
+        comment: &saved::Comment,
+        depth: usize,
+        offline_data: Option<&std::collections::HashMap<String, storage::Blob>>,
+    ) {
+        writer.set_depth(depth);
+        writer.raw(&format!(
+            "{} {} said on {}:",
+            markdown::image(
+                "avatar",
+                &find_offline_data_or_use_remove(comment.user_avatar.clone(), offline_data)
+            ),
+            markdown::escape(&comment.author),
+            comment.date.to_string()
+        ));
+        content_constructor(writer, &comment.content, offline_data);
+        for reply in &comment.replies {
+            comments_constructor(writer, reply, depth + 1, offline_data);
+        }
+    }
     let mut constructed = Vec::new();
     for game in database {
-        let mut game_detail = format!("# {}\n\n", game.name);
-        game_detail.push_str(&format!(
-            "> id: {}@{} | published at {} | {} viewed\n\n",
+        let mut writer = markdown::Writer::new();
+        if front_matter {
+            writer.front_matter(&[
+                ("title", serde_json::json!(game.name)),
+                // As a string, since ids don't fit in the integers of most parsers
+                ("id", serde_json::json!(game.id.to_string())),
+                ("site", serde_json::json!(game.website)),
+                ("tags", serde_json::json!(game.tags)),
+                (
+                    "published",
+                    serde_json::json!(game.published.format("%FT%H:%M:%S")),
+                ),
+                ("viewed", serde_json::json!(game.viewed)),
+            ]);
+        }
+        writer.heading(1, &game.name);
+        writer.set_depth(1);
+        writer.raw(&format!(
+            "id: {}@{} | published at {} | {} viewed",
             game.id.to_string(),
-            game.website,
+            markdown::escape(&game.website),
             game.published.to_string(),
             game.viewed
         ));
-        game_detail.push_str("Tags: ");
-        game_detail.push_str(&game.tags.join(", "));
-        game_detail.push_str("\n\n");
+        writer.set_depth(0);
+        writer.raw(&format!(
+            "Tags: {}",
+            game.tags
+                .iter()
+                .map(|x| markdown::escape(x))
+                .collect::<Vec<_>>()
+                .join(", ")
+        ));
         for paragraph in &game.paragraphs {
-            let title = if let Some(i) = &paragraph.0 {
-                format!("## {}", i)
+            if let Some(i) = &paragraph.0 {
+                writer.heading(2, i);
             } else {
-                String::from("---")
-            };
-            let mut content_rendered = String::new();
-            for content in &paragraph.1 {
-                match content {
-                    saved::ParagraphContent::Text(i) => content_rendered.push_str(i),
-                    saved::ParagraphContent::Image(i) => content_rendered.push_str(&format!(
-                        "![image]({})",
-                        find_offline_data_or_use_remove(i.to_string(), offline_data)
-                    )),
-                }
-                content_rendered.push('\n')
+                writer.rule();
             }
-            game_detail.push_str(&format!("{}\n{}\n\n", title, content_rendered));
+            content_constructor(&mut writer, &paragraph.1, offline_data);
         }
-        game_detail.push_str(&format!(
-            "## Downloads\n{}\n\n",
-            game.files
+        writer.heading(2, "Downloads");
+        writer.list(
+            &game
+                .files
                 .iter()
-                .map(|x| format!(
-                    "- {}\n  {} {}",
-                    x.0,
-                    x.1 .0,
-                    if let Some(i) = x.1 .1 {
-                        byte_unit::Byte::from_bytes(i)
-                            .get_appropriate_unit(true)
-                            .to_string()
-                    } else {
-                        String::new()
-                    }
-                ))
-                .collect::<Vec<_>>()
-                .join("\n")
-        ));
-        fn comments_constructor(
-            comment: &saved::Comment,
-            offline_data: Option<&saved::GameBinaryDatabase>,
-        ) -> String {
-            let avatar_link = if let Some(data) = offline_data {
-                if data.0.get(&comment.user_avatar).is_some() {
-                    let avatar_suffix_location = comment
-                        .user_avatar
-                        .rfind('.')
-                        .unwrap_or(comment.user_avatar.len() - 1);
+                .map(|x| {
                     format!(
-                        "imgs/{}.{}",
-                        hash_for_filename(&comment.user_avatar[..avatar_suffix_location]),
-                        &comment.user_avatar[avatar_suffix_location + 1..]
+                        "{} {}",
+                        markdown::link(&x.0, &x.1 .0),
+                        if let Some(i) = x.1 .1 {
+                            byte_unit::Byte::from_bytes(i)
+                                .get_appropriate_unit(true)
+                                .to_string()
+                        } else {
+                            String::new()
+                        }
                     )
-                } else {
-                    comment.user_avatar.clone()
-                }
-            } else {
-                comment.user_avatar.clone()
-            };
-            let mut content = String::new();
-            for i in &comment.content {
-                match i {
-                    saved::ParagraphContent::Text(i) => content.push_str(&i),
-                    saved::ParagraphContent::Image(i) => content.push_str(&format!(
-                        "![image]({})",
-                        find_offline_data_or_use_remove(i.to_string(), offline_data)
-                    )),
-                }
-                content.push('\n');
-            }
-            let formatted = format!(
-                "\n![avatar]({}) {} said on {}:\n{}\n{}",
-                avatar_link,
-                comment.author,
-                comment.date.to_string(),
-                content,
-                comment
-                    .replies
-                    .iter()
-                    .map(|x| comments_constructor(x, offline_data))
-                    .collect::<Vec<_>>()
-                    .join("\n")
-            );
-            formatted.replace("\n", "\n> ")
-        };
-        game_detail.push_str(&format!(
-            "## Comments\n{}\n\n",
-            game.comments
-                .iter()
-                .map(|x| comments_constructor(x, offline_data))
-                .collect::<Vec<_>>()
-                .join("\n")
-        ));
-        constructed.push((to_legal_name(&game.name, game.id), game_detail))
+                    .trim_end()
+                    .to_string()
+                })
+                .collect::<Vec<_>>(),
+        );
+        writer.heading(2, "Comments");
+        for comment in &game.comments {
+            comments_constructor(&mut writer, comment, 1, offline_data);
+            writer.set_depth(0);
+        }
+        constructed.push((
+            to_legal_name(&game.name, game.id),
+            writer.finish(),
+            vec![manifest::game_key(game)],
+        ))
     }
     constructed
 }
-fn hash_for_filename(text: &str) -> String {
-    let text = if text.len() > 64 {
-        &text[text.len() - 65..text.len()]
-    } else {
-        text
-    };
-    base64::encode_config(text, base64::URL_SAFE_NO_PAD)
+fn print_history(game: &saved::GameTextInformation, logging_client: &log::LoggingClient) {
+    fn list_change<T>(
+        change: &saved::ListChange<T>,
+        formatter: impl Fn(&T) -> String,
+    ) -> Vec<String> {
+        change
+            .added
+            .iter()
+            .map(|x| format!("+ {}", formatter(x)))
+            .chain(change.removed.iter().map(|x| format!("- {}", formatter(x))))
+            .collect()
+    }
+    fn paragraph_content(content: &[saved::ParagraphContent]) -> String {
+        content
+            .iter()
+            .map(|x| match x {
+                saved::ParagraphContent::Text(i) => i.replace('\n', " "),
+                saved::ParagraphContent::Image(i) => format!("[image {}]", i),
+            })
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+    logging_client.log(
+        log::LoggingLevel::Message,
+        &format!(
+            "{}@{} {}: {} revisions",
+            game.id,
+            game.website,
+            game.name,
+            game.revisions.len()
+        ),
+    );
+    for revision in &game.revisions {
+        let mut lines = Vec::new();
+        for change in &revision.changes {
+            match change {
+                saved::FieldChange::Name { before, after } => {
+                    lines.push(format!("name: {} => {}", before, after))
+                }
+                saved::FieldChange::Files(i) => {
+                    lines.extend(list_change(i, |x| format!("file {} ({})", x.0, x.1 .0)))
+                }
+                saved::FieldChange::Tags(i) => {
+                    lines.extend(list_change(i, |x| format!("tag {}", x)))
+                }
+                saved::FieldChange::Paragraphs(i) => lines.extend(list_change(i, |x| {
+                    format!(
+                        "paragraph {}: {}",
+                        x.0.as_deref().unwrap_or("-"),
+                        paragraph_content(&x.1)
+                    )
+                })),
+                saved::FieldChange::Comments(i) => lines.extend(list_change(i, |x| {
+                    format!(
+                        "comment by {} on {}: {}",
+                        x.author,
+                        x.date,
+                        paragraph_content(&x.content)
+                    )
+                })),
+            }
+        }
+        if revision.changes.len() == 0 {
+            lines.push(String::from("first fetched"));
+        }
+        logging_client.log(
+            log::LoggingLevel::Message,
+            &format!("{} UTC\n    {}", revision.fetched, lines.join("\n    ")),
+        );
+    }
 }
@@ -0,0 +1,267 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+/// Website and id of a game.
+pub type GameKey = (String, u64);
+pub fn game_key(game: &crate::saved::GameTextInformation) -> GameKey {
+    (game.website.clone(), game.id)
+}
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    hash: u64,
+    /// Games shown by the file, or whose pages show it
+    games: Vec<GameKey>,
+}
+/// Content hashes of the files an export wrote to a directory, so the next export only rewrites
+/// what changed and removes what it no longer produces. Html and markdown exports keep separate
+/// manifests, so they may share a directory.
+///
+/// Files of games which are still in the database but weren't selected are kept.
+pub struct Manifest {
+    location: String,
+    file: String,
+    previous: HashMap<String, Entry>,
+    current: HashMap<String, Entry>,
+    /// Files which couldn't be written, kept out of the manifest so they are retried next time
+    failed: HashSet<String>,
+}
+impl Manifest {
+    /// Starts from an empty manifest if the directory has none for `kind` or it can't be read.
+    pub fn load(location: &str, kind: &str) -> Self {
+        let file = format!(".bishojo-manifest-{}.json", kind);
+        let previous = std::fs::read(format!("{}/{}", location, file))
+            .ok()
+            .and_then(|x| serde_json::from_slice(&x).ok())
+            .unwrap_or_default();
+        Self {
+            location: location.to_string(),
+            file,
+            previous,
+            current: HashMap::new(),
+            failed: HashSet::new(),
+        }
+    }
+    /// Whether the previous export wrote the same content to `file`, which still exists.
+    pub fn unchanged(&self, file: &str, hash: u64) -> bool {
+        self.previous.get(file).map(|x| x.hash) == Some(hash)
+            && std::path::Path::new(&format!("{}/{}", self.location, file)).is_file()
+    }
+    pub fn record(&mut self, file: &str, hash: u64, mut games: Vec<GameKey>) {
+        // Pages of games this export didn't select may still show the file
+        if let Some(i) = self.previous.get(file) {
+            games.extend(i.games.iter().cloned());
+        }
+        games.sort();
+        games.dedup();
+        self.current.insert(file.to_string(), Entry { hash, games });
+    }
+    pub fn record_failure(&mut self, file: &str) {
+        self.failed.insert(file.to_string());
+    }
+    /// Deletes the files of the previous export which this one didn't write, unless they belong
+    /// to a game of `database` which isn't in `selected`, and saves the manifest. Returns the
+    /// deleted files.
+    pub fn finish(
+        mut self,
+        database: &crate::saved::GameTextDatabase,
+        selected: &crate::saved::GameTextDatabase,
+    ) -> Result<Vec<String>, String> {
+        let existing: HashSet<GameKey> = database.iter().map(game_key).collect();
+        let selected: HashSet<GameKey> = selected.iter().map(game_key).collect();
+        let mut removed = Vec::new();
+        for (file, entry) in std::mem::take(&mut self.previous) {
+            if self.current.contains_key(&file) || self.failed.contains(&file) {
+                continue;
+            }
+            if entry
+                .games
+                .iter()
+                .any(|x| existing.contains(x) && !selected.contains(x))
+            {
+                self.current.insert(file, entry);
+                continue;
+            }
+            match std::fs::remove_file(format!("{}/{}", self.location, file)) {
+                Ok(_) => removed.push(file),
+                Err(i) if i.kind() == std::io::ErrorKind::NotFound => (),
+                Err(i) => return Err(format!("{}: {}", file, i)),
+            }
+        }
+        for entry in self.current.values_mut() {
+            entry.games.retain(|x| existing.contains(x));
+        }
+        std::fs::write(
+            format!("{}/{}", self.location, self.file),
+            serde_json::to_vec(&self.current).map_err(|x| x.to_string())?,
+        )
+        .map_err(|x| x.to_string())?;
+        Ok(removed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{game_key, Manifest};
+    use crate::saved::{GameTextDatabase, GameTextInformation};
+    fn directory(name: &str) -> String {
+        let directory = std::env::temp_dir().join(format!("bishojo-manifest-{}", name));
+        let _ = std::fs::remove_dir_all(&directory);
+        std::fs::create_dir_all(&directory).unwrap();
+        directory.to_str().unwrap().to_string()
+    }
+    fn game(id: u64) -> GameTextInformation {
+        GameTextInformation::default(id, "kkgal".to_string())
+    }
+    /// Writes the files and records them as an export of `selected` would.
+    fn export(
+        location: &str,
+        files: &[(&str, &str, &GameTextInformation)],
+        database: &GameTextDatabase,
+        selected: &GameTextDatabase,
+    ) -> Vec<String> {
+        let mut manifest = Manifest::load(location, "html");
+        for (file, content, game) in files {
+            let hash = seahash::hash(content.as_bytes());
+            if !manifest.unchanged(file, hash) {
+                std::fs::write(format!("{}/{}", location, file), content).unwrap();
+            }
+            manifest.record(file, hash, vec![game_key(game)]);
+        }
+        let mut removed = manifest.finish(database, selected).unwrap();
+        removed.sort();
+        removed
+    }
+    #[test]
+    fn skips_unchanged_files() {
+        let location = directory("unchanged");
+        let database = vec![game(1)];
+        export(
+            &location,
+            &[("1.html", "first", &game(1))],
+            &database,
+            &database,
+        );
+        let manifest = Manifest::load(&location, "html");
+        assert!(manifest.unchanged("1.html", seahash::hash(b"first")));
+        assert!(!manifest.unchanged("2.html", seahash::hash(b"first")));
+        // Files deleted by hand are written again
+        std::fs::remove_file(format!("{}/1.html", location)).unwrap();
+        assert!(!manifest.unchanged("1.html", seahash::hash(b"first")));
+        // Other kinds of export keep their own manifest
+        assert!(!Manifest::load(&location, "markdown").unchanged("1.html", seahash::hash(b"first")));
+    }
+    #[test]
+    fn rewrites_changed_files() {
+        let location = directory("changed");
+        let database = vec![game(1)];
+        export(
+            &location,
+            &[("1.html", "first", &game(1))],
+            &database,
+            &database,
+        );
+        let manifest = Manifest::load(&location, "html");
+        assert!(!manifest.unchanged("1.html", seahash::hash(b"second")));
+        export(
+            &location,
+            &[("1.html", "second", &game(1))],
+            &database,
+            &database,
+        );
+        assert_eq!(
+            std::fs::read_to_string(format!("{}/1.html", location)).unwrap(),
+            "second"
+        );
+        let manifest = Manifest::load(&location, "html");
+        assert!(manifest.unchanged("1.html", seahash::hash(b"second")));
+        assert!(!manifest.unchanged("1.html", seahash::hash(b"first")));
+    }
+    #[test]
+    fn deletes_files_of_removed_games() {
+        let location = directory("removed");
+        let database = vec![game(1), game(2)];
+        export(
+            &location,
+            &[
+                ("1.html", "first", &game(1)),
+                ("2.html", "second", &game(2)),
+            ],
+            &database,
+            &database,
+        );
+        let database = vec![game(1)];
+        let removed = export(
+            &location,
+            &[("1.html", "first", &game(1))],
+            &database,
+            &database,
+        );
+        assert_eq!(removed, vec!["2.html".to_string()]);
+        assert!(!std::path::Path::new(&format!("{}/2.html", location)).exists());
+        assert!(std::path::Path::new(&format!("{}/1.html", location)).is_file());
+        // The deleted file is forgotten, so it isn't reported again
+        assert!(export(
+            &location,
+            &[("1.html", "first", &game(1))],
+            &database,
+            &database
+        )
+        .is_empty());
+    }
+    #[test]
+    fn keeps_files_of_unselected_games() {
+        let location = directory("unselected");
+        let database = vec![game(1), game(2)];
+        export(
+            &location,
+            &[
+                ("1.html", "first", &game(1)),
+                ("2.html", "second", &game(2)),
+            ],
+            &database,
+            &database,
+        );
+        // Filtering out a game which is still in the database keeps its page
+        let selected = vec![game(1)];
+        let removed = export(
+            &location,
+            &[("1.html", "first", &game(1))],
+            &database,
+            &selected,
+        );
+        assert!(removed.is_empty());
+        assert!(std::path::Path::new(&format!("{}/2.html", location)).is_file());
+        // and so does a later export, until the game leaves the database
+        export(
+            &location,
+            &[("1.html", "first", &game(1))],
+            &database,
+            &selected,
+        );
+        assert!(std::path::Path::new(&format!("{}/2.html", location)).is_file());
+        let database = vec![game(1)];
+        let removed = export(
+            &location,
+            &[("1.html", "first", &game(1))],
+            &database,
+            &selected,
+        );
+        assert_eq!(removed, vec!["2.html".to_string()]);
+    }
+    #[test]
+    fn keeps_files_which_failed_to_be_written() {
+        let location = directory("failed");
+        let database = vec![game(1)];
+        export(
+            &location,
+            &[("1.html", "first", &game(1))],
+            &database,
+            &database,
+        );
+        let mut manifest = Manifest::load(&location, "html");
+        manifest.record_failure("1.html");
+        assert!(manifest.finish(&database, &database).unwrap().is_empty());
+        assert!(std::path::Path::new(&format!("{}/1.html", location)).is_file());
+        // It is left out of the manifest so the next export writes it again
+        assert!(!Manifest::load(&location, "html").unchanged("1.html", seahash::hash(b"first")));
+    }
+}
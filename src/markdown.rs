@@ -0,0 +1,205 @@
+/// Builds a markdown document block by block, escaping the text it is given and prefixing every
+/// line with the current quote depth.
+pub struct Writer {
+    output: String,
+    depth: usize,
+    /// Shallowest quote depth since the last written line, where the next separator goes
+    separator_depth: usize,
+    /// Whether a block has been written since the start of the document or its front matter
+    needs_separator: bool,
+}
+impl Writer {
+    pub fn new() -> Self {
+        Self {
+            output: String::new(),
+            depth: 0,
+            separator_depth: 0,
+            needs_separator: false,
+        }
+    }
+    /// Blocks written afterwards are nested in `depth` block quotes.
+    pub fn set_depth(&mut self, depth: usize) {
+        self.depth = depth;
+        self.separator_depth = self.separator_depth.min(depth);
+    }
+    /// YAML front matter. Strings are written as JSON which is valid YAML.
+    pub fn front_matter(&mut self, fields: &[(&str, serde_json::Value)]) {
+        self.output.push_str("---\n");
+        for (key, value) in fields {
+            self.output.push_str(&format!("{}: {}\n", key, value));
+        }
+        self.output.push_str("---\n");
+        self.needs_separator = false;
+    }
+    pub fn heading(&mut self, level: usize, text: &str) {
+        self.separate();
+        let line = format!(
+            "{} {}",
+            "#".repeat(level),
+            escape(&text.split_whitespace().collect::<Vec<_>>().join(" "))
+        );
+        self.line(&line);
+    }
+    /// Lines become hard line breaks and empty lines start a new paragraph.
+    pub fn text(&mut self, text: &str) {
+        let lines: Vec<&str> = text.lines().map(str::trim).collect();
+        for paragraph in lines.split(|x| x.len() == 0).filter(|x| x.len() != 0) {
+            self.separate();
+            for (position, line) in paragraph.iter().enumerate() {
+                let mut escaped = escape_line(line);
+                if position + 1 != paragraph.len() {
+                    escaped.push('\\');
+                }
+                self.line(&escaped);
+            }
+        }
+    }
+    /// A paragraph made of inline markdown, which is written as is.
+    pub fn raw(&mut self, markdown: &str) {
+        self.separate();
+        self.line(markdown);
+    }
+    pub fn image(&mut self, alt: &str, link: &str) {
+        self.raw(&image(alt, link));
+    }
+    /// A bullet list of inline markdown items.
+    pub fn list(&mut self, items: &[String]) {
+        if items.len() == 0 {
+            return;
+        }
+        self.separate();
+        for item in items {
+            self.line(&format!("- {}", item));
+        }
+    }
+    pub fn rule(&mut self) {
+        self.separate();
+        self.line("---");
+    }
+    pub fn finish(mut self) -> String {
+        self.output.push('\n');
+        self.output
+    }
+    fn separate(&mut self) {
+        if self.needs_separator {
+            self.output
+                .push_str("> ".repeat(self.separator_depth).trim_end());
+            self.output.push('\n');
+        }
+    }
+    fn line(&mut self, line: &str) {
+        self.output.push_str(&"> ".repeat(self.depth));
+        self.output.push_str(line);
+        self.output.push('\n');
+        self.separator_depth = self.depth;
+        self.needs_separator = true;
+    }
+}
+/// Escapes the characters which have a meaning anywhere in a line.
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for i in text.chars() {
+        if "\\`*_[]<>#|~&".contains(i) {
+            escaped.push('\\');
+        }
+        escaped.push(i);
+    }
+    escaped
+}
+/// Escapes a whole line, including the characters which would start a list, a heading or a rule.
+fn escape_line(line: &str) -> String {
+    let escaped = escape(line);
+    if escaped.starts_with(|x| "+-=".contains(x)) {
+        return format!("\\{}", escaped);
+    }
+    // Ordered list: digits followed by `.` or `)`
+    let digits = escaped.len()
+        - escaped
+            .trim_start_matches(|x: char| x.is_ascii_digit())
+            .len();
+    if digits != 0 && escaped[digits..].starts_with(|x| x == '.' || x == ')') {
+        return format!("{}\\{}", &escaped[..digits], &escaped[digits..]);
+    }
+    escaped
+}
+pub fn link(text: &str, link: &str) -> String {
+    format!("[{}]({})", escape(text), destination(link))
+}
+pub fn image(alt: &str, link: &str) -> String {
+    format!("!{}", self::link(alt, link))
+}
+fn destination(link: &str) -> String {
+    format!(
+        "<{}>",
+        link.replace('<', "%3C")
+            .replace('>', "%3E")
+            .replace('\n', "%0A")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{escape, escape_line, link, Writer};
+    #[test]
+    fn escapes_inline_markup() {
+        assert_eq!(
+            escape("a*b_c [x](y) <t> &amp; | ~ `#` \\"),
+            "a\\*b\\_c \\[x\\](y) \\<t\\> \\&amp; \\| \\~ \\`\\#\\` \\\\"
+        );
+        assert_eq!(escape("普通の文章"), "普通の文章");
+    }
+    #[test]
+    fn escapes_line_starts() {
+        assert_eq!(escape_line("- item"), "\\- item");
+        assert_eq!(escape_line("+ item"), "\\+ item");
+        assert_eq!(escape_line("==="), "\\===");
+        assert_eq!(escape_line("12. twelve"), "12\\. twelve");
+        assert_eq!(escape_line("3) three"), "3\\) three");
+        assert_eq!(escape_line("# heading"), "\\# heading");
+        assert_eq!(escape_line("2020 was"), "2020 was");
+    }
+    #[test]
+    fn keeps_link_destinations_in_angle_brackets() {
+        assert_eq!(
+            link("a]b", "http://x/<y>\nz"),
+            "[a\\]b](<http://x/%3Cy%3E%0Az>)"
+        );
+    }
+    #[test]
+    fn writes_hard_breaks_and_paragraphs() {
+        let mut writer = Writer::new();
+        writer.heading(2, "Title\nwith  spaces");
+        writer.text("a\n b \n\n\nc");
+        assert_eq!(writer.finish(), "## Title with spaces\n\na\\\nb\n\nc\n\n");
+    }
+    #[test]
+    fn nests_replies_in_quotes() {
+        let mut writer = Writer::new();
+        writer.text("Hello");
+        writer.set_depth(1);
+        writer.text("Reply");
+        writer.set_depth(2);
+        writer.text("Nested");
+        writer.set_depth(1);
+        writer.text("Second reply");
+        writer.set_depth(0);
+        writer.text("Next");
+        assert_eq!(
+            writer.finish(),
+            "Hello\n\n> Reply\n>\n> > Nested\n>\n> Second reply\n\nNext\n\n"
+        );
+    }
+    #[test]
+    fn starts_with_front_matter() {
+        let mut writer = Writer::new();
+        writer.front_matter(&[
+            ("title", serde_json::json!("A \"B\": c")),
+            ("tags", serde_json::json!(["x", "y"])),
+        ]);
+        writer.heading(1, "A");
+        assert_eq!(
+            writer.finish(),
+            "---\ntitle: \"A \\\"B\\\": c\"\ntags: [\"x\",\"y\"]\n---\n# A\n\n"
+        );
+    }
+}
@@ -0,0 +1,217 @@
+use crate::error::Error;
+use std::time::Duration;
+/// Errors which retrying may fix, see `Error::is_retryable`.
+#[derive(Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RetryClass {
+    Transport,
+    Http,
+    Image,
+    Challenge,
+}
+impl RetryClass {
+    fn matches(self, error: &Error) -> bool {
+        matches!(
+            (self, error),
+            (Self::Transport, Error::Transport(_))
+                | (Self::Http, Error::Response(_))
+                | (Self::Image, Error::InvalidImage { .. })
+                | (Self::Challenge, Error::Challenge { .. })
+        )
+    }
+}
+impl std::str::FromStr for RetryClass {
+    type Err = &'static str;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "transport" => Ok(Self::Transport),
+            "http" => Ok(Self::Http),
+            "image" => Ok(Self::Image),
+            "challenge" => Ok(Self::Challenge),
+            _ => Err("Unknown error class"),
+        }
+    }
+}
+/// How failed requests are tried again: up to `retries` times, waiting twice as long before
+/// each retry, from `delay` up to `max_delay`.
+#[derive(Clone)]
+pub struct RetryPolicy {
+    pub retries: usize,
+    pub delay: Duration,
+    pub max_delay: Duration,
+    pub retry_on: Vec<RetryClass>,
+}
+impl RetryPolicy {
+    /// Whether to try again after `retry` retries ended with `error`.
+    pub fn should_retry(&self, retry: usize, error: &Error) -> bool {
+        retry < self.retries
+            && error.is_retryable()
+            && self.retry_on.iter().any(|x| x.matches(error))
+    }
+    /// Waits between half and all of the backoff, so that jobs which failed together don't all
+    /// retry at the same time. A longer `Retry-After` from the server is honoured up to
+    /// `max_delay`.
+    pub fn delay(&self, retry: usize, error: &Error) -> Duration {
+        let backoff = self
+            .delay
+            .checked_mul(2u32.saturating_pow(retry as u32))
+            .unwrap_or(self.max_delay)
+            .min(self.max_delay);
+        let delay = backoff / 2 + backoff.mul_f64(fastrand::f64() / 2.0);
+        match error.retry_after() {
+            Some(i) => delay.max(i.min(self.max_delay)),
+            None => delay,
+        }
+    }
+}
+/// Retry settings of one website, e.g. `kkgal:retries=5,delay=2000,max-delay=120000,on=http`.
+/// Delays are in milliseconds, error classes are separated by `+`. Settings which aren't given
+/// are left to the global policy.
+pub struct SiteRetryPolicy {
+    pub site: crate::cli::AvailableWebsite,
+    retries: Option<usize>,
+    delay: Option<Duration>,
+    max_delay: Option<Duration>,
+    retry_on: Option<Vec<RetryClass>>,
+}
+impl SiteRetryPolicy {
+    pub fn apply(&self, policy: RetryPolicy) -> RetryPolicy {
+        RetryPolicy {
+            retries: self.retries.unwrap_or(policy.retries),
+            delay: self.delay.unwrap_or(policy.delay),
+            max_delay: self.max_delay.unwrap_or(policy.max_delay),
+            retry_on: self.retry_on.clone().unwrap_or(policy.retry_on),
+        }
+    }
+}
+impl std::str::FromStr for SiteRetryPolicy {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (site, settings) = s
+            .split_once(':')
+            .ok_or(String::from("Expected <site>:<setting>=<value>,..."))?;
+        let mut policy = Self {
+            site: site.parse()?,
+            retries: None,
+            delay: None,
+            max_delay: None,
+            retry_on: None,
+        };
+        for setting in settings.split(',') {
+            let (name, value) = setting
+                .split_once('=')
+                .ok_or(format!("Expected <setting>=<value>, found {}", setting))?;
+            let milliseconds = || {
+                value
+                    .parse()
+                    .map(Duration::from_millis)
+                    .map_err(|x| format!("{}: {}", name, x))
+            };
+            match name {
+                "retries" => {
+                    policy.retries = Some(value.parse().map_err(|x| format!("{}: {}", name, x))?)
+                }
+                "delay" => policy.delay = Some(milliseconds()?),
+                "max-delay" => policy.max_delay = Some(milliseconds()?),
+                "on" => {
+                    policy.retry_on = Some(
+                        value
+                            .split('+')
+                            .map(|x| x.parse())
+                            .collect::<Result<_, _>>()?,
+                    )
+                }
+                _ => return Err(format!("Unknown retry setting {}", name)),
+            }
+        }
+        Ok(policy)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RetryClass, RetryPolicy, SiteRetryPolicy};
+    use crate::error::{Error, ResponseError};
+    use std::time::Duration;
+    fn policy() -> RetryPolicy {
+        RetryPolicy {
+            retries: 3,
+            delay: Duration::from_millis(1000),
+            max_delay: Duration::from_millis(5000),
+            retry_on: vec![RetryClass::Http],
+        }
+    }
+    fn response(status: u16, retry_after: Option<u64>) -> Error {
+        Error::Response(ResponseError {
+            status: isahc::http::StatusCode::from_u16(status).unwrap(),
+            url: String::from("https://example.com"),
+            content_type: None,
+            snippet: String::new(),
+            retry_after: retry_after.map(Duration::from_secs),
+        })
+    }
+    #[test]
+    fn backs_off_with_jitter_up_to_the_maximum() {
+        let policy = policy();
+        let error = response(503, None);
+        for _ in 0..100 {
+            for (retry, backoff) in [(0, 1000), (1, 2000), (2, 4000), (3, 5000), (40, 5000)] {
+                let delay = policy.delay(retry, &error);
+                assert!(delay >= Duration::from_millis(backoff / 2));
+                assert!(delay <= Duration::from_millis(backoff));
+            }
+        }
+    }
+    #[test]
+    fn honours_retry_after_up_to_the_maximum() {
+        let policy = policy();
+        assert_eq!(
+            policy.delay(0, &response(429, Some(3))),
+            Duration::from_secs(3)
+        );
+        assert_eq!(
+            policy.delay(0, &response(429, Some(3600))),
+            Duration::from_secs(5)
+        );
+        // A shorter one doesn't cut the backoff
+        assert!(policy.delay(2, &response(429, Some(0))) >= Duration::from_secs(2));
+    }
+    #[test]
+    fn retries_selected_classes_only() {
+        let policy = policy();
+        assert!(policy.should_retry(0, &response(503, None)));
+        assert!(policy.should_retry(2, &response(429, None)));
+        assert!(!policy.should_retry(3, &response(503, None)));
+        assert!(!policy.should_retry(0, &response(404, None)));
+        assert!(!policy.should_retry(
+            0,
+            &Error::Challenge {
+                url: String::from("https://example.com"),
+                message: String::new(),
+            }
+        ));
+    }
+    #[test]
+    fn parses_site_policies() {
+        let site: SiteRetryPolicy = "kkgal:retries=5,max-delay=120000,on=http+image"
+            .parse()
+            .unwrap();
+        assert!(site.site == crate::cli::AvailableWebsite::KKGal);
+        let policy = site.apply(policy());
+        assert_eq!(policy.retries, 5);
+        assert_eq!(policy.delay, Duration::from_millis(1000));
+        assert_eq!(policy.max_delay, Duration::from_secs(120));
+        assert!(policy.retry_on == [RetryClass::Http, RetryClass::Image]);
+        for invalid in [
+            "kkgal",
+            "other:retries=5",
+            "kkgal:retries",
+            "kkgal:retries=-1",
+            "kkgal:delay=1s",
+            "kkgal:on=http+parse",
+            "kkgal:jitter=0",
+        ] {
+            assert!(invalid.parse::<SiteRetryPolicy>().is_err(), "{}", invalid);
+        }
+    }
+}
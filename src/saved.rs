@@ -1,3 +1,4 @@
+use crate::error::Error;
 use serde::{Deserialize, Serialize};
 use serde_bytes::ByteBuf;
 use std::collections::HashMap;
@@ -14,6 +15,8 @@ pub struct GameTextInformation {
     pub miscellaneous: HashMap<String, String>,
     pub files: Vec<(String, (String, Option<u128>))>,
     pub comments: Vec<Comment>,
+    #[serde(default)]
+    pub revisions: Vec<Revision>,
 }
 impl GameTextInformation {
     pub fn default(id: u64, website: String) -> Self {
@@ -28,16 +31,203 @@ impl GameTextInformation {
             miscellaneous: std::collections::HashMap::new(),
             files: Vec::default(),
             comments: Vec::default(),
+            revisions: Vec::default(),
         }
     }
+    /// Every image and avatar shown by the pages of the game.
+    pub fn get_images(&self) -> Vec<String> {
+        let mut images: Vec<String> = self
+            .paragraphs
+            .iter()
+            .flat_map(|x| x.1.iter())
+            .filter_map(|x| match x {
+                ParagraphContent::Image(i) => Some(i.clone()),
+                ParagraphContent::Text(_) => None,
+            })
+            .collect();
+        for i in &self.comments {
+            images.append(&mut i.get_images());
+            images.append(&mut i.get_avatars());
+        }
+        images
+    }
+    /// Merges a newer fetch of the same game into this one. Newer values win, but empty lists
+    /// don't wipe what was fetched before, since they usually mean that part failed to parse.
+    ///
+    /// Returns whether a tracked field changed, recorded as a new revision.
+    pub fn merge(&mut self, newer: GameTextInformation) -> bool {
+        // Only the tracked fields are compared, copying the history as well would make every
+        // merge slower than the last
+        let revisions = std::mem::take(&mut self.revisions);
+        let older = self.clone();
+        self.revisions = revisions;
+        self.name = newer.name;
+        self.published = newer.published;
+        self.viewed = newer.viewed;
+        if newer.tags.len() != 0 {
+            self.tags = newer.tags;
+        }
+        if newer.paragraphs.len() != 0 {
+            self.paragraphs = newer.paragraphs;
+        }
+        if newer.files.len() != 0 {
+            self.files = newer.files;
+        }
+        if newer.comments.len() != 0 {
+            self.comments = newer.comments;
+        }
+        self.miscellaneous.extend(newer.miscellaneous);
+        let changes = older.diff(self);
+        if changes.len() == 0 {
+            return false;
+        }
+        self.revisions.push(Revision {
+            fetched: now(),
+            changes,
+        });
+        true
+    }
+    /// Lists what changed in the tracked fields between this game and `newer`.
+    pub fn diff(&self, newer: &GameTextInformation) -> Vec<FieldChange> {
+        let mut changes = Vec::new();
+        if self.name != newer.name {
+            changes.push(FieldChange::Name {
+                before: self.name.clone(),
+                after: newer.name.clone(),
+            });
+        }
+        if let Some(i) = ListChange::between(&self.files, &newer.files) {
+            changes.push(FieldChange::Files(i));
+        }
+        if let Some(i) = ListChange::between(&self.tags, &newer.tags) {
+            changes.push(FieldChange::Tags(i));
+        }
+        if let Some(i) = ListChange::between(&self.paragraphs, &newer.paragraphs) {
+            changes.push(FieldChange::Paragraphs(i));
+        }
+        if let Some(i) = ListChange::between(
+            &Comment::flatten(&self.comments),
+            &Comment::flatten(&newer.comments),
+        ) {
+            changes.push(FieldChange::Comments(i));
+        }
+        changes
+    }
+}
+/// What `upsert` did with a game.
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum Upserted {
+    Inserted,
+    /// Merged into a stored game, changing it
+    Changed,
+    /// Merged into a stored game which already had the same content
+    Unchanged,
+}
+/// Inserts `game`, or merges it into the game with the same website and id.
+///
+/// Returns what was done, along with the stored game.
+pub fn upsert(
+    database: &mut GameTextDatabase,
+    game: GameTextInformation,
+) -> (Upserted, &GameTextInformation) {
+    match database
+        .iter()
+        .position(|x| x.id == game.id && x.website == game.website)
+    {
+        Some(i) => {
+            let upserted = if database[i].merge(game) {
+                Upserted::Changed
+            } else {
+                Upserted::Unchanged
+            };
+            (upserted, &database[i])
+        }
+        None => {
+            let mut game = game;
+            if game.revisions.len() == 0 {
+                game.revisions.push(Revision {
+                    fetched: now(),
+                    changes: Vec::new(),
+                });
+            }
+            database.push(game);
+            (Upserted::Inserted, database.last().unwrap())
+        }
+    }
+}
+/// Merges games sharing the same website and id, keeping the position of the first one.
+pub fn dedupe(database: GameTextDatabase) -> GameTextDatabase {
+    let mut positions: HashMap<(String, u64), usize> = HashMap::new();
+    let mut deduped: GameTextDatabase = Vec::with_capacity(database.len());
+    for game in database {
+        match positions.get(&(game.website.clone(), game.id)) {
+            Some(i) => {
+                deduped[*i].merge(game);
+            }
+            None => {
+                positions.insert((game.website.clone(), game.id), deduped.len());
+                deduped.push(game);
+            }
+        }
+    }
+    deduped
 }
 
+fn now() -> time::PrimitiveDateTime {
+    let now = time::OffsetDateTime::now_utc();
+    time::PrimitiveDateTime::new(now.date(), now.time())
+}
+/// A fetch which changed a game. The first revision of a game is the fetch which found it, and
+/// has no changes.
 #[derive(Serialize, Deserialize, Clone)]
+pub struct Revision {
+    /// In UTC
+    pub fetched: time::PrimitiveDateTime,
+    pub changes: Vec<FieldChange>,
+}
+#[derive(Serialize, Deserialize, Clone)]
+pub enum FieldChange {
+    Name {
+        before: String,
+        after: String,
+    },
+    Files(ListChange<(String, (String, Option<u128>))>),
+    Tags(ListChange<String>),
+    Paragraphs(ListChange<(Option<String>, Vec<ParagraphContent>)>),
+    /// Comments are compared one by one, without their replies.
+    Comments(ListChange<Comment>),
+}
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ListChange<T> {
+    pub added: Vec<T>,
+    pub removed: Vec<T>,
+}
+impl<T: PartialEq + Clone> ListChange<T> {
+    pub fn between(before: &[T], after: &[T]) -> Option<Self> {
+        let added: Vec<T> = after
+            .iter()
+            .filter(|x| !before.contains(x))
+            .cloned()
+            .collect();
+        let removed: Vec<T> = before
+            .iter()
+            .filter(|x| !after.contains(x))
+            .cloned()
+            .collect();
+        if added.len() == 0 && removed.len() == 0 {
+            None
+        } else {
+            Some(Self { added, removed })
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
 pub enum ParagraphContent {
     Text(String),
     Image(String),
 }
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
 pub struct Comment {
     pub user_avatar: String,
     pub author: String,
@@ -46,6 +236,32 @@ pub struct Comment {
     pub replies: Vec<Comment>,
 }
 impl Comment {
+    /// Returns every comment of the threads, with their replies removed.
+    pub fn flatten(comments: &[Comment]) -> Vec<Comment> {
+        let mut flattened = Vec::new();
+        for i in comments {
+            flattened.push(Comment {
+                replies: Vec::new(),
+                ..i.clone()
+            });
+            flattened.append(&mut Self::flatten(&i.replies));
+        }
+        flattened
+    }
+    pub fn get_images(&self) -> Vec<String> {
+        let mut images: Vec<String> = self
+            .content
+            .iter()
+            .filter_map(|x| match x {
+                ParagraphContent::Image(i) => Some(i.clone()),
+                ParagraphContent::Text(_) => None,
+            })
+            .collect();
+        for i in &self.replies {
+            images.append(&mut i.get_images());
+        }
+        images
+    }
     pub fn get_avatars(&self) -> Vec<String> {
         let mut avatars = vec![self.user_avatar.to_owned()];
         for i in &self.replies {
@@ -63,35 +279,487 @@ impl Comment {
         }
     }
 }
-#[derive(Serialize, Deserialize)]
-pub struct GameBinaryDatabase(pub HashMap<String, ByteBuf>);
+/// Blobs by content hash, and the content hash of the blob downloaded from each URL.
+#[derive(Serialize, Deserialize, Default)]
+pub struct GameBinaryDatabase {
+    pub urls: HashMap<String, String>,
+    pub blobs: HashMap<String, ByteBuf>,
+    /// Content hash => MIME type, missing from older databases
+    #[serde(default)]
+    pub mime_types: HashMap<String, String>,
+}
+impl GameBinaryDatabase {
+    /// Converts the databases of older versions, which stored a copy of each blob per URL.
+    fn from_url_keyed(blobs: HashMap<String, ByteBuf>) -> Self {
+        let mut database = Self::default();
+        for (key, data) in blobs {
+            database.insert(&key, &data);
+        }
+        database
+    }
+    pub fn get(&self, key: &str) -> Option<&ByteBuf> {
+        self.urls.get(key).and_then(|x| self.blobs.get(x))
+    }
+    pub fn blob(&self, key: &str) -> Option<crate::storage::Blob> {
+        let hash = self.urls.get(key)?;
+        match self.mime_types.get(hash) {
+            Some(i) => Some(crate::storage::Blob {
+                hash: hash.clone(),
+                mime_type: i.clone(),
+            }),
+            None => Some(crate::storage::Blob::new(self.blobs.get(hash)?)),
+        }
+    }
+    pub fn insert(&mut self, key: &str, data: &[u8]) -> crate::storage::Blob {
+        let blob = crate::storage::Blob::new(data);
+        self.blobs
+            .entry(blob.hash.clone())
+            .or_insert_with(|| ByteBuf::from(data));
+        self.mime_types
+            .insert(blob.hash.clone(), blob.mime_type.clone());
+        if let Some(old) = self.urls.insert(key.to_string(), blob.hash.clone()) {
+            // The key used to point at other data, which may not be referenced anymore
+            if old != blob.hash && !self.urls.values().any(|x| *x == old) {
+                self.blobs.remove(&old);
+                self.mime_types.remove(&old);
+            }
+        }
+        blob
+    }
+}
 pub fn load(
     text_db: &str,
     binary_db: &str,
 ) -> (
-    Result<GameTextDatabase, String>,
-    Result<GameBinaryDatabase, String>,
+    Result<GameTextDatabase, Error>,
+    Result<GameBinaryDatabase, Error>,
 ) {
-    let text_db = std::fs::read(text_db)
-        .map_err(|x| x.to_string())
-        .and_then(|x| serde_json::from_slice(&x).map_err(|x| x.to_string()));
-    let binary_db = std::fs::read(binary_db)
-        .map_err(|x| x.to_string())
-        .and_then(|x| rmp_serde::from_slice(&x).map_err(|x| x.to_string()));
+    let text_db = load_text(text_db);
+    let binary_db = std::fs::read(binary_db).map_err(Error::from).and_then(|x| {
+        rmp_serde::from_slice(&x)
+            .or_else(|_| rmp_serde::from_slice(&x).map(GameBinaryDatabase::from_url_keyed))
+            .map_err(|x| Error::Storage(x.to_string()))
+    });
     (text_db, binary_db)
 }
+pub fn load_text(text_db: &str) -> Result<GameTextDatabase, Error> {
+    let data = std::fs::read(text_db)?;
+    serde_json::from_slice(&data).map_err(|x| Error::Storage(x.to_string()))
+}
+/// Writes both databases, backing up the replaced files first unless `backup_count` is `None`.
 pub fn save(
     text_db: (&GameTextDatabase, &str),
     binary_db: (&GameBinaryDatabase, &str),
-) -> Result<(), String> {
-    std::fs::write(
+    backup_count: Option<usize>,
+) -> Result<(), Error> {
+    let timestamp = backup_timestamp();
+    let backup = backup_count.map(|x| (timestamp.as_str(), x));
+    write_atomically(
         text_db.1,
-        serde_json::to_vec_pretty(text_db.0).map_err(|x| x.to_string())?,
-    )
-    .map_err(|x| x.to_string())?;
-    std::fs::write(
+        &serde_json::to_vec_pretty(text_db.0).map_err(|x| Error::Storage(x.to_string()))?,
+        backup,
+    )?;
+    write_atomically(
         binary_db.1,
-        rmp_serde::to_vec(binary_db.0).map_err(|x| x.to_string())?,
+        &rmp_serde::to_vec(binary_db.0).map_err(|x| Error::Storage(x.to_string()))?,
+        backup,
+    )
+}
+/// Down to the microsecond, so that runs in the same second don't overwrite each other's backups.
+pub fn backup_timestamp() -> String {
+    let now = time::OffsetDateTime::now_utc();
+    format!("{}{:06}", now.format("%Y%m%d%H%M%S"), now.microsecond())
+}
+fn backup_location(location: &str, timestamp: &str) -> String {
+    format!("{}.{}.bak", location, timestamp)
+}
+fn sync_parent_directory(location: &str) {
+    // Directories can't be opened on every platform, the rename is still atomic without this
+    if let Some(i) = std::path::Path::new(location).parent() {
+        let directory = if i.as_os_str().is_empty() {
+            std::path::Path::new(".")
+        } else {
+            i
+        };
+        if let Ok(i) = std::fs::File::open(directory) {
+            let _ = i.sync_all();
+        }
+    }
+}
+/// Writes `content` to `location` through a temporary file which is synced and then renamed over
+/// the old one, so that a crash never leaves a half written database behind.
+///
+/// Given a timestamp and a backup count, the replaced file is kept as a backup named after the
+/// timestamp.
+pub fn write_atomically(
+    location: &str,
+    content: &[u8],
+    backup: Option<(&str, usize)>,
+) -> Result<(), Error> {
+    let temporary = format!("{}.tmp", location);
+    {
+        use std::io::Write;
+        let mut file = std::fs::File::create(&temporary)?;
+        file.write_all(content)?;
+        file.sync_all()?;
+    }
+    replace_with_temporary(location, &temporary, backup)
+}
+fn replace_with_temporary(
+    location: &str,
+    temporary: &str,
+    backup: Option<(&str, usize)>,
+) -> Result<(), Error> {
+    if let Some((timestamp, backup_count)) = backup {
+        if backup_count != 0 && std::path::Path::new(location).is_file() {
+            let backup = backup_location(location, timestamp);
+            // The old file is about to be replaced rather than modified, so a hard link is enough
+            if std::fs::hard_link(location, &backup).is_err() {
+                std::fs::copy(location, &backup)?;
+            }
+        }
+    }
+    std::fs::rename(temporary, location)?;
+    sync_parent_directory(location);
+    match backup {
+        Some((_, backup_count)) => prune_backups(location, backup_count),
+        None => Ok(()),
+    }
+}
+/// Copies `location` to a backup named after `timestamp`. Used for databases which are modified
+/// in place and thus can't be backed up when they're replaced.
+pub fn backup(location: &str, timestamp: &str, backup_count: usize) -> Result<(), Error> {
+    if backup_count == 0 || !std::path::Path::new(location).is_file() {
+        return Ok(());
+    }
+    let backup = backup_location(location, timestamp);
+    let temporary = format!("{}.tmp", backup);
+    std::fs::copy(location, &temporary)?;
+    std::fs::File::open(&temporary).and_then(|x| x.sync_all())?;
+    std::fs::rename(&temporary, &backup)?;
+    sync_parent_directory(&backup);
+    prune_backups(location, backup_count)
+}
+/// Returns the timestamps and locations of the backups of `location`, newest first.
+pub fn list_backups(location: &str) -> Result<Vec<(String, String)>, Error> {
+    let path = std::path::Path::new(location);
+    let prefix = format!(
+        "{}.",
+        path.file_name()
+            .ok_or(Error::Storage(String::from(
+                "Database location is not a file"
+            )))?
+            .to_string_lossy()
+    );
+    let directory = match path.parent() {
+        Some(i) if !i.as_os_str().is_empty() => i,
+        _ => std::path::Path::new("."),
+    };
+    let mut backups = Vec::new();
+    for entry in std::fs::read_dir(directory)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        if name.starts_with(&prefix) && name.ends_with(".bak") {
+            let timestamp = &name[prefix.len()..name.len() - ".bak".len()];
+            if timestamp.len() != 0 && timestamp.chars().all(|x| x.is_ascii_digit()) {
+                backups.push((
+                    timestamp.to_string(),
+                    entry.path().to_string_lossy().to_string(),
+                ));
+            }
+        }
+    }
+    backups.sort_by(|x, y| y.0.cmp(&x.0));
+    Ok(backups)
+}
+fn prune_backups(location: &str, backup_count: usize) -> Result<(), Error> {
+    for i in list_backups(location)?.into_iter().skip(backup_count) {
+        std::fs::remove_file(i.1)?;
+    }
+    Ok(())
+}
+/// Replaces `location` with the backup taken at `timestamp`. The current file is backed up
+/// first, so a restore can be undone.
+pub fn restore_backup(location: &str, timestamp: &str, backup_count: usize) -> Result<(), Error> {
+    let backup = backup_location(location, timestamp);
+    if !std::path::Path::new(&backup).is_file() {
+        return Err(Error::Storage(format!("{} does not exist", backup)));
+    }
+    let temporary = format!("{}.tmp", location);
+    std::fs::copy(&backup, &temporary)?;
+    std::fs::File::open(&temporary).and_then(|x| x.sync_all())?;
+    // Keep one more backup so the one being restored isn't pruned by the current file's backup
+    replace_with_temporary(
+        location,
+        &temporary,
+        Some((&backup_timestamp(), backup_count + 1)),
     )
-    .map_err(|x| x.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        dedupe, list_backups, restore_backup, upsert, write_atomically, Comment, FieldChange,
+        GameBinaryDatabase, GameTextInformation, ParagraphContent, Upserted,
+    };
+    use serde_bytes::ByteBuf;
+    /// An empty directory for the test, returning the location of a database in it.
+    fn database_location(name: &str) -> String {
+        let directory = std::env::temp_dir().join(format!("bishojo-saved-{}", name));
+        let _ = std::fs::remove_dir_all(&directory);
+        std::fs::create_dir_all(&directory).unwrap();
+        directory
+            .join("database.json")
+            .to_str()
+            .unwrap()
+            .to_string()
+    }
+    fn game(website: &str, id: u64, name: &str) -> GameTextInformation {
+        GameTextInformation {
+            name: name.to_string(),
+            ..GameTextInformation::default(id, website.to_string())
+        }
+    }
+    fn comment(author: &str, replies: Vec<Comment>) -> Comment {
+        Comment {
+            user_avatar: String::new(),
+            author: author.to_string(),
+            date: time::PrimitiveDateTime::new(time::date!(2020 - 01 - 01), time::time!(0:00)),
+            content: vec![ParagraphContent::Text(format!("By {}", author))],
+            replies,
+        }
+    }
+    fn timestamps(location: &str) -> Vec<String> {
+        list_backups(location)
+            .unwrap()
+            .into_iter()
+            .map(|x| x.0)
+            .collect()
+    }
+    #[test]
+    fn stores_shared_content_once() {
+        let mut database = GameBinaryDatabase::default();
+        let first = database.insert("https://a/1.png", b"data");
+        let second = database.insert("https://b/1.png", b"data");
+        assert_eq!(first.hash, second.hash);
+        assert_eq!(database.blobs.len(), 1);
+        assert_eq!(database.get("https://b/1.png").unwrap().as_slice(), b"data");
+        database.insert("https://b/1.png", b"newer");
+        assert_eq!(database.blobs.len(), 2);
+        // Nothing points at the old data anymore
+        database.insert("https://a/1.png", b"newer");
+        assert_eq!(database.blobs.len(), 1);
+        assert_eq!(database.mime_types.len(), 1);
+        assert_eq!(
+            database.blob("https://a/1.png").unwrap().hash,
+            crate::storage::content_hash(b"newer")
+        );
+    }
+    #[test]
+    fn converts_url_keyed_databases() {
+        let blobs = vec![
+            (
+                String::from("https://a/1.png"),
+                ByteBuf::from(b"data".to_vec()),
+            ),
+            (
+                String::from("https://b/1.png"),
+                ByteBuf::from(b"data".to_vec()),
+            ),
+            (
+                String::from("https://c/1.png"),
+                ByteBuf::from(b"other".to_vec()),
+            ),
+        ];
+        let database = GameBinaryDatabase::from_url_keyed(blobs.into_iter().collect());
+        assert_eq!(database.urls.len(), 3);
+        assert_eq!(database.blobs.len(), 2);
+        assert_eq!(
+            database.urls["https://a/1.png"],
+            crate::storage::content_hash(b"data")
+        );
+    }
+    #[test]
+    fn backs_up_replaced_files_up_to_the_count() {
+        let location = database_location("backups");
+        write_atomically(&location, b"1", Some(("20200101000000000001", 2))).unwrap();
+        // There was nothing to back up
+        assert_eq!(timestamps(&location).len(), 0);
+        write_atomically(&location, b"2", Some(("20200101000000000002", 2))).unwrap();
+        write_atomically(&location, b"3", Some(("20200101000000000003", 2))).unwrap();
+        write_atomically(&location, b"4", Some(("20200101000000000004", 2))).unwrap();
+        write_atomically(&location, b"5", None).unwrap();
+        assert_eq!(std::fs::read(&location).unwrap(), b"5");
+        assert!(!std::path::Path::new(&format!("{}.tmp", location)).exists());
+        let backups = list_backups(&location).unwrap();
+        assert_eq!(
+            timestamps(&location),
+            ["20200101000000000004", "20200101000000000003"]
+        );
+        assert_eq!(std::fs::read(&backups[0].1).unwrap(), b"3");
+        assert_eq!(std::fs::read(&backups[1].1).unwrap(), b"2");
+        write_atomically(&location, b"6", Some(("20200101000000000005", 0))).unwrap();
+        assert_eq!(timestamps(&location).len(), 0);
+    }
+    #[test]
+    fn lists_only_backups_of_the_database() {
+        let location = database_location("listing");
+        for i in [
+            "database.json.20200101000000000002.bak",
+            "database.json.20200101000000000010.bak",
+            "database.json.20200101000000000001.bak",
+            "database.json.old.bak",
+            "database.json..bak",
+            "database.json.tmp",
+            "other.json.20200101000000000003.bak",
+        ] {
+            std::fs::write(std::path::Path::new(&location).with_file_name(i), b"").unwrap();
+        }
+        assert_eq!(
+            timestamps(&location),
+            [
+                "20200101000000000010",
+                "20200101000000000002",
+                "20200101000000000001"
+            ]
+        );
+    }
+    #[test]
+    fn restores_the_chosen_backup() {
+        let location = database_location("restore");
+        write_atomically(&location, b"1", None).unwrap();
+        write_atomically(&location, b"2", Some(("20200101000000000001", 2))).unwrap();
+        write_atomically(&location, b"3", Some(("20200101000000000002", 2))).unwrap();
+        assert!(restore_backup(&location, "20200101000000000003", 2).is_err());
+        assert_eq!(std::fs::read(&location).unwrap(), b"3");
+        restore_backup(&location, "20200101000000000001", 2).unwrap();
+        assert_eq!(std::fs::read(&location).unwrap(), b"1");
+        // The replaced file is backed up too, without pruning the restored backup
+        let backups = list_backups(&location).unwrap();
+        assert_eq!(backups.len(), 3);
+        assert_eq!(std::fs::read(&backups[0].1).unwrap(), b"3");
+        assert_eq!(backups[2].0, "20200101000000000001");
+    }
+    #[test]
+    fn upserts_games_by_website_and_id() {
+        let mut database = Vec::new();
+        assert_eq!(
+            upsert(&mut database, game("kkgal", 1, "A")).0,
+            Upserted::Inserted
+        );
+        assert_eq!(
+            upsert(&mut database, game("kkgal", 1, "A")).0,
+            Upserted::Unchanged
+        );
+        let (upserted, stored) = upsert(&mut database, game("kkgal", 1, "B"));
+        assert_eq!(upserted, Upserted::Changed);
+        assert_eq!(stored.name, "B");
+        // The same id on another website is another game
+        assert_eq!(
+            upsert(&mut database, game("liuli", 1, "A")).0,
+            Upserted::Inserted
+        );
+        assert_eq!(database.len(), 2);
+        assert_eq!(database[0].revisions.len(), 2);
+        assert_eq!(database[1].revisions.len(), 1);
+    }
+    #[test]
+    fn dedupes_within_websites_only() {
+        let database = dedupe(vec![
+            game("kkgal", 1, "A"),
+            game("liuli", 1, "B"),
+            game("kkgal", 2, "C"),
+            game("kkgal", 1, "D"),
+            game("liuli", 1, "E"),
+            game("kkgal", 1, "F"),
+        ]);
+        let games: Vec<_> = database
+            .iter()
+            .map(|x| (x.website.as_str(), x.id, x.name.as_str()))
+            .collect();
+        assert_eq!(
+            games,
+            [("kkgal", 1, "F"), ("liuli", 1, "E"), ("kkgal", 2, "C")]
+        );
+        assert_eq!(database[0].revisions.len(), 2);
+    }
+    #[test]
+    fn diffs_tracked_fields() {
+        let older = GameTextInformation {
+            tags: vec![String::from("a"), String::from("b")],
+            files: vec![(String::from("1.zip"), (String::from("https://a/1"), None))],
+            paragraphs: vec![(None, vec![ParagraphContent::Text(String::from("Old"))])],
+            comments: vec![comment("x", vec![comment("y", Vec::new())])],
+            ..game("kkgal", 1, "A")
+        };
+        let newer = GameTextInformation {
+            tags: vec![String::from("b"), String::from("c")],
+            files: vec![
+                (String::from("1.zip"), (String::from("https://a/1"), None)),
+                (
+                    String::from("2.zip"),
+                    (String::from("https://a/2"), Some(2)),
+                ),
+            ],
+            paragraphs: vec![(None, vec![ParagraphContent::Image(String::from("1.png"))])],
+            comments: vec![comment("x", vec![comment("z", Vec::new())])],
+            ..game("kkgal", 1, "B")
+        };
+        assert_eq!(older.diff(&older.clone()).len(), 0);
+        let changes = older.diff(&newer);
+        assert_eq!(changes.len(), 5);
+        match &changes[0] {
+            FieldChange::Name { before, after } => {
+                assert_eq!((before.as_str(), after.as_str()), ("A", "B"))
+            }
+            _ => panic!("Expected the name first"),
+        }
+        match &changes[1] {
+            FieldChange::Files(i) => assert!(i.added == newer.files[1..] && i.removed.len() == 0),
+            _ => panic!("Expected the files second"),
+        }
+        match &changes[2] {
+            FieldChange::Tags(i) => assert!(i.added == ["c"] && i.removed == ["a"]),
+            _ => panic!("Expected the tags third"),
+        }
+        match &changes[3] {
+            FieldChange::Paragraphs(i) => {
+                assert!(i.added == newer.paragraphs && i.removed == older.paragraphs)
+            }
+            _ => panic!("Expected the paragraphs fourth"),
+        }
+        // Replies are compared on their own, the unchanged comment above them isn't listed
+        match &changes[4] {
+            FieldChange::Comments(i) => {
+                assert!(i.added == [comment("z", Vec::new())]);
+                assert!(i.removed == [comment("y", Vec::new())]);
+            }
+            _ => panic!("Expected the comments last"),
+        }
+    }
+    #[test]
+    fn merges_without_wiping_lists() {
+        let mut stored = GameTextInformation {
+            tags: vec![String::from("a")],
+            ..game("kkgal", 1, "A")
+        };
+        assert!(!stored.merge(game("kkgal", 1, "A")));
+        assert_eq!(stored.tags, ["a"]);
+        assert_eq!(stored.revisions.len(), 0);
+        assert!(stored.merge(game("kkgal", 1, "B")));
+        assert!(stored.merge(GameTextInformation {
+            tags: vec![String::from("b")],
+            ..game("kkgal", 1, "B")
+        }));
+        // Each revision only holds what its fetch changed
+        assert_eq!(stored.revisions.len(), 2);
+        assert!(matches!(
+            stored.revisions[0].changes[..],
+            [FieldChange::Name { .. }]
+        ));
+        assert!(matches!(
+            stored.revisions[1].changes[..],
+            [FieldChange::Tags(_)]
+        ));
+    }
 }
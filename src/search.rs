@@ -0,0 +1,207 @@
+use std::collections::HashMap;
+/// How much an occurrence of a token weighs depending on where it has been found.
+const NAME_WEIGHT: f64 = 5.0;
+const TAG_WEIGHT: f64 = 3.0;
+const PARAGRAPH_WEIGHT: f64 = 1.0;
+const COMMENT_WEIGHT: f64 = 0.5;
+fn is_cjk(x: char) -> bool {
+    matches!(x,
+        '\u{3040}'..='\u{30ff}' // Hiragana and Katakana
+        | '\u{3400}'..='\u{4dbf}' // CJK Extension A
+        | '\u{4e00}'..='\u{9fff}' // CJK Unified Ideographs
+        | '\u{ac00}'..='\u{d7af}' // Hangul
+        | '\u{f900}'..='\u{faff}' // CJK Compatibility Ideographs
+        | '\u{ff66}'..='\u{ff9f}' // Halfwidth Katakana
+    )
+}
+/// Splits text into lowercase words, except for CJK text which has no spaces and is split into
+/// overlapping bigrams instead (a lone CJK character is kept as is).
+pub fn tokenize(text: &str) -> Vec<String> {
+    split(text, false)
+}
+/// Tokens under which text is indexed: the ones of `tokenize`, plus every CJK character on its
+/// own, so that a one character query finds the words containing it.
+fn index_tokens(text: &str) -> Vec<String> {
+    split(text, true)
+}
+fn split(text: &str, unigrams: bool) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut word = String::new();
+    let mut cjk_run: Vec<char> = Vec::new();
+    let flush_cjk = |cjk_run: &mut Vec<char>, tokens: &mut Vec<String>| {
+        if cjk_run.len() == 1 || unigrams {
+            tokens.extend(cjk_run.iter().map(|x| x.to_string()));
+        }
+        for i in cjk_run.windows(2) {
+            tokens.push(i.iter().collect());
+        }
+        cjk_run.clear();
+    };
+    for i in text.chars() {
+        if is_cjk(i) {
+            if word.len() != 0 {
+                tokens.push(std::mem::take(&mut word));
+            }
+            cjk_run.push(i);
+        } else if i.is_alphanumeric() {
+            flush_cjk(&mut cjk_run, &mut tokens);
+            word.extend(i.to_lowercase());
+        } else {
+            flush_cjk(&mut cjk_run, &mut tokens);
+            if word.len() != 0 {
+                tokens.push(std::mem::take(&mut word));
+            }
+        }
+    }
+    flush_cjk(&mut cjk_run, &mut tokens);
+    if word.len() != 0 {
+        tokens.push(word);
+    }
+    tokens
+}
+/// An inverted index over the names, tags, text paragraphs and comments of a game database.
+pub struct SearchIndex {
+    /// Token => (position of the game in the database, weighted occurrences)
+    postings: HashMap<String, HashMap<usize, f64>>,
+    game_count: usize,
+}
+impl SearchIndex {
+    pub fn build(database: &crate::saved::GameTextDatabase) -> Self {
+        let mut postings: HashMap<String, HashMap<usize, f64>> = HashMap::new();
+        let mut add = |text: &str, game: usize, weight: f64| {
+            for token in index_tokens(text) {
+                *postings.entry(token).or_default().entry(game).or_default() += weight;
+            }
+        };
+        fn add_comments(
+            comments: &[crate::saved::Comment],
+            add: &mut impl FnMut(&str, usize, f64),
+            game: usize,
+        ) {
+            for comment in comments {
+                for content in &comment.content {
+                    if let crate::saved::ParagraphContent::Text(i) = content {
+                        add(i, game, COMMENT_WEIGHT);
+                    }
+                }
+                add_comments(&comment.replies, add, game);
+            }
+        }
+        for (position, game) in database.iter().enumerate() {
+            add(&game.name, position, NAME_WEIGHT);
+            for tag in &game.tags {
+                add(tag, position, TAG_WEIGHT);
+            }
+            for paragraph in &game.paragraphs {
+                if let Some(i) = &paragraph.0 {
+                    add(i, position, PARAGRAPH_WEIGHT);
+                }
+                for content in &paragraph.1 {
+                    if let crate::saved::ParagraphContent::Text(i) = content {
+                        add(i, position, PARAGRAPH_WEIGHT);
+                    }
+                }
+            }
+            add_comments(&game.comments, &mut add, position);
+        }
+        Self {
+            postings,
+            game_count: database.len(),
+        }
+    }
+    /// Returns the positions of the games containing every token of `query` along with their
+    /// score, best first. An empty query matches every game with a score of 0.
+    pub fn search(&self, query: &str) -> Vec<(usize, f64)> {
+        let mut tokens = tokenize(query);
+        tokens.sort();
+        tokens.dedup();
+        if tokens.len() == 0 {
+            return (0..self.game_count).map(|x| (x, 0.0)).collect();
+        }
+        let mut scores: Option<HashMap<usize, f64>> = None;
+        for token in tokens {
+            let postings = match self.postings.get(&token) {
+                Some(i) => i,
+                None => return Vec::new(),
+            };
+            let inverse_document_frequency =
+                (1.0 + self.game_count as f64 / postings.len() as f64).ln();
+            let token_scores = postings.iter().map(|(game, weight)| {
+                // Saturate so that a word repeated in every comment doesn't outweigh the name
+                (*game, inverse_document_frequency * weight / (weight + 1.0))
+            });
+            scores = Some(match scores {
+                None => token_scores.collect(),
+                Some(scores) => token_scores
+                    .filter_map(|(game, score)| scores.get(&game).map(|x| (game, x + score)))
+                    .collect(),
+            });
+        }
+        let mut results: Vec<(usize, f64)> = scores.unwrap_or_default().into_iter().collect();
+        results.sort_by(|x, y| y.1.partial_cmp(&x.1).unwrap_or(std::cmp::Ordering::Equal));
+        results
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{tokenize, SearchIndex};
+    use crate::saved::{GameTextInformation, ParagraphContent};
+    fn game(id: u64, name: &str, paragraph: &str) -> GameTextInformation {
+        let mut game = GameTextInformation::default(id, String::from("liuli"));
+        game.name = name.to_string();
+        game.paragraphs = vec![(None, vec![ParagraphContent::Text(paragraph.to_string())])];
+        game
+    }
+    fn ids(database: &[GameTextInformation], results: Vec<(usize, f64)>) -> Vec<u64> {
+        results.into_iter().map(|x| database[x.0].id).collect()
+    }
+    #[test]
+    fn tokenizes_words_and_cjk_bigrams() {
+        assert_eq!(tokenize("Hello, World 2!"), vec!["hello", "world", "2"]);
+        assert_eq!(tokenize("恋爱物语"), vec!["恋爱", "爱物", "物语"]);
+        assert_eq!(tokenize("恋"), vec!["恋"]);
+        assert_eq!(
+            tokenize("Summer夏日ADV，恋"),
+            vec!["summer", "夏日", "adv", "恋"]
+        );
+        assert_eq!(tokenize(" ,。 "), Vec::<String>::new());
+    }
+    #[test]
+    fn finds_cjk_words_by_one_character() {
+        let database = vec![
+            game(1, "恋爱物语", ""),
+            game(2, "夏日", ""),
+            game(3, "恋", ""),
+        ];
+        let index = SearchIndex::build(&database);
+        let mut found = ids(&database, index.search("恋"));
+        found.sort();
+        assert_eq!(found, vec![1, 3]);
+        assert_eq!(ids(&database, index.search("物语")), vec![1]);
+        assert_eq!(ids(&database, index.search("语物")), Vec::<u64>::new());
+    }
+    #[test]
+    fn matches_every_token_of_mixed_queries() {
+        let database = vec![
+            game(1, "恋爱 Adventure", ""),
+            game(2, "恋爱", ""),
+            game(3, "Adventure", ""),
+        ];
+        let index = SearchIndex::build(&database);
+        assert_eq!(ids(&database, index.search("恋爱 adventure")), vec![1]);
+        assert_eq!(ids(&database, index.search("ADVENTURE恋")), vec![1]);
+    }
+    #[test]
+    fn ranks_names_above_paragraphs() {
+        let database = vec![
+            game(1, "Other", "a summer story, 夏日的故事"),
+            game(2, "Summer 夏日", ""),
+            game(3, "Winter", "冬天"),
+        ];
+        let index = SearchIndex::build(&database);
+        assert_eq!(ids(&database, index.search("summer")), vec![2, 1]);
+        assert_eq!(ids(&database, index.search("夏日")), vec![2, 1]);
+        assert_eq!(ids(&database, index.search("夏 summer")), vec![2, 1]);
+    }
+}
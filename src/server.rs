@@ -0,0 +1,435 @@
+use crate::html::escape;
+use crate::{log, saved, search, storage, templates};
+use std::collections::{BTreeMap, HashMap};
+const PAGE_SIZE: usize = 50;
+type Response = tiny_http::Response<std::io::Cursor<Vec<u8>>>;
+/// Browses a game database over http, rendering every page on request.
+pub struct Server<'a> {
+    database: &'a saved::GameTextDatabase,
+    storage: &'a dyn storage::Storage,
+    blob_index: &'a HashMap<String, storage::Blob>,
+    /// Exported image file name => blob key
+    images: HashMap<String, &'a str>,
+    /// Positions in the database, newest first
+    newest: Vec<usize>,
+    tags: BTreeMap<&'a str, Vec<usize>>,
+    sites: BTreeMap<&'a str, Vec<usize>>,
+    games: HashMap<(&'a str, u64), usize>,
+    index: search::SearchIndex,
+    templates: &'a templates::Templates,
+}
+impl<'a> Server<'a> {
+    pub fn new(
+        database: &'a saved::GameTextDatabase,
+        storage: &'a dyn storage::Storage,
+        blob_index: &'a HashMap<String, storage::Blob>,
+        templates: &'a templates::Templates,
+    ) -> Self {
+        let mut newest: Vec<usize> = (0..database.len()).collect();
+        newest.sort_by(|x, y| database[*y].published.cmp(&database[*x].published));
+        let mut tags: BTreeMap<&str, Vec<usize>> = BTreeMap::new();
+        let mut sites: BTreeMap<&str, Vec<usize>> = BTreeMap::new();
+        for position in &newest {
+            for tag in &database[*position].tags {
+                tags.entry(tag).or_default().push(*position);
+            }
+            sites
+                .entry(&database[*position].website)
+                .or_default()
+                .push(*position);
+        }
+        Self {
+            database,
+            storage,
+            blob_index,
+            images: crate::offline_file_names(blob_index),
+            newest,
+            tags,
+            sites,
+            games: database
+                .iter()
+                .enumerate()
+                .map(|(position, game)| ((game.website.as_str(), game.id), position))
+                .collect(),
+            index: search::SearchIndex::build(database),
+            templates,
+        }
+    }
+    /// Answers requests until a shutdown is requested.
+    pub fn run(
+        &self,
+        address: &str,
+        shutdown_requested: impl Fn() -> bool,
+        logging_client: &log::LoggingClient,
+    ) -> Result<(), String> {
+        let server = tiny_http::Server::http(address).map_err(|x| x.to_string())?;
+        logging_client.log(
+            log::LoggingLevel::Message,
+            &format!(
+                "Serving {} games on http://{}/",
+                self.database.len(),
+                address
+            ),
+        );
+        while !shutdown_requested() {
+            let request = match server.recv_timeout(std::time::Duration::from_millis(500)) {
+                Ok(Some(i)) => i,
+                Ok(None) => continue,
+                Err(i) => return Err(i.to_string()),
+            };
+            let response = self.route(request.url(), logging_client);
+            logging_client.log(
+                log::LoggingLevel::StatusReport,
+                &format!("{} {}", response.status_code().0, request.url()),
+            );
+            if let Err(i) = request.respond(response) {
+                logging_client.log(
+                    log::LoggingLevel::Warning,
+                    &format!("Error while sending response: {}", i),
+                );
+            }
+        }
+        Ok(())
+    }
+    fn route(&self, url: &str, logging_client: &log::LoggingClient) -> Response {
+        let (path, query) = match url.find('?') {
+            Some(i) => (&url[..i], parse_query(&url[i + 1..])),
+            None => (url, HashMap::new()),
+        };
+        let page = query
+            .get("page")
+            .and_then(|x| x.parse::<usize>().ok())
+            .unwrap_or(1)
+            .max(1);
+        match path {
+            "/" | "/index.html" => self.listing("Galgames", &self.newest, page, &query),
+            "/tags" | "/tags.html" => self.tag_list(),
+            "/tag" => match query.get("name").and_then(|x| self.tags.get(x.as_str())) {
+                Some(i) => self.listing(&format!("Tag: {}", query["name"]), i, page, &query),
+                None => not_found(),
+            },
+            "/search" => {
+                let keywords = query.get("q").map(String::as_str).unwrap_or("");
+                let results: Vec<usize> = self
+                    .index
+                    .search(keywords)
+                    .into_iter()
+                    .map(|x| x.0)
+                    .collect();
+                self.listing(&format!("Search: {}", keywords), &results, page, &query)
+            }
+            "/game" => {
+                let game = query
+                    .get("id")
+                    .and_then(|x| x.parse::<u64>().ok())
+                    .zip(query.get("website"))
+                    .and_then(|(id, website)| self.games.get(&(website.as_str(), id)));
+                match game {
+                    Some(i) => rendered(crate::html_page(
+                        &self.database[*i],
+                        Some(self.blob_index),
+                        false,
+                        self.templates,
+                    )),
+                    None => not_found(),
+                }
+            }
+            // The links of the exported pages
+            _ if path.starts_with("/tags/") || path.starts_with("/sites/") => {
+                let (kind, listings) = if path.starts_with("/tags/") {
+                    ("Tag", &self.tags)
+                } else {
+                    ("Website", &self.sites)
+                };
+                let file =
+                    percent_encoding::percent_decode_str(path.splitn(3, '/').nth(2).unwrap())
+                        .decode_utf8_lossy()
+                        .into_owned();
+                match listings
+                    .iter()
+                    .find(|x| format!("{}.html", crate::listing_file_name(x.0)) == file)
+                {
+                    Some(i) => self.listing(&format!("{}: {}", kind, i.0), i.1, page, &query),
+                    None => not_found(),
+                }
+            }
+            _ if path.starts_with("/imgs/") => {
+                let key = match self.images.get(&path["/imgs/".len()..]) {
+                    Some(i) => i,
+                    None => return not_found(),
+                };
+                match self.storage.get_blob(key) {
+                    // Scraped files may be scripted and must not run as pages of the server
+                    Ok(Some(i)) => tiny_http::Response::from_data(i)
+                        .with_header(header("Content-Type", &self.blob_index[*key].mime_type))
+                        .with_header(header(
+                            "Content-Security-Policy",
+                            "default-src 'none'; style-src 'unsafe-inline'; sandbox",
+                        ))
+                        .with_header(header("X-Content-Type-Options", "nosniff")),
+                    Ok(None) => not_found(),
+                    Err(i) => {
+                        logging_client.log(
+                            log::LoggingLevel::Warning,
+                            &format!("Error while reading {} from database: {}", key, i),
+                        );
+                        html(
+                            500,
+                            layout("Error", &format!("<p>{}</p>", escape(&i.to_string()))),
+                        )
+                    }
+                }
+            }
+            _ => not_found(),
+        }
+    }
+    fn listing(
+        &self,
+        title: &str,
+        games: &[usize],
+        page: usize,
+        query: &HashMap<String, String>,
+    ) -> Response {
+        let page_count = (games.len() + PAGE_SIZE - 1) / PAGE_SIZE;
+        let page_link = |page: usize| {
+            let mut query = query.clone();
+            query.insert(String::from("page"), page.to_string());
+            format!(
+                "?{}",
+                query
+                    .iter()
+                    .map(|(key, value)| {
+                        format!("{}={}", crate::url_encode(key), crate::url_encode(value))
+                    })
+                    .collect::<Vec<_>>()
+                    .join("&")
+            )
+        };
+        rendered(
+            self.templates.listing(&templates::ListingPage {
+                root: String::from("/"),
+                title: title.to_string(),
+                search: Some(String::from("/search")),
+                query: query.get("q").cloned(),
+                sites: self
+                    .sites
+                    .keys()
+                    .map(|x| templates::Link {
+                        name: x.to_string(),
+                        href: format!(
+                            "/sites/{}.html",
+                            crate::url_encode(&crate::listing_file_name(x))
+                        ),
+                    })
+                    .collect(),
+                sort: None,
+                game_count: games.len(),
+                games: games
+                    .iter()
+                    .skip((page - 1) * PAGE_SIZE)
+                    .take(PAGE_SIZE)
+                    .map(|x| {
+                        let game = &self.database[*x];
+                        templates::ListedGame {
+                            href: format!(
+                                "/game?website={}&id={}",
+                                crate::url_encode(&game.website),
+                                game.id
+                            ),
+                            name: game.name.clone(),
+                            id: game.id,
+                            website: game.website.clone(),
+                            published: game.published.to_string(),
+                            viewed: game.viewed,
+                        }
+                    })
+                    .collect(),
+                pages: Some(templates::Pages {
+                    current: page,
+                    count: page_count.max(1),
+                    previous: if page > 1 {
+                        Some(page_link(page - 1))
+                    } else {
+                        None
+                    },
+                    next: if page < page_count {
+                        Some(page_link(page + 1))
+                    } else {
+                        None
+                    },
+                }),
+            }),
+        )
+    }
+    fn tag_list(&self) -> Response {
+        rendered(
+            self.templates.tags(&templates::TagsPage {
+                root: String::from("/"),
+                tags: self
+                    .tags
+                    .iter()
+                    .map(|(tag, games)| templates::ListedTag {
+                        name: tag.to_string(),
+                        href: format!(
+                            "/tags/{}.html",
+                            crate::url_encode(&crate::listing_file_name(tag))
+                        ),
+                        count: games.len(),
+                    })
+                    .collect(),
+            }),
+        )
+    }
+}
+fn layout(title: &str, body: &str) -> String {
+    format!(
+        r#"<!DOCTYPE html>
+<html>
+<head>
+    <meta charset="utf-8">
+    <title>{}</title>
+</head>
+<body>
+    <nav><a href="/">Index</a> | <a href="/tags">Tags</a> | <form action="/search" style="display: inline"><input name="q"> <input type="submit" value="Search"></form></nav>
+    <h1>{}</h1>
+{}
+</body>
+</html>"#,
+        escape(title),
+        escape(title),
+        body
+    )
+}
+fn html(status: u16, body: String) -> Response {
+    tiny_http::Response::from_string(body)
+        .with_status_code(status)
+        .with_header(header("Content-Type", "text/html; charset=utf-8"))
+}
+fn rendered(page: Result<String, String>) -> Response {
+    match page {
+        Ok(i) => html(200, i),
+        Err(i) => html(500, layout("Error", &format!("<p>{}</p>", escape(&i)))),
+    }
+}
+fn not_found() -> Response {
+    html(404, layout("Not found", ""))
+}
+fn header(key: &str, value: &str) -> tiny_http::Header {
+    tiny_http::Header::from_bytes(key.as_bytes(), value.as_bytes()).unwrap()
+}
+fn parse_query(query: &str) -> HashMap<String, String> {
+    let decode = |x: &str| {
+        percent_encoding::percent_decode_str(&x.replace('+', " "))
+            .decode_utf8_lossy()
+            .into_owned()
+    };
+    query
+        .split('&')
+        .filter(|x| x.len() != 0)
+        .map(|x| match x.find('=') {
+            Some(i) => (decode(&x[..i]), decode(&x[i + 1..])),
+            None => (decode(x), String::new()),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Server;
+    use crate::saved::{GameTextInformation, ParagraphContent};
+    use crate::storage::Storage;
+    const PNG: &[u8] = b"\x89PNG\r\n\x1A\n\0\0\0\0IEND\xAE\x42\x60\x82";
+    fn game(id: u64, name: &str, tags: &[&str]) -> GameTextInformation {
+        GameTextInformation {
+            name: name.to_string(),
+            tags: tags.iter().map(|x| x.to_string()).collect(),
+            paragraphs: vec![(
+                None,
+                vec![ParagraphContent::Image(String::from("https://a/1.png"))],
+            )],
+            ..GameTextInformation::default(id, String::from("kkgal"))
+        }
+    }
+    /// Answers `url` from a server of two games, returning the status, headers and body.
+    fn get(url: &str) -> (u16, Vec<(String, String)>, String) {
+        let database = vec![
+            game(1, "First Game", &["Romance"]),
+            game(2, "Second <Game>", &["Romance", "Horror"]),
+        ];
+        let mut storage = crate::storage::sqlite::SqliteStorage::open(":memory:", 0).unwrap();
+        storage.insert_blob("https://a/1.png", PNG).unwrap();
+        let blob_index = storage.blob_index().unwrap();
+        let templates = crate::templates::Templates::load(None).unwrap();
+        let server = Server::new(&database, &storage, &blob_index, &templates);
+        let response = server.route(url, &crate::log::LoggingClient::new());
+        let status = response.status_code().0;
+        let headers = response
+            .headers()
+            .iter()
+            .map(|x| (x.field.to_string(), x.value.to_string()))
+            .collect();
+        let body = String::from_utf8_lossy(&response.into_reader().into_inner()).to_string();
+        (status, headers, body)
+    }
+    #[test]
+    fn lists_games() {
+        let (status, _, body) = get("/");
+        assert_eq!(status, 200);
+        assert!(body.contains("2 games"));
+        assert!(body.contains(r#"<a href="/game?website&#x3D;kkgal&amp;id&#x3D;1">First Game</a>"#));
+        assert!(body.contains("Second &lt;Game&gt;"));
+        let (status, _, body) = get("/tags");
+        assert_eq!(status, 200);
+        assert!(body.contains("/tags/Horror.html"));
+        let (status, _, body) = get("/tag?name=Horror");
+        assert_eq!(status, 200);
+        assert!(body.contains("1 games") && body.contains("Second &lt;Game&gt;"));
+        let (_, _, body) = get("/tags/Romance.html");
+        assert!(body.contains("2 games"));
+    }
+    #[test]
+    fn searches_games() {
+        let (status, _, body) = get("/search?q=second+game");
+        assert_eq!(status, 200);
+        assert!(body.contains("Search: second game"));
+        assert!(body.contains("1 games") && body.contains("id&#x3D;2"));
+    }
+    #[test]
+    fn shows_games() {
+        let (status, _, body) = get("/game?website=kkgal&id=2");
+        assert_eq!(status, 200);
+        assert!(body.contains("Second &lt;Game&gt;"));
+        assert!(body.contains(&crate::storage::content_hash(PNG)));
+        assert_eq!(get("/game?website=liuli&id=2").0, 404);
+        assert_eq!(get("/game?website=kkgal&id=x").0, 404);
+    }
+    #[test]
+    fn answers_unknown_paths_with_not_found() {
+        for url in [
+            "/other",
+            "/tag?name=Comedy",
+            "/tags/Comedy.html",
+            "/imgs/1.png",
+        ] {
+            assert_eq!(get(url).0, 404, "{}", url);
+        }
+    }
+    #[test]
+    fn serves_images_without_letting_them_run() {
+        let (status, headers, body) =
+            get(&format!("/imgs/{}.png", crate::storage::content_hash(PNG)));
+        assert_eq!(status, 200);
+        assert_eq!(body.as_bytes(), String::from_utf8_lossy(PNG).as_bytes());
+        let header = |name: &str| {
+            headers
+                .iter()
+                .find(|x| x.0.eq_ignore_ascii_case(name))
+                .map(|x| x.1.as_str())
+        };
+        assert_eq!(header("Content-Type"), Some("image/png"));
+        assert_eq!(header("X-Content-Type-Options"), Some("nosniff"));
+        assert!(header("Content-Security-Policy")
+            .unwrap()
+            .starts_with("default-src 'none'"));
+    }
+}
@@ -0,0 +1,141 @@
+/// The original storage format: the whole text database as JSON and the whole binary database
+/// as MessagePack, both kept in memory and rewritten on every flush.
+pub struct LegacyStorage {
+    /// The stored games until `load_games` hands them out, then only the ones changed since. The
+    /// others are read back from the file when saving.
+    text_database: crate::saved::GameTextDatabase,
+    handed_out: bool,
+    /// Whether anything changed since the last flush
+    dirty: bool,
+    /// Whether the files replaced by this run have been backed up. Only the first flush does it,
+    /// so that checkpoints don't rotate the backups of previous runs away.
+    backed_up: bool,
+    binary_database: crate::saved::GameBinaryDatabase,
+    text_database_location: String,
+    binary_database_location: String,
+    backup_count: usize,
+}
+impl LegacyStorage {
+    pub fn open(
+        text_database_location: &str,
+        binary_database_location: &str,
+        backup_count: usize,
+        log_client: &crate::log::LoggingClient,
+    ) -> Self {
+        let database = crate::saved::load(text_database_location, binary_database_location);
+        Self {
+            text_database: match database.0 {
+                Ok(i) => i,
+                Err(i) => {
+                    log_client.log(
+                        crate::log::LoggingLevel::Warning,
+                        &format!("Unable to load text database: {}. Using new database.", i),
+                    );
+                    Vec::new()
+                }
+            },
+            binary_database: match database.1 {
+                Ok(i) => i,
+                Err(i) => {
+                    log_client.log(
+                        crate::log::LoggingLevel::Warning,
+                        &format!("Unable to load binary database: {}. Using new database.", i),
+                    );
+                    crate::saved::GameBinaryDatabase::default()
+                }
+            },
+            handed_out: false,
+            dirty: false,
+            backed_up: false,
+            text_database_location: text_database_location.to_string(),
+            binary_database_location: binary_database_location.to_string(),
+            backup_count,
+        }
+    }
+}
+impl super::Storage for LegacyStorage {
+    fn load_games(&mut self) -> Result<crate::saved::GameTextDatabase, crate::error::Error> {
+        let games = std::mem::take(&mut self.text_database);
+        // An empty database has nothing to read back, nor has one which couldn't be loaded
+        self.handed_out = self.handed_out || games.len() != 0;
+        Ok(games)
+    }
+    fn upsert_games(
+        &mut self,
+        games: &[crate::saved::GameTextInformation],
+    ) -> Result<(), crate::error::Error> {
+        upsert(&mut self.text_database, games);
+        self.dirty = true;
+        Ok(())
+    }
+    fn replace_games(
+        &mut self,
+        games: &[crate::saved::GameTextInformation],
+    ) -> Result<(), crate::error::Error> {
+        self.text_database = games.to_vec();
+        self.handed_out = false;
+        self.dirty = true;
+        Ok(())
+    }
+    fn blob_index(
+        &self,
+    ) -> Result<std::collections::HashMap<String, super::Blob>, crate::error::Error> {
+        Ok(self
+            .binary_database
+            .urls
+            .keys()
+            .filter_map(|x| Some((x.clone(), self.binary_database.blob(x)?)))
+            .collect())
+    }
+    fn get_blob(&self, key: &str) -> Result<Option<Vec<u8>>, crate::error::Error> {
+        Ok(self.binary_database.get(key).map(|x| x.to_vec()))
+    }
+    fn insert_blob(&mut self, key: &str, data: &[u8]) -> Result<super::Blob, crate::error::Error> {
+        self.dirty = true;
+        Ok(self.binary_database.insert(key, data))
+    }
+    fn flush(&mut self) -> Result<(), crate::error::Error> {
+        if !self.dirty {
+            return Ok(());
+        }
+        let backup_count = if self.backed_up {
+            None
+        } else {
+            Some(self.backup_count)
+        };
+        if self.handed_out {
+            let mut text_database = crate::saved::load_text(&self.text_database_location)?;
+            upsert(&mut text_database, &self.text_database);
+            crate::saved::save(
+                (&text_database, &self.text_database_location),
+                (&self.binary_database, &self.binary_database_location),
+                backup_count,
+            )?;
+            self.text_database.clear();
+        } else {
+            crate::saved::save(
+                (&self.text_database, &self.text_database_location),
+                (&self.binary_database, &self.binary_database_location),
+                backup_count,
+            )?;
+        }
+        self.dirty = false;
+        self.backed_up = true;
+        Ok(())
+    }
+}
+/// Replaces the stored games with the same website and id, and adds the others.
+fn upsert(
+    database: &mut crate::saved::GameTextDatabase,
+    games: &[crate::saved::GameTextInformation],
+) {
+    for game in games {
+        match database
+            .iter_mut()
+            .find(|x| x.id == game.id && x.website == game.website)
+        {
+            Some(i) => *i = game.clone(),
+            None => database.push(game.clone()),
+        }
+    }
+}
@@ -0,0 +1,130 @@
+pub mod legacy;
+pub mod sqlite;
+/// A place where games and binary blobs (screenshots, avatars) are persisted.
+///
+/// Writes may be buffered by the backend and are only guaranteed to be on disk after `flush`.
+pub trait Storage {
+    /// Hands over the stored games, once after opening. Backends keeping them in memory may not
+    /// keep a copy.
+    fn load_games(&mut self) -> Result<crate::saved::GameTextDatabase, crate::error::Error>;
+    /// Inserts the games, replacing stored ones with the same website and id.
+    fn upsert_games(
+        &mut self,
+        games: &[crate::saved::GameTextInformation],
+    ) -> Result<(), crate::error::Error>;
+    /// Replaces every stored game with `games`.
+    fn replace_games(
+        &mut self,
+        games: &[crate::saved::GameTextInformation],
+    ) -> Result<(), crate::error::Error>;
+    /// Key (the URL a blob was downloaded from) => blob, for every stored blob.
+    fn blob_index(&self) -> Result<std::collections::HashMap<String, Blob>, crate::error::Error>;
+    fn get_blob(&self, key: &str) -> Result<Option<Vec<u8>>, crate::error::Error>;
+    /// Stores the data once per distinct content and points the key at it.
+    fn insert_blob(&mut self, key: &str, data: &[u8]) -> Result<Blob, crate::error::Error>;
+    fn flush(&mut self) -> Result<(), crate::error::Error>;
+}
+/// What is known about a stored blob without reading it.
+#[derive(Clone)]
+pub struct Blob {
+    pub hash: String,
+    /// Detected from the data, see `images::sniff`
+    pub mime_type: String,
+}
+impl Blob {
+    pub fn new(data: &[u8]) -> Self {
+        Self {
+            hash: content_hash(data),
+            mime_type: crate::images::sniff(data)
+                .unwrap_or(crate::images::UNKNOWN_MIME_TYPE)
+                .to_string(),
+        }
+    }
+    /// SVG blobs stored by older versions aren't, as they may run scripts.
+    pub fn is_image(&self) -> bool {
+        crate::images::extension(&self.mime_type) != "bin"
+    }
+}
+/// Blobs are stored under the SHA-256 of their data, as lowercase hex.
+pub fn content_hash(data: &[u8]) -> String {
+    use sha2::Digest;
+    sha2::Sha256::digest(data)
+        .iter()
+        .map(|x| format!("{:02x}", x))
+        .collect()
+}
+/// Flushes a storage every `items` recorded changes or every `interval`, whichever comes first,
+/// so that an interrupted job only loses what it did since the last checkpoint.
+pub struct Checkpoint {
+    items: usize,
+    interval: std::time::Duration,
+    pending: usize,
+    last_flush: std::time::Instant,
+}
+impl Checkpoint {
+    pub fn new(items: usize, interval: std::time::Duration) -> Self {
+        Self {
+            items,
+            interval,
+            pending: 0,
+            last_flush: std::time::Instant::now(),
+        }
+    }
+    /// Records a change, returns whether the storage has been flushed.
+    pub fn record(&mut self, storage: &mut dyn Storage) -> Result<bool, crate::error::Error> {
+        self.pending += 1;
+        if self.pending < self.items && self.last_flush.elapsed() < self.interval {
+            return Ok(false);
+        }
+        storage.flush()?;
+        self.pending = 0;
+        self.last_flush = std::time::Instant::now();
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{content_hash, Blob, Storage};
+    const PNG: &[u8] = b"\x89PNG\r\n\x1A\n\0\0\0\0IEND\xAE\x42\x60\x82";
+    #[test]
+    fn names_blobs_after_their_sha256() {
+        assert_eq!(
+            content_hash(b"abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        let blob = Blob::new(PNG);
+        assert_eq!(blob.hash, content_hash(PNG));
+        assert_eq!(blob.mime_type, "image/png");
+        assert!(blob.is_image());
+        assert!(!Blob::new(b"<html>").is_image());
+        assert!(!Blob::new(b"<svg></svg>").is_image());
+        let legacy = Blob {
+            hash: String::new(),
+            mime_type: "image/svg+xml".to_string(),
+        };
+        assert!(!legacy.is_image());
+    }
+    #[test]
+    fn stores_shared_content_once() {
+        let mut storage = super::sqlite::SqliteStorage::open(":memory:", 0).unwrap();
+        let first = storage.insert_blob("https://a/1.png", PNG).unwrap();
+        let second = storage.insert_blob("https://b/1.png", PNG).unwrap();
+        assert_eq!(first.hash, second.hash);
+        storage.insert_blob("https://c/other", b"other").unwrap();
+        let index = storage.blob_index().unwrap();
+        assert_eq!(index.len(), 3);
+        assert_eq!(index["https://b/1.png"].hash, first.hash);
+        // Pointing the last key at other data drops the blob nothing references anymore
+        storage.insert_blob("https://c/other", PNG).unwrap();
+        storage.flush().unwrap();
+        assert_eq!(storage.get_blob("https://c/other").unwrap().unwrap(), PNG);
+        let hashes: std::collections::HashSet<_> = storage
+            .blob_index()
+            .unwrap()
+            .into_iter()
+            .map(|x| x.1.hash)
+            .collect();
+        assert_eq!(hashes.len(), 1);
+    }
+}
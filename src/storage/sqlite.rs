@@ -0,0 +1,250 @@
+use rusqlite::OptionalExtension;
+/// Stores every game and blob as its own row, so that a run only writes what it changed and
+/// blobs are only read when they're needed.
+///
+/// Writes are grouped in a transaction which is committed on `flush`, so a crash never leaves
+/// the database half written.
+pub struct SqliteStorage {
+    connection: rusqlite::Connection,
+    in_transaction: bool,
+    location: String,
+    backup_count: usize,
+    /// Whether the database has been backed up by this run, or didn't exist before it
+    backed_up: bool,
+}
+impl SqliteStorage {
+    pub fn open(location: &str, backup_count: usize) -> Result<Self, crate::error::Error> {
+        let existed = std::path::Path::new(location).is_file();
+        let mut storage = Self {
+            connection: rusqlite::Connection::open(location)?,
+            in_transaction: false,
+            location: location.to_string(),
+            backup_count,
+            backed_up: !existed,
+        };
+        storage.create_tables()?;
+        storage.detect_mime_types()?;
+        storage.migrate_url_keyed_blobs()?;
+        Ok(storage)
+    }
+    fn create_tables(&mut self) -> Result<(), crate::error::Error> {
+        let count: i64 = self.connection.query_row(
+            "SELECT COUNT(*) FROM sqlite_master WHERE name IN
+                    ('games', 'blob_data', 'blob_urls', 'blob_urls_hash')",
+            rusqlite::NO_PARAMS,
+            |x| x.get(0),
+        )?;
+        if count == 4 {
+            return Ok(());
+        }
+        self.begin()?;
+        self.connection.execute_batch(
+            "CREATE TABLE IF NOT EXISTS games (
+                    website TEXT NOT NULL,
+                    id INTEGER NOT NULL,
+                    data TEXT NOT NULL,
+                    PRIMARY KEY (website, id)
+                );
+                CREATE TABLE IF NOT EXISTS blob_data (
+                    hash TEXT PRIMARY KEY NOT NULL,
+                    data BLOB NOT NULL,
+                    mime_type TEXT
+                );
+                CREATE TABLE IF NOT EXISTS blob_urls (
+                    key TEXT PRIMARY KEY NOT NULL,
+                    hash TEXT NOT NULL
+                );
+                CREATE INDEX IF NOT EXISTS blob_urls_hash ON blob_urls (hash);",
+        )?;
+        super::Storage::flush(self)
+    }
+    /// Fills in the MIME type of the blobs stored before it was recorded.
+    fn detect_mime_types(&mut self) -> Result<(), crate::error::Error> {
+        let has_column: bool = self.connection.query_row(
+            "SELECT EXISTS (SELECT 1 FROM pragma_table_info('blob_data') WHERE name = 'mime_type')",
+            rusqlite::NO_PARAMS,
+            |x| x.get(0),
+        )?;
+        if !has_column {
+            self.begin()?;
+            self.connection
+                .execute_batch("ALTER TABLE blob_data ADD COLUMN mime_type TEXT")?;
+        }
+        let hashes: Vec<String> = {
+            let mut statement = self
+                .connection
+                .prepare("SELECT hash FROM blob_data WHERE mime_type IS NULL")?;
+            let rows = statement.query_map(rusqlite::NO_PARAMS, |x| x.get(0))?;
+            rows.collect::<Result<_, _>>()?
+        };
+        if hashes.len() != 0 {
+            self.begin()?;
+        }
+        for hash in hashes {
+            let data: Vec<u8> = self.connection.query_row(
+                "SELECT data FROM blob_data WHERE hash = ?1",
+                rusqlite::params![hash],
+                |x| x.get(0),
+            )?;
+            self.connection.execute(
+                "UPDATE blob_data SET mime_type = ?1 WHERE hash = ?2",
+                rusqlite::params![super::Blob::new(&data).mime_type, hash],
+            )?;
+        }
+        super::Storage::flush(self)
+    }
+    /// Moves the blobs of older versions, which were stored once per URL, to the content
+    /// addressed tables.
+    fn migrate_url_keyed_blobs(&mut self) -> Result<(), crate::error::Error> {
+        let exists: bool = self.connection.query_row(
+            "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'blobs')",
+            rusqlite::NO_PARAMS,
+            |x| x.get(0),
+        )?;
+        if !exists {
+            return Ok(());
+        }
+        self.begin()?;
+        let keys: Vec<String> = {
+            let mut statement = self.connection.prepare("SELECT key FROM blobs")?;
+            let rows = statement.query_map(rusqlite::NO_PARAMS, |x| x.get(0))?;
+            rows.collect::<Result<_, _>>()?
+        };
+        // One blob at a time, so they never all have to be in memory
+        for key in keys {
+            let data: Vec<u8> = self.connection.query_row(
+                "SELECT data FROM blobs WHERE key = ?1",
+                rusqlite::params![key],
+                |x| x.get(0),
+            )?;
+            super::Storage::insert_blob(self, &key, &data)?;
+        }
+        self.connection.execute_batch("DROP TABLE blobs")?;
+        super::Storage::flush(self)
+    }
+    /// Starts a transaction unless one is running. The database is modified in place, so it's
+    /// backed up before the first write of the run.
+    fn begin(&mut self) -> Result<(), crate::error::Error> {
+        if !self.in_transaction {
+            if !self.backed_up {
+                crate::saved::backup(
+                    &self.location,
+                    &crate::saved::backup_timestamp(),
+                    self.backup_count,
+                )?;
+                self.backed_up = true;
+            }
+            self.connection.execute_batch("BEGIN")?;
+            self.in_transaction = true;
+        }
+        Ok(())
+    }
+}
+impl super::Storage for SqliteStorage {
+    fn load_games(&mut self) -> Result<crate::saved::GameTextDatabase, crate::error::Error> {
+        let mut statement = self
+            .connection
+            .prepare("SELECT data FROM games ORDER BY rowid")?;
+        let rows = statement.query_map(rusqlite::NO_PARAMS, |x| x.get::<_, String>(0))?;
+        let mut games = Vec::new();
+        for row in rows {
+            games.push(
+                serde_json::from_str(&row?)
+                    .map_err(|x| crate::error::Error::Storage(x.to_string()))?,
+            );
+        }
+        Ok(games)
+    }
+    fn upsert_games(
+        &mut self,
+        games: &[crate::saved::GameTextInformation],
+    ) -> Result<(), crate::error::Error> {
+        self.begin()?;
+        for game in games {
+            self.connection.execute(
+                "INSERT OR REPLACE INTO games (website, id, data) VALUES (?1, ?2, ?3)",
+                rusqlite::params![
+                    game.website,
+                    // SQLite only has signed integers, the bits are kept as is
+                    game.id as i64,
+                    serde_json::to_string(game)
+                        .map_err(|x| crate::error::Error::Storage(x.to_string()))?
+                ],
+            )?;
+        }
+        Ok(())
+    }
+    fn replace_games(
+        &mut self,
+        games: &[crate::saved::GameTextInformation],
+    ) -> Result<(), crate::error::Error> {
+        self.begin()?;
+        self.connection
+            .execute("DELETE FROM games", rusqlite::NO_PARAMS)?;
+        self.upsert_games(games)
+    }
+    fn blob_index(
+        &self,
+    ) -> Result<std::collections::HashMap<String, super::Blob>, crate::error::Error> {
+        let mut statement = self
+            .connection
+            .prepare("SELECT key, hash, mime_type FROM blob_urls JOIN blob_data USING (hash)")?;
+        let rows = statement.query_map(rusqlite::NO_PARAMS, |x| {
+            Ok((
+                x.get(0)?,
+                super::Blob {
+                    hash: x.get(1)?,
+                    mime_type: x.get(2)?,
+                },
+            ))
+        })?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+    fn get_blob(&self, key: &str) -> Result<Option<Vec<u8>>, crate::error::Error> {
+        Ok(self
+            .connection
+            .query_row(
+                "SELECT data FROM blob_urls JOIN blob_data USING (hash) WHERE key = ?1",
+                rusqlite::params![key],
+                |x| x.get(0),
+            )
+            .optional()?)
+    }
+    fn insert_blob(&mut self, key: &str, data: &[u8]) -> Result<super::Blob, crate::error::Error> {
+        self.begin()?;
+        let blob = super::Blob::new(data);
+        let hash = &blob.hash;
+        let old: Option<String> = self
+            .connection
+            .query_row(
+                "SELECT hash FROM blob_urls WHERE key = ?1",
+                rusqlite::params![key],
+                |x| x.get(0),
+            )
+            .optional()?;
+        self.connection.execute(
+            "INSERT OR IGNORE INTO blob_data (hash, data, mime_type) VALUES (?1, ?2, ?3)",
+            rusqlite::params![hash, data, blob.mime_type],
+        )?;
+        self.connection.execute(
+            "INSERT OR REPLACE INTO blob_urls (key, hash) VALUES (?1, ?2)",
+            rusqlite::params![key, hash],
+        )?;
+        if let Some(old) = old.filter(|x| x != hash) {
+            // The key used to point at other data, which may not be referenced anymore
+            self.connection.execute(
+                "DELETE FROM blob_data WHERE hash = ?1
+                        AND NOT EXISTS (SELECT 1 FROM blob_urls WHERE hash = ?1)",
+                rusqlite::params![old],
+            )?;
+        }
+        Ok(blob)
+    }
+    fn flush(&mut self) -> Result<(), crate::error::Error> {
+        if self.in_transaction {
+            self.connection.execute_batch("COMMIT")?;
+            self.in_transaction = false;
+        }
+        Ok(())
+    }
+}
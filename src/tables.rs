@@ -0,0 +1,520 @@
+use serde::Serialize;
+/// A value of a flat table.
+pub enum Cell {
+    Null,
+    Integer(u64),
+    Text(String),
+}
+impl std::fmt::Display for Cell {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Null => Ok(()),
+            Self::Integer(i) => write!(f, "{}", i),
+            Self::Text(i) => write!(f, "{}", i),
+        }
+    }
+}
+impl rusqlite::ToSql for Cell {
+    fn to_sql(&self) -> rusqlite::Result<rusqlite::types::ToSqlOutput<'_>> {
+        Ok(match self {
+            Self::Null => rusqlite::types::ToSqlOutput::from(rusqlite::types::Null),
+            // SQLite only has signed integers, counts past i64::MAX are saturated
+            Self::Integer(i) => rusqlite::types::ToSqlOutput::from(
+                std::convert::TryFrom::try_from(*i).unwrap_or(i64::MAX),
+            ),
+            Self::Text(i) => rusqlite::types::ToSqlOutput::from(i.as_str()),
+        })
+    }
+}
+pub trait Row: Serialize {
+    const TABLE: &'static str;
+    /// Names and SQLite types of the columns, in the order of `cells`
+    const COLUMNS: &'static [(&'static str, &'static str)];
+    fn cells(&self) -> Vec<Cell>;
+}
+#[derive(Serialize)]
+pub struct GameRow {
+    pub website: String,
+    /// As text, like in the front matter, since ids use the whole u64 range which SQLite and
+    /// JavaScript integers can't hold
+    pub id: String,
+    pub name: String,
+    pub published: String,
+    pub viewed: u64,
+    pub tags: Vec<String>,
+    pub file_count: usize,
+    /// Sum of the known file sizes
+    pub total_size: u64,
+}
+impl Row for GameRow {
+    const TABLE: &'static str = "games";
+    const COLUMNS: &'static [(&'static str, &'static str)] = &[
+        ("website", "TEXT"),
+        ("id", "TEXT"),
+        ("name", "TEXT"),
+        ("published", "TEXT"),
+        ("viewed", "INTEGER"),
+        ("tags", "TEXT"),
+        ("file_count", "INTEGER"),
+        ("total_size", "INTEGER"),
+    ];
+    fn cells(&self) -> Vec<Cell> {
+        vec![
+            Cell::Text(self.website.clone()),
+            Cell::Text(self.id.clone()),
+            Cell::Text(self.name.clone()),
+            Cell::Text(self.published.clone()),
+            Cell::Integer(self.viewed),
+            Cell::Text(serde_json::to_string(&self.tags).unwrap()),
+            Cell::Integer(self.file_count as u64),
+            Cell::Integer(self.total_size),
+        ]
+    }
+}
+#[derive(Serialize)]
+pub struct FileRow {
+    pub website: String,
+    pub game_id: String,
+    pub name: String,
+    pub link: String,
+    pub size: Option<u64>,
+}
+impl Row for FileRow {
+    const TABLE: &'static str = "files";
+    const COLUMNS: &'static [(&'static str, &'static str)] = &[
+        ("website", "TEXT"),
+        ("game_id", "TEXT"),
+        ("name", "TEXT"),
+        ("link", "TEXT"),
+        ("size", "INTEGER"),
+    ];
+    fn cells(&self) -> Vec<Cell> {
+        vec![
+            Cell::Text(self.website.clone()),
+            Cell::Text(self.game_id.clone()),
+            Cell::Text(self.name.clone()),
+            Cell::Text(self.link.clone()),
+            self.size.map(Cell::Integer).unwrap_or(Cell::Null),
+        ]
+    }
+}
+#[derive(Serialize)]
+pub struct CommentRow {
+    pub website: String,
+    pub game_id: String,
+    /// Position of the comment among the comments of the game, replies following their parent
+    pub position: usize,
+    pub parent: Option<usize>,
+    /// 0 for comments, 1 for their replies and so on
+    pub depth: usize,
+    pub author: String,
+    pub date: String,
+    pub avatar: String,
+    pub text: String,
+    pub images: Vec<String>,
+}
+impl Row for CommentRow {
+    const TABLE: &'static str = "comments";
+    const COLUMNS: &'static [(&'static str, &'static str)] = &[
+        ("website", "TEXT"),
+        ("game_id", "TEXT"),
+        ("position", "INTEGER"),
+        ("parent", "INTEGER"),
+        ("depth", "INTEGER"),
+        ("author", "TEXT"),
+        ("date", "TEXT"),
+        ("avatar", "TEXT"),
+        ("text", "TEXT"),
+        ("images", "TEXT"),
+    ];
+    fn cells(&self) -> Vec<Cell> {
+        vec![
+            Cell::Text(self.website.clone()),
+            Cell::Text(self.game_id.clone()),
+            Cell::Integer(self.position as u64),
+            self.parent
+                .map(|x| Cell::Integer(x as u64))
+                .unwrap_or(Cell::Null),
+            Cell::Integer(self.depth as u64),
+            Cell::Text(self.author.clone()),
+            Cell::Text(self.date.clone()),
+            Cell::Text(self.avatar.clone()),
+            Cell::Text(self.text.clone()),
+            Cell::Text(serde_json::to_string(&self.images).unwrap()),
+        ]
+    }
+}
+/// The game database as flat tables, for analysis.
+pub struct Tables {
+    pub games: Vec<GameRow>,
+    pub files: Vec<FileRow>,
+    pub comments: Vec<CommentRow>,
+}
+impl Tables {
+    pub fn build(database: &crate::saved::GameTextDatabase) -> Self {
+        fn saturate(x: u128) -> u64 {
+            std::convert::TryFrom::try_from(x).unwrap_or(u64::MAX)
+        }
+        fn add_comments(
+            comments: &[crate::saved::Comment],
+            game: &crate::saved::GameTextInformation,
+            parent: Option<usize>,
+            depth: usize,
+            rows: &mut Vec<CommentRow>,
+            position: &mut usize,
+        ) {
+            for comment in comments {
+                let mut text = Vec::new();
+                let mut images = Vec::new();
+                for content in &comment.content {
+                    match content {
+                        crate::saved::ParagraphContent::Text(i) => text.push(i.as_str()),
+                        crate::saved::ParagraphContent::Image(i) => images.push(i.clone()),
+                    }
+                }
+                let current = *position;
+                rows.push(CommentRow {
+                    website: game.website.clone(),
+                    game_id: game.id.to_string(),
+                    position: current,
+                    parent,
+                    depth,
+                    author: comment.author.clone(),
+                    date: comment.date.format("%F %H:%M:%S"),
+                    avatar: comment.user_avatar.clone(),
+                    text: text.join("\n"),
+                    images,
+                });
+                *position += 1;
+                add_comments(
+                    &comment.replies,
+                    game,
+                    Some(current),
+                    depth + 1,
+                    rows,
+                    position,
+                );
+            }
+        }
+        let mut tables = Self {
+            games: Vec::new(),
+            files: Vec::new(),
+            comments: Vec::new(),
+        };
+        for game in database {
+            tables.games.push(GameRow {
+                website: game.website.clone(),
+                id: game.id.to_string(),
+                name: game.name.clone(),
+                published: game.published.format("%F %H:%M:%S"),
+                viewed: saturate(game.viewed),
+                tags: game.tags.clone(),
+                file_count: game.files.len(),
+                total_size: saturate(
+                    game.files
+                        .iter()
+                        .filter_map(|x| x.1 .1)
+                        .fold(0, u128::saturating_add),
+                ),
+            });
+            for file in &game.files {
+                tables.files.push(FileRow {
+                    website: game.website.clone(),
+                    game_id: game.id.to_string(),
+                    name: file.0.clone(),
+                    link: file.1 .0.clone(),
+                    size: file.1 .1.map(saturate),
+                });
+            }
+            add_comments(&game.comments, game, None, 0, &mut tables.comments, &mut 0);
+        }
+        tables
+    }
+    pub fn write_csv(&self, location: &str) -> Result<(), String> {
+        fn write<R: Row>(location: &str, rows: &[R]) -> Result<(), String> {
+            let mut writer = csv::Writer::from_path(format!("{}/{}.csv", location, R::TABLE))
+                .map_err(|x| x.to_string())?;
+            writer
+                .write_record(R::COLUMNS.iter().map(|x| x.0))
+                .map_err(|x| x.to_string())?;
+            for row in rows {
+                writer
+                    .write_record(row.cells().iter().map(|x| x.to_string()))
+                    .map_err(|x| x.to_string())?;
+            }
+            writer.flush().map_err(|x| x.to_string())
+        }
+        write(location, &self.games)?;
+        write(location, &self.files)?;
+        write(location, &self.comments)
+    }
+    pub fn write_ndjson(&self, location: &str) -> Result<(), String> {
+        fn write<R: Row>(location: &str, rows: &[R]) -> Result<(), String> {
+            use std::io::Write;
+            let file = std::fs::File::create(format!("{}/{}.ndjson", location, R::TABLE))
+                .map_err(|x| x.to_string())?;
+            let mut writer = std::io::BufWriter::new(file);
+            for row in rows {
+                serde_json::to_writer(&mut writer, row).map_err(|x| x.to_string())?;
+                writer.write_all(b"\n").map_err(|x| x.to_string())?;
+            }
+            writer.flush().map_err(|x| x.to_string())
+        }
+        write(location, &self.games)?;
+        write(location, &self.files)?;
+        write(location, &self.comments)
+    }
+    /// Writes every table to `tables.sqlite`, replacing the previous export.
+    pub fn write_sqlite(&self, location: &str) -> Result<(), String> {
+        fn write<R: Row>(connection: &rusqlite::Connection, rows: &[R]) -> Result<(), String> {
+            connection
+                .execute_batch(&format!(
+                    "CREATE TABLE {} ({});",
+                    R::TABLE,
+                    R::COLUMNS
+                        .iter()
+                        .map(|x| format!("{} {}", x.0, x.1))
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ))
+                .map_err(|x| x.to_string())?;
+            let mut statement = connection
+                .prepare(&format!(
+                    "INSERT INTO {} VALUES ({})",
+                    R::TABLE,
+                    vec!["?"; R::COLUMNS.len()].join(", ")
+                ))
+                .map_err(|x| x.to_string())?;
+            for row in rows {
+                statement.execute(row.cells()).map_err(|x| x.to_string())?;
+            }
+            Ok(())
+        }
+        let file = format!("{}/tables.sqlite", location);
+        if std::path::Path::new(&file).exists() {
+            std::fs::remove_file(&file).map_err(|x| x.to_string())?;
+        }
+        let mut connection = rusqlite::Connection::open(&file).map_err(|x| x.to_string())?;
+        let transaction = connection.transaction().map_err(|x| x.to_string())?;
+        write(&transaction, &self.games)?;
+        write(&transaction, &self.files)?;
+        write(&transaction, &self.comments)?;
+        transaction.commit().map_err(|x| x.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Tables;
+    use crate::saved::{Comment, GameTextInformation, ParagraphContent};
+    fn comment(author: &str, replies: Vec<Comment>) -> Comment {
+        Comment {
+            user_avatar: format!("https://a/{}.png", author),
+            author: author.to_string(),
+            date: time::PrimitiveDateTime::new(time::date!(2020 - 01 - 02), time::time!(3:04:05)),
+            content: vec![
+                ParagraphContent::Text(String::from("Line 1")),
+                ParagraphContent::Image(String::from("https://a/image.png")),
+                ParagraphContent::Text(String::from("Line 2")),
+            ],
+            replies,
+        }
+    }
+    /// A game with every count past what SQLite can hold and nested comments, and a plain one.
+    fn database() -> crate::saved::GameTextDatabase {
+        vec![
+            GameTextInformation {
+                name: String::from("First, \"quoted\""),
+                tags: vec![String::from("a"), String::from("b,c")],
+                viewed: u128::MAX,
+                files: vec![
+                    (
+                        String::from("1.zip"),
+                        (String::from("https://a/1"), Some(10)),
+                    ),
+                    (String::from("2.zip"), (String::from("https://a/2"), None)),
+                    (
+                        String::from("3.zip"),
+                        (String::from("https://a/3"), Some(u128::MAX)),
+                    ),
+                ],
+                comments: vec![
+                    comment("A", vec![comment("B", vec![comment("C", Vec::new())])]),
+                    comment("D", Vec::new()),
+                ],
+                ..GameTextInformation::default(u64::MAX, String::from("kkgal"))
+            },
+            GameTextInformation {
+                name: String::from("Second"),
+                viewed: 7,
+                files: vec![
+                    (
+                        String::from("1.zip"),
+                        (String::from("https://b/1"), Some(1)),
+                    ),
+                    (
+                        String::from("2.zip"),
+                        (String::from("https://b/2"), Some(2)),
+                    ),
+                ],
+                comments: vec![comment("E", Vec::new())],
+                ..GameTextInformation::default(2, String::from("liuli"))
+            },
+        ]
+    }
+    fn directory(name: &str) -> String {
+        let directory = std::env::temp_dir().join(format!("bishojo-tables-{}", name));
+        let _ = std::fs::remove_dir_all(&directory);
+        std::fs::create_dir_all(&directory).unwrap();
+        directory.to_str().unwrap().to_string()
+    }
+    #[test]
+    fn flattens_games_files_and_comments() {
+        let tables = Tables::build(&database());
+        let games: Vec<_> = tables
+            .games
+            .iter()
+            .map(|x| (x.id.as_str(), x.viewed, x.file_count, x.total_size))
+            .collect();
+        assert_eq!(
+            games,
+            [
+                ("18446744073709551615", u64::MAX, 3, u64::MAX),
+                ("2", 7, 2, 3)
+            ]
+        );
+        let sizes: Vec<_> = tables.files.iter().map(|x| x.size).collect();
+        assert_eq!(sizes, [Some(10), None, Some(u64::MAX), Some(1), Some(2)]);
+        let comments: Vec<_> = tables
+            .comments
+            .iter()
+            .map(|x| {
+                (
+                    x.game_id.as_str(),
+                    x.author.as_str(),
+                    x.position,
+                    x.parent,
+                    x.depth,
+                )
+            })
+            .collect();
+        assert_eq!(
+            comments,
+            [
+                ("18446744073709551615", "A", 0, None, 0),
+                ("18446744073709551615", "B", 1, Some(0), 1),
+                ("18446744073709551615", "C", 2, Some(1), 2),
+                ("18446744073709551615", "D", 3, None, 0),
+                ("2", "E", 0, None, 0)
+            ]
+        );
+        assert_eq!(tables.comments[0].text, "Line 1\nLine 2");
+        assert_eq!(tables.comments[0].images, ["https://a/image.png"]);
+        assert_eq!(tables.comments[0].date, "2020-01-02 03:04:05");
+    }
+    #[test]
+    fn writes_csv() {
+        let location = directory("csv");
+        Tables::build(&database()).write_csv(&location).unwrap();
+        let read = |table: &str| -> Vec<Vec<String>> {
+            csv::Reader::from_path(format!("{}/{}.csv", location, table))
+                .unwrap()
+                .records()
+                .map(|x| x.unwrap().iter().map(String::from).collect())
+                .collect()
+        };
+        let games = read("games");
+        assert_eq!(games.len(), 2);
+        assert_eq!(
+            games[0],
+            [
+                "kkgal",
+                "18446744073709551615",
+                "First, \"quoted\"",
+                "2019-01-01 00:00:00",
+                "18446744073709551615",
+                "[\"a\",\"b,c\"]",
+                "3",
+                "18446744073709551615"
+            ]
+        );
+        assert_eq!(read("files")[1][4], "");
+        let comments = read("comments");
+        assert_eq!(comments.len(), 5);
+        assert_eq!(comments[2][2..5], ["2", "1", "2"]);
+        assert_eq!(comments[3][3], "");
+        assert_eq!(comments[0][8], "Line 1\nLine 2");
+    }
+    #[test]
+    fn writes_ndjson() {
+        let location = directory("ndjson");
+        Tables::build(&database()).write_ndjson(&location).unwrap();
+        let games = std::fs::read_to_string(format!("{}/games.ndjson", location)).unwrap();
+        let games: Vec<serde_json::Value> = games
+            .lines()
+            .map(|x| serde_json::from_str(x).unwrap())
+            .collect();
+        assert_eq!(games.len(), 2);
+        assert_eq!(games[0]["id"], "18446744073709551615");
+        assert_eq!(games[0]["tags"], serde_json::json!(["a", "b,c"]));
+    }
+    #[test]
+    fn writes_sqlite() {
+        let location = directory("sqlite");
+        let tables = Tables::build(&database());
+        tables.write_sqlite(&location).unwrap();
+        // A second export replaces the first one
+        tables.write_sqlite(&location).unwrap();
+        let connection = rusqlite::Connection::open(format!("{}/tables.sqlite", location)).unwrap();
+        let game: (String, String, i64, String, i64) = connection
+            .query_row(
+                "SELECT typeof(id), id, viewed, tags, total_size FROM games WHERE website = 'kkgal'",
+                rusqlite::NO_PARAMS,
+                |x| Ok((x.get(0)?, x.get(1)?, x.get(2)?, x.get(3)?, x.get(4)?)),
+            )
+            .unwrap();
+        assert_eq!(
+            game,
+            (
+                String::from("text"),
+                String::from("18446744073709551615"),
+                i64::MAX,
+                String::from("[\"a\",\"b,c\"]"),
+                i64::MAX
+            )
+        );
+        let count = |table: &str| -> i64 {
+            connection
+                .query_row(
+                    &format!("SELECT count(*) FROM {}", table),
+                    rusqlite::NO_PARAMS,
+                    |x| x.get(0),
+                )
+                .unwrap()
+        };
+        assert_eq!(
+            (count("games"), count("files"), count("comments")),
+            (2, 5, 5)
+        );
+        let replies: Vec<(String, Option<i64>, i64)> = connection
+            .prepare(
+                "SELECT author, parent, depth FROM comments WHERE game_id = '18446744073709551615'",
+            )
+            .unwrap()
+            .query_map(rusqlite::NO_PARAMS, |x| {
+                Ok((x.get(0)?, x.get(1)?, x.get(2)?))
+            })
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(
+            replies,
+            [
+                (String::from("A"), None, 0),
+                (String::from("B"), Some(0), 1),
+                (String::from("C"), Some(1), 2),
+                (String::from("D"), None, 0)
+            ]
+        );
+    }
+}
@@ -0,0 +1,238 @@
+use handlebars::handlebars_helper;
+use serde::Serialize;
+/// Built in templates, each one can be replaced by a file of the same name in the template
+/// directory.
+const DEFAULT_TEMPLATES: [(&str, &str); 5] = [
+    ("game", include_str!("../templates/game.hbs")),
+    ("listing", include_str!("../templates/listing.hbs")),
+    ("tags", include_str!("../templates/tags.hbs")),
+    ("content", include_str!("../templates/content.hbs")),
+    ("comment", include_str!("../templates/comment.hbs")),
+];
+/// Schemes which links and images of the pages may use, see `safe_url`.
+const ALLOWED_SCHEMES: [&str; 3] = ["http", "https", "magnet"];
+/// Returns `url` if it's relative or has one of the allowed schemes, `#` otherwise, so that a
+/// `javascript:` link scraped from a website can't run in the pages.
+pub fn safe_url(url: &str) -> &str {
+    if is_safe_url(url) {
+        url
+    } else {
+        "#"
+    }
+}
+/// Whether `url` is relative or has one of the allowed schemes.
+pub fn is_safe_url(url: &str) -> bool {
+    // Browsers ignore these when looking for the scheme
+    let cleaned: String = url
+        .trim_matches(|x: char| x.is_control() || x == ' ')
+        .chars()
+        .filter(|x| !matches!(x, '\t' | '\n' | '\r'))
+        .collect();
+    let scheme = match cleaned.find(|x| matches!(x, ':' | '/' | '?' | '#')) {
+        Some(i) if cleaned[i..].starts_with(':') => &cleaned[..i],
+        _ => return true,
+    };
+    ALLOWED_SCHEMES
+        .iter()
+        .any(|x| x.eq_ignore_ascii_case(scheme))
+}
+handlebars_helper!(SafeUrl: |url: str| safe_url(url));
+/// Renders html pages. Every value is escaped by the template engine, so the pages below only
+/// hold plain text. URLs taken from the websites go through the `safe_url` helper.
+pub struct Templates {
+    registry: handlebars::Handlebars<'static>,
+}
+impl Templates {
+    pub fn load(directory: Option<&str>) -> Result<Self, String> {
+        let mut registry = handlebars::Handlebars::new();
+        registry.register_helper("safe_url", Box::new(SafeUrl));
+        for (name, default) in DEFAULT_TEMPLATES.iter() {
+            let overridden = directory
+                .map(|x| std::path::Path::new(x).join(format!("{}.hbs", name)))
+                .filter(|x| x.is_file());
+            let template = match overridden {
+                Some(i) => std::fs::read_to_string(&i)
+                    .map_err(|x| format!("{}: {}", i.to_string_lossy(), x))?,
+                None => default.to_string(),
+            };
+            registry
+                .register_template_string(name, template)
+                .map_err(|x| format!("{}.hbs: {}", name, x))?;
+        }
+        Ok(Self { registry })
+    }
+    pub fn game(&self, page: &GamePage) -> Result<String, String> {
+        self.registry
+            .render("game", page)
+            .map_err(|x| x.to_string())
+    }
+    pub fn listing(&self, page: &ListingPage) -> Result<String, String> {
+        self.registry
+            .render("listing", page)
+            .map_err(|x| x.to_string())
+    }
+    pub fn tags(&self, page: &TagsPage) -> Result<String, String> {
+        self.registry
+            .render("tags", page)
+            .map_err(|x| x.to_string())
+    }
+}
+#[derive(Serialize)]
+pub struct Link {
+    pub name: String,
+    pub href: String,
+}
+#[derive(Serialize)]
+pub struct Content {
+    pub lines: Vec<String>,
+    pub image: Option<String>,
+    /// Shown in place of the image, which it links to
+    pub thumbnail: Option<String>,
+}
+#[derive(Serialize)]
+pub struct Paragraph {
+    pub title: Option<String>,
+    pub content: Vec<Content>,
+}
+#[derive(Serialize)]
+pub struct File {
+    pub name: String,
+    pub link: String,
+    pub size: Option<String>,
+}
+#[derive(Serialize)]
+pub struct Comment {
+    pub avatar: String,
+    pub avatar_thumbnail: Option<String>,
+    pub author: String,
+    pub date: String,
+    pub content: Vec<Content>,
+    pub replies: Vec<Comment>,
+}
+#[derive(Serialize)]
+pub struct GamePage {
+    /// Prefix of links to the listing pages
+    pub root: String,
+    pub name: String,
+    pub id: u64,
+    pub website: String,
+    pub website_href: String,
+    pub published: String,
+    pub viewed: u128,
+    pub tags: Vec<Link>,
+    pub paragraphs: Vec<Paragraph>,
+    pub files: Vec<File>,
+    pub comments: Vec<Comment>,
+}
+#[derive(Serialize)]
+pub struct ListedGame {
+    pub href: String,
+    pub name: String,
+    pub id: u64,
+    pub website: String,
+    pub published: String,
+    pub viewed: u128,
+}
+#[derive(Serialize)]
+pub struct Pages {
+    pub current: usize,
+    pub count: usize,
+    pub previous: Option<String>,
+    pub next: Option<String>,
+}
+#[derive(Serialize)]
+pub struct ListingPage {
+    pub root: String,
+    pub title: String,
+    /// Action of the search form, if there is one
+    pub search: Option<String>,
+    pub query: Option<String>,
+    pub sites: Vec<Link>,
+    /// How the games are sorted, if the page is one of the sorted indexes
+    pub sort: Option<String>,
+    pub game_count: usize,
+    pub games: Vec<ListedGame>,
+    pub pages: Option<Pages>,
+}
+#[derive(Serialize)]
+pub struct ListedTag {
+    pub name: String,
+    pub href: String,
+    pub count: usize,
+}
+#[derive(Serialize)]
+pub struct TagsPage {
+    pub root: String,
+    pub tags: Vec<ListedTag>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{safe_url, Content, File, GamePage, Paragraph, Templates};
+    #[test]
+    fn keeps_web_magnet_and_relative_urls() {
+        for url in &[
+            "https://www.liuli.cat/wp/80812.html",
+            "HTTP://example.com",
+            "magnet:?xt=urn:btih:0123456789abcdef",
+            "imgs/3a7bd3e2.png",
+            "../index.html",
+            "/blobs/3a7bd3e2",
+            "?page=2",
+        ] {
+            assert_eq!(safe_url(url), *url);
+        }
+    }
+    #[test]
+    fn replaces_other_schemes() {
+        for url in &[
+            "javascript:alert(1)",
+            "JavaScript:alert(1)",
+            " java\tscript:alert(1)",
+            "\u{1}javascript:alert(1)",
+            "data:text/html,<script>alert(1)</script>",
+            "vbscript:msgbox",
+        ] {
+            assert_eq!(safe_url(url), "#");
+        }
+    }
+    #[test]
+    fn renders_unsafe_links_and_images_as_placeholders() {
+        let page = GamePage {
+            root: String::new(),
+            name: String::from("Game"),
+            id: 1,
+            website: String::from("liuli"),
+            website_href: String::from("sites/liuli.html"),
+            published: String::new(),
+            viewed: 0,
+            tags: Vec::new(),
+            paragraphs: vec![Paragraph {
+                title: None,
+                content: vec![Content {
+                    lines: Vec::new(),
+                    image: Some(String::from("javascript:alert('image')")),
+                    thumbnail: None,
+                }],
+            }],
+            files: vec![
+                File {
+                    name: String::from("Bad"),
+                    link: String::from("javascript:alert('file')"),
+                    size: None,
+                },
+                File {
+                    name: String::from("Good"),
+                    link: String::from("https://example.com/game.zip"),
+                    size: None,
+                },
+            ],
+            comments: Vec::new(),
+        };
+        let html = Templates::load(None).unwrap().game(&page).unwrap();
+        assert!(!html.contains("javascript"));
+        assert!(html.contains(r##"<img src="#" width="50%">"##));
+        assert!(html.contains(r##"<a href="#" download="Bad">"##));
+        assert!(html.contains(r#"<a href="https://example.com/game.zip" download="Good">"#));
+    }
+}
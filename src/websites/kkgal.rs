@@ -1,87 +1,65 @@
 use async_trait::async_trait;
-use futures::io::AsyncReadExt;
 use futures::stream::StreamExt;
 use isahc::prelude::*;
 pub const WEBSITE_LINK: &'static str = "https://www.kkgal.com";
-pub struct KKGal {}
+pub struct KKGal {
+    pub settings: crate::config::SiteSettings,
+}
 impl KKGal {
     pub async fn download_file_information(
+        &self,
         url: &str,
-        client: &isahc::HttpClient,
+        client: &crate::client::HttpClient,
         log_client: &crate::log::LoggingClient,
-    ) -> Result<Vec<(String, (String, Option<u128>))>, String> {
+    ) -> Result<Vec<(String, (String, Option<u128>))>, super::Error> {
         #[derive(serde::Deserialize)]
         struct DownloadedFileStructure {
             pub name: String,
             pub size: String,
             pub date: String,
         }
-        let mut response_content;
-        let parsed_html = loop {
-            let mut response_body = client
-                .send_async(
-                    Request::get(url)
-                        .header(isahc::http::header::REFERER, WEBSITE_LINK)
-                        .body(())
-                        .map_err(|x| x.to_string())?,
+        fn parse_script(
+            parsed_html: &[rusthtml::HtmlTag],
+        ) -> Result<Vec<DownloadedFileStructure>, String> {
+            let script = parsed_html
+                .get(
+                    parsed_html
+                        .iter()
+                        .enumerate()
+                        .find(|x| x.1 == &rusthtml::HtmlTag::OpeningTag("script", vec![]))
+                        .ok_or(String::from("Error while finding script"))?
+                        .0
+                        + 1,
                 )
-                .await
-                .map_err(|x| x.to_string())?
-                .into_body();
-            let mut response_vec = Vec::new();
-            response_body
-                .read_to_end(&mut response_vec)
-                .await
-                .map_err(|x| x.to_string())?;
-            response_content = String::from_utf8_lossy(&response_vec).to_string();
-            match Self::pass_groot_verify(&response_content, client, log_client).await {
-                Ok(u) => {
-                    if let Some(i) = u {
-                        break i;
-                    }
-                }
-                Err(i) => return Err(i),
-            }
-        };
-        let script = parsed_html
-            .get(
-                parsed_html
-                    .iter()
-                    .enumerate()
-                    .find(|x| x.1 == &rusthtml::HtmlTag::OpeningTag("script", vec![]))
-                    .ok_or(String::from("Error while finding script"))?
-                    .0
-                    + 1,
-            )
-            .ok_or(String::from("Error while reading script"))?;
-        let script = if let rusthtml::HtmlTag::Unparsable(i) = script {
-            i
-        } else {
-            return Err(String::from("Assertion error: script should be a text"));
-        };
-        let text = {
-            let text_start = script
-                .find("rawData")
-                .ok_or(String::from("Error while finding rawData of the script"))?;
-            let text = &script[text_start..];
-            let text_start = text.find("\"").ok_or(String::from(
-                "Error while finding start position of the script",
-            ))?;
-            let text = &text[text_start + 1..];
-            let text_end = text.find("\"").ok_or(String::from(
-                "Error while finding end position of the script",
-            ))?;
-            text[..text_end].to_string()
-        };
-        let text = unescape::unescape(&text).ok_or(String::from("Error while unescaping quote"))?;
-        let text =
-            base64::decode(&text).map_err(|x| format!("Error while parsing script: {}", x))?;
-        let parsed_detail: Vec<DownloadedFileStructure> = serde_json::from_slice(&text)
-            .map_err(|x| format!("Error while deserializing script: {}", x))?;
-        let mut constructed = Vec::new();
-        for detail in parsed_detail {
-            let mut site_link = format!("{}/{}", url, &detail.name);
-            let mut date_and_time: std::str::SplitWhitespace = detail.date.split_whitespace();
+                .ok_or(String::from("Error while reading script"))?;
+            let script = if let rusthtml::HtmlTag::Unparsable(i) = script {
+                i
+            } else {
+                return Err(String::from("Assertion error: script should be a text"));
+            };
+            let text = {
+                let text_start = script
+                    .find("rawData")
+                    .ok_or(String::from("Error while finding rawData of the script"))?;
+                let text = &script[text_start..];
+                let text_start = text.find("\"").ok_or(String::from(
+                    "Error while finding start position of the script",
+                ))?;
+                let text = &text[text_start + 1..];
+                let text_end = text.find("\"").ok_or(String::from(
+                    "Error while finding end position of the script",
+                ))?;
+                text[..text_end].to_string()
+            };
+            let text =
+                unescape::unescape(&text).ok_or(String::from("Error while unescaping quote"))?;
+            let text =
+                base64::decode(&text).map_err(|x| format!("Error while parsing script: {}", x))?;
+            serde_json::from_slice(&text)
+                .map_err(|x| format!("Error while deserializing script: {}", x))
+        }
+        fn parse_date(date: &str) -> Result<time::PrimitiveDateTime, String> {
+            let mut date_and_time: std::str::SplitWhitespace = date.split_whitespace();
             let mut date = date_and_time
                 .next()
                 .ok_or(String::from("Error while parsing date from file detail"))?
@@ -90,7 +68,7 @@ impl KKGal {
                 .next()
                 .ok_or(String::from("Error while parsing time from file detail"))?
                 .split(':');
-            let _date = time::Date::try_from_ymd(
+            time::Date::try_from_ymd(
                 date.next()
                     .ok_or("Bad date".to_string())?
                     .parse()
@@ -124,7 +102,21 @@ impl KKGal {
                     .parse()
                     .map_err(|_| String::from("Error while parsing second"))?,
             )
-            .map_err(|x| x.to_string())?;
+            .map_err(|x| x.to_string())
+        }
+        let response_content = Self::download_page(
+            || Request::get(url).header(isahc::http::header::REFERER, WEBSITE_LINK),
+            client,
+            log_client,
+        )
+        .await?;
+        let parsed_detail = parse_script(&rusthtml::HtmlTag::parse(&response_content))
+            .map_err(|x| super::Error::parse(url, String::from("download script"), x))?;
+        let mut constructed = Vec::new();
+        for detail in parsed_detail {
+            let mut site_link = format!("{}/{}", url, &detail.name);
+            let _date = parse_date(&detail.date)
+                .map_err(|x| super::Error::parse(url, format!("date of {}", detail.name), x))?;
             //log_client.log(
             //    crate::log::LoggingLevel::StatusReport,
             //    &format!(
@@ -135,14 +127,13 @@ impl KKGal {
             //    ),
             //);
             //FIXME: this is discouraged.
-            if std::env::var_os("KKGAL_USE_DIRECT").is_some() {
+            if self.settings.direct == Some(true) {
                 if let Ok(i) = client
                     .send_async(
                         Request::get(&site_link)
                             .redirect_policy(isahc::config::RedirectPolicy::None)
                             .header(isahc::http::header::REFERER, WEBSITE_LINK)
-                            .body(())
-                            .map_err(|x| x.to_string())?,
+                            .body(())?,
                     )
                     .await
                 {
@@ -169,76 +160,118 @@ impl KKGal {
         }
         Ok(constructed)
     }
-    async fn pass_groot_verify<'a>(
-        response: &'a str,
-        http_client: &isahc::HttpClient,
-        log_client: &crate::log::LoggingClient,
-    ) -> Result<Option<Vec<rusthtml::HtmlTag<'a>>>, String> {
-        let response_parsed = rusthtml::HtmlTag::parse(response);
-        if response_parsed
-            .iter()
-            .find(|x| &&rusthtml::HtmlTag::Unparsable("I'm Groot") == x)
-            .is_none()
+    /// Where the download link redirects to, so that the file can be fetched with another proxy.
+    async fn resolve_redirect(
+        link: &str,
+        http_client: &crate::client::HttpClient,
+    ) -> Result<String, super::Error> {
+        let response = http_client
+            .send_async(
+                Request::get(link)
+                    .redirect_policy(isahc::config::RedirectPolicy::None)
+                    .header(isahc::http::header::REFERER, WEBSITE_LINK)
+                    .body(())?,
+            )
+            .await?;
+        let response = if response.status().is_redirection() {
+            response
+        } else {
+            super::check_response(response, link, super::Expected::Page).await?
+        };
+        match response
+            .headers()
+            .get("location")
+            .and_then(|x| x.to_str().ok())
         {
-            return Ok(Some(response_parsed));
+            Some(i) => Ok(i.to_string()),
+            None => Err(super::Error::Response(super::ResponseError {
+                status: response.status(),
+                url: link.to_string(),
+                content_type: None,
+                snippet: String::from("No usable location header"),
+                retry_after: None,
+            })),
+        }
+    }
+    /// Downloads the page built by `request`, passing the Groot challenge if it's served
+    /// instead. Being challenged again right after is an error.
+    async fn download_page(
+        request: impl Fn() -> isahc::http::request::Builder,
+        http_client: &crate::client::HttpClient,
+        log_client: &crate::log::LoggingClient,
+    ) -> Result<String, super::Error> {
+        let mut passed = false;
+        loop {
+            let request = request().body(())?;
+            let url = request.uri().to_string();
+            let response_vec =
+                super::read_response(request, super::Expected::Page, http_client).await?;
+            let response = String::from_utf8_lossy(&response_vec).to_string();
+            if !Self::is_groot(&response) {
+                return Ok(response);
+            }
+            if passed {
+                return Err(super::Error::Challenge {
+                    url,
+                    message: String::from("Groot detected again after passing it"),
+                });
+            }
+            Self::pass_groot_verify(&url, &response, http_client, log_client).await?;
+            passed = true;
         }
-        for i in response_parsed {
+    }
+    fn is_groot(response: &str) -> bool {
+        response.contains("I'm Groot")
+            && rusthtml::HtmlTag::parse(response)
+                .iter()
+                .any(|x| x == &rusthtml::HtmlTag::Unparsable("I'm Groot"))
+    }
+    async fn pass_groot_verify(
+        url: &str,
+        response: &str,
+        http_client: &crate::client::HttpClient,
+        log_client: &crate::log::LoggingClient,
+    ) -> Result<(), super::Error> {
+        let challenge = |message: &str| super::Error::Challenge {
+            url: url.to_string(),
+            message: message.to_string(),
+        };
+        for i in rusthtml::HtmlTag::parse(response) {
             if let rusthtml::HtmlTag::OpeningTag("a", j) = i {
                 let link = j
                     .iter()
                     .find(|x| x.0 == "href")
-                    .ok_or(String::from("Unknown link format when passing groot"))?
+                    .ok_or_else(|| challenge("Unknown link format when passing groot"))?
                     .1
-                    .ok_or(String::from("Link without argument when passing groot"))?;
-                http_client
-                    .send_async(
-                        Request::get(&format!("{}{}", WEBSITE_LINK, link))
-                            .header(isahc::http::header::CONNECTION, "keep-alive")
-                            .body(())
-                            .map_err(|x| x.to_string())?,
-                    )
-                    .await
-                    .map_err(|x| x.to_string())?;
+                    .ok_or_else(|| challenge("Link without argument when passing groot"))?;
+                super::read_response(
+                    Request::get(&format!("{}{}", WEBSITE_LINK, link))
+                        .header(isahc::http::header::CONNECTION, "keep-alive")
+                        .body(())?,
+                    super::Expected::Page,
+                    http_client,
+                )
+                .await?;
                 log_client.log(crate::log::LoggingLevel::StatusReport, "Groot Bypassed.");
-                return Ok(None);
+                return Ok(());
             }
         }
-        Err(String::from("Groot detected, but cannot find link"))
+        Err(challenge("Groot detected, but cannot find link"))
     }
     pub async fn download_index(
         page: u32,
-        http_client: &isahc::HttpClient,
+        http_client: &crate::client::HttpClient,
         log_client: &crate::log::LoggingClient,
-    ) -> Result<Vec<String>, String> {
-        let mut content_text;
-        let mut parsed_html = loop {
-            let mut content = http_client
-                .send_async(
-                    Request::get(&format!("{}/page/{}/", WEBSITE_LINK, page.to_string()))
-                        .header(isahc::http::header::CONNECTION, "keep-alive")
-                        .body(())
-                        .map_err(|x| x.to_string())?,
-                )
-                .await
-                .map_err(|x| x.to_string())?
-                .into_body();
-            let mut content_text_vec = Vec::new();
-            content
-                .read_to_end(&mut content_text_vec)
-                .await
-                .map_err(|x| x.to_string())?;
-            content_text = String::from_utf8_lossy(&content_text_vec).to_string();
-            match Self::pass_groot_verify(&content_text, http_client, log_client).await {
-                Ok(i) => {
-                    if let Some(i) = i {
-                        break i;
-                    }
-                }
-                Err(i) => {
-                    return Err(i);
-                }
-            }
-        };
+    ) -> Result<Vec<String>, super::Error> {
+        let url = format!("{}/page/{}/", WEBSITE_LINK, page.to_string());
+        let content_text = Self::download_page(
+            || Request::get(&url).header(isahc::http::header::CONNECTION, "keep-alive"),
+            http_client,
+            log_client,
+        )
+        .await?;
+        let mut parsed_html = rusthtml::HtmlTag::parse(&content_text);
+        let total = parsed_html.len();
         let mut found_links = Vec::new();
         while parsed_html.len() != 0 {
             match parsed_html.remove(0) {
@@ -254,7 +287,14 @@ impl KKGal {
                                         .filter(|x| x.0 == "href")
                                         .map(|x| x.1.unwrap().to_string())
                                         .next()
-                                        .ok_or(String::from("Bad link"))?,
+                                        .ok_or_else(|| {
+                                            super::parse_error(
+                                                &url,
+                                                total,
+                                                parsed_html.len(),
+                                                String::from("Bad link"),
+                                            )
+                                        })?,
                                 );
                             }
                         }
@@ -270,40 +310,56 @@ impl KKGal {
         Ok(found_links)
     }
     pub async fn download_information(
+        &self,
         url_owned: String,
-        http_client: &isahc::HttpClient,
+        http_client: &crate::client::HttpClient,
         log_client: &crate::log::LoggingClient,
-    ) -> Result<crate::saved::GameTextInformation, String> {
+    ) -> Result<crate::saved::GameTextInformation, super::Error> {
         let url: &str = &url_owned;
-        let mut response_text;
-        let mut parsed_html = loop {
-            let mut response = http_client
-                .send_async(
-                    Request::get(url)
-                        .header(isahc::http::header::CONNECTION, "keep-alive")
-                        .body(())
-                        .map_err(|x| x.to_string())?,
-                )
-                .await
-                .map_err(|x| x.to_string())?
-                .into_body();
-            let mut response_vec = Vec::new();
-            response
-                .read_to_end(&mut response_vec)
+        let response_text = Self::download_page(
+            || Request::get(url).header(isahc::http::header::CONNECTION, "keep-alive"),
+            http_client,
+            log_client,
+        )
+        .await?;
+        let mut parsed_html = rusthtml::HtmlTag::parse(&response_text);
+        let total = parsed_html.len();
+        let mut constructed = Self::parse_information(url, &mut parsed_html)
+            .map_err(|x| super::parse_error(url, total, parsed_html.len(), x))?;
+        if let Some(i) = constructed.miscellaneous.get("overall_link") {
+            match self
+                .download_file_information(i, http_client, log_client)
                 .await
-                .map_err(|x| x.to_string())?;
-            response_text = String::from_utf8_lossy(&response_vec).to_string();
-            match Self::pass_groot_verify(&response_text, http_client, log_client).await {
-                Ok(i) => {
-                    if let Some(i) = i {
-                        break i;
-                    }
-                }
-                Err(i) => {
-                    return Err(i);
+            {
+                Ok(i) => constructed.files = i,
+                Err(j) => {
+                    log_client.log(
+                        crate::log::LoggingLevel::Warning,
+                        &format!(
+                            "Error while parsing download url: {}, storing it as unparsable link.",
+                            j
+                        ),
+                    );
+                    constructed.files = vec![(
+                        String::from("Download link"),
+                        (format!("Unparsable:{}", i), None),
+                    )]
                 }
             }
-        };
+        }
+        log_client.log(
+            crate::log::LoggingLevel::StatusReport,
+            &format!(
+                "Parsed information from {}(id: {})",
+                constructed.name, constructed.id
+            ),
+        );
+        Ok(constructed)
+    }
+    fn parse_information(
+        url: &str,
+        parsed_html: &mut Vec<rusthtml::HtmlTag>,
+    ) -> Result<crate::saved::GameTextInformation, String> {
         let mut constructed = crate::saved::GameTextInformation::default(
             seahash::hash(url.as_bytes()),
             String::from("kkgal"),
@@ -557,62 +613,39 @@ impl KKGal {
                 _ => continue,
             }
         }
-        if let Some(i) = constructed.miscellaneous.get("overall_link") {
-            match Self::download_file_information(i, http_client, log_client).await {
-                Ok(i) => constructed.files = i,
-                Err(j) => {
-                    log_client.log(
-                        crate::log::LoggingLevel::Warning,
-                        &format!(
-                            "Error while parsing download url: {}, storing it as unparsable link.",
-                            j
-                        ),
-                    );
-                    constructed.files = vec![(
-                        String::from("Download link"),
-                        (format!("Unparsable:{}", i), None),
-                    )]
-                }
-            }
-        }
         constructed
             .paragraphs
             .push((Some(String::from("Screenshots")), screenshots));
-        log_client.log(
-            crate::log::LoggingLevel::StatusReport,
-            &format!(
-                "Parsed information from {}(id: {})",
-                constructed.name, constructed.id
-            ),
-        );
         Ok(constructed)
     }
 }
 
 #[async_trait]
 impl super::GalgameWebsite for KKGal {
-    async fn fetch_metadata(
+    async fn fetch_index(
         &self,
         page: u32,
+        http_client: &crate::client::HttpClient,
+        log_client: &crate::log::LoggingClient,
+    ) -> Result<Vec<String>, super::Error> {
+        Self::download_index(page, http_client, log_client).await
+    }
+    async fn fetch_games(
+        &self,
+        links: Vec<String>,
         overwrite: bool,
         database: &crate::saved::GameTextDatabase,
-        http_client: &isahc::HttpClient,
+        http_client: &crate::client::HttpClient,
         log_client: &crate::log::LoggingClient,
-    ) -> Result<Vec<crate::saved::GameTextInformation>, String> {
-        let metadata = Self::download_index(page, http_client, log_client).await?;
+    ) -> Result<Vec<crate::saved::GameTextInformation>, super::Error> {
         let mut job_vec = Vec::new();
-        for i in metadata {
-            if !overwrite
-                && database
-                    .iter()
-                    .find(|x| x.id == seahash::hash(i.as_bytes()))
-                    .is_some()
-            {
+        for i in links {
+            if !overwrite && database.iter().find(|x| x.id == self.game_id(&i)).is_some() {
                 continue;
             }
             job_vec.push(crate::exec_future_and_return_vars(
                 i.clone(),
-                Self::download_information(i.clone(), http_client, log_client),
+                self.download_information(i.clone(), http_client, log_client),
             ));
         }
         let mut job_queue: futures::stream::FuturesUnordered<_> = job_vec.into_iter().collect();
@@ -631,30 +664,20 @@ impl super::GalgameWebsite for KKGal {
     async fn download_user_avatars(
         &self,
         avatar_url: String,
-        http_client: &isahc::HttpClient,
+        http_client: &crate::client::HttpClient,
         logging_client: &crate::log::LoggingClient,
-    ) -> Result<Vec<u8>, String> {
-        let mut buffer = Vec::new();
-        http_client
-            .send_async(
-                Request::get(&avatar_url)
-                    .header(isahc::http::header::CONNECTION, "keep-alive")
-                    .body(())
-                    .map_err(|x| x.to_string())?,
-            )
-            .await
-            .map_err(|x| x.to_string())?
-            .into_body()
-            .read_to_end(&mut buffer)
-            .await
-            .map_err(|x| x.to_string())?;
-        tokio::time::sleep(std::time::Duration::from_secs(
-            std::env::var("KKGAL_AVATAR_SLEEP_SEC")
-                .unwrap_or(String::from("1"))
-                .parse()
-                .unwrap(),
-        ))
-        .await;
+    ) -> Result<Vec<u8>, super::Error> {
+        let buffer = super::read_response(
+            Request::get(&avatar_url)
+                .header(isahc::http::header::CONNECTION, "keep-alive")
+                .body(())?,
+            super::Expected::Image,
+            http_client,
+        )
+        .await?;
+        if let Some(i) = self.settings.sleep {
+            tokio::time::sleep(std::time::Duration::from_secs(i)).await;
+        }
         logging_client.log(
             crate::log::LoggingLevel::StatusReport,
             &format!("Downloaded {}", avatar_url),
@@ -665,74 +688,48 @@ impl super::GalgameWebsite for KKGal {
         &self,
         screenshot_url: String,
         _: &crate::saved::GameTextInformation,
-        http_client: &isahc::HttpClient,
+        http_client: &crate::client::HttpClient,
         _: &crate::log::LoggingClient,
-    ) -> Result<Vec<u8>, String> {
-        let mut result = Vec::new();
-        http_client
-            .get_async(screenshot_url)
-            .await
-            .map_err(|x| x.to_string())?
-            .into_body()
-            .read_to_end(&mut result)
-            .await
-            .map_err(|x| x.to_string())?;
-        Ok(result)
+    ) -> Result<Vec<u8>, super::Error> {
+        super::read_response(
+            Request::get(screenshot_url).body(())?,
+            super::Expected::Image,
+            http_client,
+        )
+        .await
     }
     async fn download_http_game(
         &self,
         link: String,
-        _: &crate::saved::GameTextInformation,
+        game_info: &crate::saved::GameTextInformation,
         file: String,
-        http_client: &isahc::HttpClient,
+        http_client: &crate::client::HttpClient,
         log_client: &crate::log::LoggingClient,
-    ) -> Result<(), String> {
-        let builder = if std::env::var_os("KKGAL_DOWN_NO_PROXY").is_some() {
-            let link = http_client
-                .send_async(
-                    Request::get(link)
-                        .redirect_policy(isahc::config::RedirectPolicy::None)
-                        .header(isahc::http::header::REFERER, WEBSITE_LINK)
-                        .body(())
-                        .map_err(|x| x.to_string())?,
-                )
-                .await
-                .map_err(|x| x.to_string())?
-                .headers()
-                .get("location")
-                .ok_or(String::from("Unable to find location header"))?
-                .to_str()
-                .map_err(|x| x.to_string())?
-                .to_string();
+    ) -> Result<(), super::Error> {
+        let expected_size = game_info
+            .files
+            .iter()
+            .find(|x| x.1 .0 == link)
+            .and_then(|x| x.1 .1);
+        let builder = if self.settings.download_direct == Some(true) {
+            let link = Self::resolve_redirect(&link, http_client).await?;
             Request::get(link).proxy(None)
-        } else if let Ok(i) = std::env::var("KKGAL_DOWN_PROXY") {
-            let link = http_client
-                .send_async(
-                    Request::get(link)
-                        .redirect_policy(isahc::config::RedirectPolicy::None)
-                        .header(isahc::http::header::REFERER, WEBSITE_LINK)
-                        .body(())
-                        .map_err(|x| x.to_string())?,
-                )
-                .await
-                .map_err(|x| x.to_string())?
-                .headers()
-                .get("location")
-                .ok_or(String::from("Unable to find location header"))?
-                .to_str()
-                .map_err(|x| x.to_string())?
-                .to_string();
-            Request::get(link).proxy(Some(i.parse().unwrap()))
+        } else if let Some(i) = &self.settings.download_proxy {
+            let link = Self::resolve_redirect(&link, http_client).await?;
+            let proxy = i
+                .parse()
+                .map_err(|x| super::Error::Config(format!("download-proxy: {}", x)))?;
+            Request::get(link).proxy(Some(proxy))
         } else {
             Request::get(link)
         };
-        let response = http_client.send_async(
-            builder
-                .header(isahc::http::header::REFERER, WEBSITE_LINK)
-                .metrics(true)
-                .body(())
-                .map_err(|x| x.to_string())?,
-        );
-        super::game_download_helper(response, &file, log_client).await
+        super::game_download_helper(
+            builder.header(isahc::http::header::REFERER, WEBSITE_LINK),
+            &file,
+            expected_size,
+            http_client,
+            log_client,
+        )
+        .await
     }
 }
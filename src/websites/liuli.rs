@@ -1,3 +1,649 @@
 use async_trait::async_trait;
+use futures::stream::StreamExt;
 use isahc::prelude::*;
-pub struct Liuli {}
+pub const WEBSITE_LINK: &'static str = "https://www.liuli.cat";
+pub struct Liuli {
+    pub settings: crate::config::SiteSettings,
+}
+impl Liuli {
+    async fn download_page(
+        url: &str,
+        http_client: &crate::client::HttpClient,
+    ) -> Result<String, super::Error> {
+        let response_vec = super::read_response(
+            Request::get(url)
+                .header(isahc::http::header::CONNECTION, "keep-alive")
+                .header(isahc::http::header::REFERER, WEBSITE_LINK)
+                .body(())?,
+            super::Expected::Page,
+            http_client,
+        )
+        .await?;
+        Ok(String::from_utf8_lossy(&response_vec).to_string())
+    }
+    fn find_attribute<'a>(
+        attributes: &[(&'a str, Option<&'a str>)],
+        name: &str,
+    ) -> Option<&'a str> {
+        attributes.iter().find(|x| x.0 == name).and_then(|x| x.1)
+    }
+    fn has_class(attributes: &[(&str, Option<&str>)], class: &str) -> bool {
+        Self::find_attribute(attributes, "class")
+            .map(|x| x.split_whitespace().any(|x| x == class))
+            .unwrap_or(false)
+    }
+    fn to_absolute_link(link: &str) -> String {
+        if link.starts_with("//") {
+            format!("https:{}", link)
+        } else if link.starts_with('/') {
+            format!("{}{}", WEBSITE_LINK, link)
+        } else {
+            link.to_string()
+        }
+    }
+    fn parse_time(text: &str) -> Result<time::PrimitiveDateTime, String> {
+        // Wordpress emits e.g. 2020-11-20T14:08:13+08:00, we only keep the local part
+        time::PrimitiveDateTime::parse(
+            text.get(..19)
+                .ok_or(String::from("Time is too short to be parsed"))?,
+            "%FT%T",
+        )
+        .map_err(|x| format!("Error while parsing time: {}", x))
+    }
+    /// Liuli usually publishes bare btih hashes in the article body instead of magnet links.
+    fn find_magnet_hashes(text: &str) -> Vec<String> {
+        let mut found = Vec::new();
+        let mut current = String::new();
+        for i in text.chars().chain(std::iter::once(' ')) {
+            if i.is_ascii_hexdigit() {
+                current.push(i);
+            } else {
+                if current.len() == 40 {
+                    found.push(current.to_lowercase());
+                }
+                current.clear();
+            }
+        }
+        found
+    }
+    pub fn parse_index(url: &str, content: &str) -> Result<Vec<String>, super::Error> {
+        let mut parsed_html = rusthtml::HtmlTag::parse(content).into_iter();
+        let total = parsed_html.len();
+        let mut found_links = Vec::new();
+        while let Some(i) = parsed_html.next() {
+            if let rusthtml::HtmlTag::OpeningTag("h1", attributes) = i {
+                if !Self::has_class(&attributes, "entry-title") {
+                    continue;
+                }
+                if let Some(rusthtml::HtmlTag::OpeningTag("a", attributes)) = parsed_html.next() {
+                    found_links.push(
+                        Self::find_attribute(&attributes, "href")
+                            .ok_or_else(|| {
+                                super::parse_error(
+                                    url,
+                                    total,
+                                    parsed_html.len(),
+                                    String::from("Bad link"),
+                                )
+                            })?
+                            .to_string(),
+                    );
+                }
+            }
+        }
+        Ok(found_links)
+    }
+    pub async fn download_index(
+        page: u32,
+        http_client: &crate::client::HttpClient,
+        log_client: &crate::log::LoggingClient,
+    ) -> Result<Vec<String>, super::Error> {
+        let url = format!("{}/wp/page/{}", WEBSITE_LINK, page.to_string());
+        let content = Self::download_page(&url, http_client).await?;
+        let found_links = Self::parse_index(&url, &content)?;
+        log_client.log(
+            crate::log::LoggingLevel::StatusReport,
+            &format!("Found {} links on page {}", found_links.len(), page),
+        );
+        Ok(found_links)
+    }
+    fn parse_comments<'a>(
+        parsed_html: &mut impl Iterator<Item = rusthtml::HtmlTag<'a>>,
+    ) -> Result<Vec<crate::saved::Comment>, String> {
+        let mut comments: Vec<crate::saved::Comment> = Vec::new();
+        // Replies are wrapped in nested <ol class="children">, the other lists are part of the
+        // comments. Whether each open list is a reply list, innermost last.
+        let mut lists: Vec<bool> = Vec::new();
+        while let Some(i) = parsed_html.next() {
+            match i {
+                rusthtml::HtmlTag::OpeningTag("li", attributes)
+                    if Self::has_class(&attributes, "comment") =>
+                {
+                    let avatar = loop {
+                        match parsed_html.next() {
+                            Some(rusthtml::HtmlTag::OpeningTag("img", attributes)) => {
+                                break Self::find_attribute(&attributes, "src")
+                                    .map(Self::to_absolute_link)
+                                    .ok_or(String::from("Unable to parse avatar"))?
+                            }
+                            Some(_) => continue,
+                            None => return Err(String::from("Unable to find avatar")),
+                        }
+                    };
+                    let name = loop {
+                        match parsed_html.next() {
+                            Some(rusthtml::HtmlTag::OpeningTag("b", attributes)) => {
+                                if Self::has_class(&attributes, "fn") {
+                                    break loop {
+                                        match parsed_html.next() {
+                                            Some(rusthtml::HtmlTag::Unparsable(i)) => break i,
+                                            Some(_) => continue,
+                                            None => {
+                                                return Err(String::from(
+                                                    "Unable to parse comment author",
+                                                ))
+                                            }
+                                        }
+                                    };
+                                }
+                            }
+                            Some(_) => continue,
+                            None => return Err(String::from("Unable to find comment author")),
+                        }
+                    }
+                    .trim()
+                    .to_string();
+                    let time = loop {
+                        match parsed_html.next() {
+                            Some(rusthtml::HtmlTag::OpeningTag("time", attributes)) => {
+                                break Self::parse_time(
+                                    Self::find_attribute(&attributes, "datetime")
+                                        .ok_or(String::from("Unable to parse time"))?,
+                                )?
+                            }
+                            Some(_) => continue,
+                            None => return Err(String::from("Unable to find comment time")),
+                        }
+                    };
+                    loop {
+                        match parsed_html.next() {
+                            Some(rusthtml::HtmlTag::OpeningTag("div", attributes)) => {
+                                if Self::has_class(&attributes, "comment-content") {
+                                    break;
+                                }
+                            }
+                            Some(_) => continue,
+                            None => return Err(String::from("Unable to find comment content")),
+                        }
+                    }
+                    let mut content = Vec::new();
+                    let mut text = String::new();
+                    let mut div_depth = 0;
+                    loop {
+                        match parsed_html.next() {
+                            Some(rusthtml::HtmlTag::OpeningTag("div", _)) => div_depth += 1,
+                            Some(rusthtml::HtmlTag::ClosingTag("div")) if div_depth != 0 => {
+                                div_depth -= 1
+                            }
+                            Some(rusthtml::HtmlTag::OpeningTag("br", _)) => text.push('\n'),
+                            Some(rusthtml::HtmlTag::ClosingTag("p")) => text.push('\n'),
+                            Some(rusthtml::HtmlTag::Unparsable(i)) => text.push_str(i),
+                            Some(rusthtml::HtmlTag::OpeningTag("img", attributes)) => {
+                                if let Some(i) = Self::find_attribute(&attributes, "src") {
+                                    if !text.trim().is_empty() {
+                                        content.push(crate::saved::ParagraphContent::Text(
+                                            text.trim().to_string(),
+                                        ));
+                                    }
+                                    text.clear();
+                                    content.push(crate::saved::ParagraphContent::Image(
+                                        Self::to_absolute_link(i),
+                                    ));
+                                }
+                            }
+                            Some(rusthtml::HtmlTag::ClosingTag("div")) | None => break,
+                            _ => continue,
+                        }
+                    }
+                    if !text.trim().is_empty() {
+                        content.push(crate::saved::ParagraphContent::Text(
+                            text.trim().to_string(),
+                        ));
+                    }
+                    let mut reference = &mut comments;
+                    for _ in lists.iter().filter(|x| **x) {
+                        reference = &mut reference
+                            .last_mut()
+                            .ok_or(String::from("Reply without parent comment"))?
+                            .replies;
+                    }
+                    reference.push(crate::saved::Comment {
+                        user_avatar: avatar,
+                        author: name,
+                        date: time,
+                        content,
+                        replies: Vec::new(),
+                    });
+                }
+                rusthtml::HtmlTag::OpeningTag("ol", attributes) => {
+                    lists.push(Self::has_class(&attributes, "children"))
+                }
+                rusthtml::HtmlTag::ClosingTag("ol") => {
+                    if lists.pop().is_none() {
+                        break;
+                    }
+                }
+                _ => continue,
+            }
+        }
+        Ok(comments)
+    }
+    fn parse_entry_content<'a>(
+        parsed_html: &mut impl Iterator<Item = rusthtml::HtmlTag<'a>>,
+        constructed: &mut crate::saved::GameTextInformation,
+    ) {
+        let mut div_depth = 0;
+        let mut title: Option<String> = None;
+        let mut section = Vec::new();
+        let mut text = String::new();
+        fn flush_text(text: &mut String, section: &mut Vec<crate::saved::ParagraphContent>) {
+            if !text.trim().is_empty() {
+                section.push(crate::saved::ParagraphContent::Text(
+                    text.trim().to_string(),
+                ));
+            }
+            text.clear();
+        }
+        while let Some(i) = parsed_html.next() {
+            match i {
+                rusthtml::HtmlTag::OpeningTag("div", _) => div_depth += 1,
+                rusthtml::HtmlTag::ClosingTag("div") => {
+                    if div_depth == 0 {
+                        break;
+                    }
+                    div_depth -= 1;
+                }
+                rusthtml::HtmlTag::OpeningTag("br", _) => text.push('\n'),
+                rusthtml::HtmlTag::ClosingTag("p") => flush_text(&mut text, &mut section),
+                rusthtml::HtmlTag::Unparsable(i) => {
+                    for hash in Self::find_magnet_hashes(i) {
+                        if constructed
+                            .files
+                            .iter()
+                            .any(|x| x.1 .0.to_lowercase().contains(&hash))
+                        {
+                            continue;
+                        }
+                        constructed.files.push((
+                            format!("Magnet {}", constructed.files.len() + 1),
+                            (format!("magnet:?xt=urn:btih:{}", hash), None),
+                        ));
+                    }
+                    text.push_str(i)
+                }
+                rusthtml::HtmlTag::OpeningTag("img", attributes) => {
+                    if let Some(i) = Self::find_attribute(&attributes, "src") {
+                        flush_text(&mut text, &mut section);
+                        section.push(crate::saved::ParagraphContent::Image(
+                            Self::to_absolute_link(i),
+                        ));
+                    }
+                }
+                rusthtml::HtmlTag::OpeningTag("a", attributes) => {
+                    if let Some(i) = Self::find_attribute(&attributes, "href") {
+                        if i.starts_with("magnet:") || i.starts_with("https://pan.baidu.com") {
+                            constructed.files.push((
+                                format!("Download link {}", constructed.files.len() + 1),
+                                (i.to_string(), None),
+                            ));
+                        }
+                    }
+                }
+                rusthtml::HtmlTag::OpeningTag(heading @ "h2", _)
+                | rusthtml::HtmlTag::OpeningTag(heading @ "h3", _)
+                | rusthtml::HtmlTag::OpeningTag(heading @ "h4", _) => {
+                    flush_text(&mut text, &mut section);
+                    if title.is_some() || section.len() != 0 {
+                        constructed
+                            .paragraphs
+                            .push((title.take(), std::mem::take(&mut section)));
+                    }
+                    let mut heading_text = String::new();
+                    while let Some(i) = parsed_html.next() {
+                        match i {
+                            rusthtml::HtmlTag::Unparsable(i) => heading_text.push_str(i),
+                            rusthtml::HtmlTag::ClosingTag(i) if i == heading => break,
+                            _ => continue,
+                        }
+                    }
+                    title = Some(heading_text.trim().to_string());
+                }
+                _ => continue,
+            }
+        }
+        flush_text(&mut text, &mut section);
+        if title.is_some() || section.len() != 0 {
+            constructed.paragraphs.push((title, section));
+        }
+    }
+    pub fn parse_information(
+        url: &str,
+        content: &str,
+    ) -> Result<crate::saved::GameTextInformation, super::Error> {
+        let mut parsed_html = rusthtml::HtmlTag::parse(content).into_iter();
+        let total = parsed_html.len();
+        Self::parse_game(url, &mut parsed_html)
+            .map_err(|x| super::parse_error(url, total, parsed_html.len(), x))
+    }
+    fn parse_game<'a>(
+        url: &str,
+        parsed_html: &mut impl Iterator<Item = rusthtml::HtmlTag<'a>>,
+    ) -> Result<crate::saved::GameTextInformation, String> {
+        let mut constructed = crate::saved::GameTextInformation::default(
+            seahash::hash(url.as_bytes()),
+            String::from("liuli"),
+        );
+        let mut published_found = false;
+        while let Some(i) = parsed_html.next() {
+            match i {
+                rusthtml::HtmlTag::OpeningTag("h1", attributes) => {
+                    if Self::has_class(&attributes, "entry-title") {
+                        while let Some(i) = parsed_html.next() {
+                            if let rusthtml::HtmlTag::Unparsable(i) = i {
+                                constructed.name = i.trim().to_string();
+                                break;
+                            }
+                        }
+                    }
+                }
+                rusthtml::HtmlTag::OpeningTag("time", attributes) => {
+                    if !published_found && Self::has_class(&attributes, "entry-date") {
+                        constructed.published = Self::parse_time(
+                            Self::find_attribute(&attributes, "datetime")
+                                .ok_or(String::from("Unable to parse published date"))?,
+                        )?;
+                        published_found = true;
+                    }
+                }
+                rusthtml::HtmlTag::OpeningTag("a", attributes) => {
+                    let is_tag = Self::find_attribute(&attributes, "rel")
+                        .map(|x| x.split_whitespace().any(|x| x == "tag"))
+                        .unwrap_or(false);
+                    if is_tag {
+                        if let Some(rusthtml::HtmlTag::Unparsable(i)) = parsed_html.next() {
+                            let tag = i.trim().to_string();
+                            if !constructed.tags.contains(&tag) {
+                                constructed.tags.push(tag);
+                            }
+                        }
+                    }
+                }
+                rusthtml::HtmlTag::OpeningTag("div", attributes) => {
+                    if Self::has_class(&attributes, "entry-content") {
+                        Self::parse_entry_content(parsed_html, &mut constructed);
+                    }
+                }
+                rusthtml::HtmlTag::OpeningTag("ol", attributes) => {
+                    if Self::has_class(&attributes, "commentlist") {
+                        constructed.comments = Self::parse_comments(parsed_html)?;
+                    }
+                }
+                _ => continue,
+            }
+        }
+        if constructed.name.is_empty() {
+            return Err(String::from("Unable to find title"));
+        }
+        Ok(constructed)
+    }
+    pub async fn download_information(
+        url: String,
+        http_client: &crate::client::HttpClient,
+        log_client: &crate::log::LoggingClient,
+    ) -> Result<crate::saved::GameTextInformation, super::Error> {
+        let content = Self::download_page(&url, http_client).await?;
+        let constructed = Self::parse_information(&url, &content)?;
+        log_client.log(
+            crate::log::LoggingLevel::StatusReport,
+            &format!(
+                "Parsed information from {}(id: {})",
+                constructed.name, constructed.id
+            ),
+        );
+        Ok(constructed)
+    }
+}
+
+#[async_trait]
+impl super::GalgameWebsite for Liuli {
+    async fn fetch_index(
+        &self,
+        page: u32,
+        http_client: &crate::client::HttpClient,
+        log_client: &crate::log::LoggingClient,
+    ) -> Result<Vec<String>, super::Error> {
+        Self::download_index(page, http_client, log_client).await
+    }
+    async fn fetch_games(
+        &self,
+        links: Vec<String>,
+        overwrite: bool,
+        database: &crate::saved::GameTextDatabase,
+        http_client: &crate::client::HttpClient,
+        log_client: &crate::log::LoggingClient,
+    ) -> Result<Vec<crate::saved::GameTextInformation>, super::Error> {
+        let mut job_vec = Vec::new();
+        for i in links {
+            if !overwrite && database.iter().find(|x| x.id == self.game_id(&i)).is_some() {
+                continue;
+            }
+            job_vec.push(crate::exec_future_and_return_vars(
+                i.clone(),
+                Self::download_information(i.clone(), http_client, log_client),
+            ));
+        }
+        let mut job_queue: futures::stream::FuturesUnordered<_> = job_vec.into_iter().collect();
+        let mut result_vec = Vec::new();
+        while let Some(i) = job_queue.next().await {
+            match i.1 {
+                Ok(j) => result_vec.push(j),
+                Err(j) => log_client.log(
+                    crate::log::LoggingLevel::Warning,
+                    &format!("Error while parsing information from page {}: {}", i.0, j),
+                ),
+            }
+        }
+        Ok(result_vec)
+    }
+    async fn download_user_avatars(
+        &self,
+        avatar_url: String,
+        http_client: &crate::client::HttpClient,
+        logging_client: &crate::log::LoggingClient,
+    ) -> Result<Vec<u8>, super::Error> {
+        let buffer = super::read_response(
+            Request::get(&avatar_url).body(())?,
+            super::Expected::Image,
+            http_client,
+        )
+        .await?;
+        if let Some(i) = self.settings.sleep {
+            tokio::time::sleep(std::time::Duration::from_secs(i)).await;
+        }
+        logging_client.log(
+            crate::log::LoggingLevel::StatusReport,
+            &format!("Downloaded {}", avatar_url),
+        );
+        Ok(buffer)
+    }
+    async fn download_screenshot(
+        &self,
+        screenshot_url: String,
+        _: &crate::saved::GameTextInformation,
+        http_client: &crate::client::HttpClient,
+        _: &crate::log::LoggingClient,
+    ) -> Result<Vec<u8>, super::Error> {
+        super::read_response(
+            Request::get(screenshot_url)
+                .header(isahc::http::header::REFERER, WEBSITE_LINK)
+                .body(())?,
+            super::Expected::Image,
+            http_client,
+        )
+        .await
+    }
+    async fn download_http_game(
+        &self,
+        link: String,
+        game_info: &crate::saved::GameTextInformation,
+        file: String,
+        http_client: &crate::client::HttpClient,
+        log_client: &crate::log::LoggingClient,
+    ) -> Result<(), super::Error> {
+        if link.starts_with("magnet:") {
+            return Err(super::Error::Unsupported(format!(
+                "{} is a magnet link, use a torrent client instead",
+                link
+            )));
+        }
+        let expected_size = game_info
+            .files
+            .iter()
+            .find(|x| x.1 .0 == link)
+            .and_then(|x| x.1 .1);
+        super::game_download_helper(
+            Request::get(link).header(isahc::http::header::REFERER, WEBSITE_LINK),
+            &file,
+            expected_size,
+            http_client,
+            log_client,
+        )
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Liuli;
+    use crate::saved::ParagraphContent;
+    const INDEX: &str = include_str!("../../fixtures/liuli/index.html");
+    const GAME: &str = include_str!("../../fixtures/liuli/game.html");
+    const GAME_URL: &str = "https://www.liuli.cat/wp/80812.html";
+    fn text(i: &ParagraphContent) -> &str {
+        match i {
+            ParagraphContent::Text(i) => i,
+            ParagraphContent::Image(i) => panic!("Expected text, found image {}", i),
+        }
+    }
+    fn image(i: &ParagraphContent) -> &str {
+        match i {
+            ParagraphContent::Image(i) => i,
+            ParagraphContent::Text(i) => panic!("Expected image, found text {}", i),
+        }
+    }
+    #[test]
+    fn parses_index_links() {
+        let links = Liuli::parse_index("https://www.liuli.cat/wp/page/2", INDEX).unwrap();
+        assert_eq!(
+            links,
+            vec![
+                "https://www.liuli.cat/wp/80812.html",
+                "https://www.liuli.cat/wp/80790.html",
+                "https://www.liuli.cat/wp/80777.html",
+            ]
+        );
+    }
+    #[test]
+    fn parses_game_fields() {
+        let game = Liuli::parse_information(GAME_URL, GAME).unwrap();
+        assert_eq!(game.id, seahash::hash(GAME_URL.as_bytes()));
+        assert_eq!(game.website, "liuli");
+        assert_eq!(game.name, "[ADV] 夏空のモノローグ 汉化硬盘版");
+        assert_eq!(game.tags, vec!["ACG游戏", "ADV", "汉化"]);
+        assert_eq!(
+            game.published,
+            time::PrimitiveDateTime::new(time::date!(2020 - 11 - 20), time::time!(14:08:13))
+        );
+    }
+    #[test]
+    fn parses_paragraphs() {
+        let game = Liuli::parse_information(GAME_URL, GAME).unwrap();
+        let titles: Vec<_> = game.paragraphs.iter().map(|x| x.0.as_deref()).collect();
+        assert_eq!(titles, vec![None, Some("游戏截图"), Some("下载")]);
+        let introduction = &game.paragraphs[0].1;
+        assert_eq!(introduction.len(), 2);
+        assert_eq!(
+            image(&introduction[0]),
+            "https://i.loli.net/2020/11/20/cover80812.jpg"
+        );
+        assert_eq!(
+            text(&introduction[1]),
+            "夏日的小岛上，\n一段关于时间的故事。"
+        );
+        let screenshots = &game.paragraphs[1].1;
+        assert_eq!(screenshots.len(), 3);
+        assert_eq!(
+            image(&screenshots[0]),
+            "https://www.liuli.cat/wp/wp-content/uploads/2020/11/shot1.jpg"
+        );
+        assert_eq!(
+            image(&screenshots[1]),
+            "https://i.loli.net/2020/11/20/shot2.png"
+        );
+        assert_eq!(text(&screenshots[2]), "画面为汉化版");
+    }
+    #[test]
+    fn parses_magnet_files() {
+        let game = Liuli::parse_information(GAME_URL, GAME).unwrap();
+        let files: Vec<_> = game
+            .files
+            .iter()
+            .map(|x| (x.0.as_str(), x.1 .0.as_str()))
+            .collect();
+        assert_eq!(
+            files,
+            vec![
+                (
+                    "Magnet 1",
+                    "magnet:?xt=urn:btih:9d3c7e2b1a0f4e5d6c7b8a9f0e1d2c3b4a5f6e7d"
+                ),
+                (
+                    "Download link 2",
+                    "magnet:?xt=urn:btih:0123456789abcdef0123456789abcdef01234567"
+                ),
+                ("Download link 3", "https://pan.baidu.com/s/1abcdefg"),
+            ]
+        );
+    }
+    #[test]
+    fn parses_comment_tree() {
+        let game = Liuli::parse_information(GAME_URL, GAME).unwrap();
+        let authors: Vec<_> = game.comments.iter().map(|x| x.author.as_str()).collect();
+        assert_eq!(authors, vec!["Alice", "Dave"]);
+        let alice = &game.comments[0];
+        assert_eq!(alice.user_avatar, "https://cdn.v2ex.com/gravatar/aaaa?s=44");
+        assert_eq!(
+            alice.date,
+            time::PrimitiveDateTime::new(time::date!(2020 - 11 - 20), time::time!(15:00:01))
+        );
+        // The list in the body of the comment is part of it, not a reply
+        assert_eq!(alice.content.len(), 2);
+        assert!(text(&alice.content[0]).starts_with("感谢分享！"));
+        assert!(text(&alice.content[0]).contains("第二条"));
+        assert!(text(&alice.content[0]).ends_with("期待后续"));
+        assert_eq!(
+            image(&alice.content[1]),
+            "https://www.liuli.cat/wp/wp-includes/images/smilies/icon_smile.gif"
+        );
+        assert_eq!(alice.replies.len(), 1);
+        let bob = &alice.replies[0];
+        assert_eq!(bob.author, "Bob");
+        assert_eq!(text(&bob.content[0]), "同感");
+        assert_eq!(bob.replies.len(), 1);
+        assert_eq!(bob.replies[0].author, "Carol");
+        assert_eq!(bob.replies[0].replies.len(), 0);
+        let dave = &game.comments[1];
+        assert_eq!(dave.replies.len(), 0);
+        assert_eq!(text(&dave.content[0]), "第一行\n第二行");
+    }
+}
@@ -1,58 +1,303 @@
 use async_trait::async_trait;
+use crate::error::{Error, ResponseError};
+use futures::io::AsyncReadExt;
 use futures::FutureExt;
 use isahc::prelude::*;
 use tokio_util::compat::FuturesAsyncReadCompatExt;
 pub mod kkgal;
 pub mod liuli;
 #[async_trait]
-pub trait GalgameWebsite {
+pub trait GalgameWebsite: Sync {
+    /// Returns the links of the games listed on the given index page.
+    async fn fetch_index(
+        &self,
+        page: u32,
+        http_client: &crate::client::HttpClient,
+        log_client: &crate::log::LoggingClient,
+    ) -> Result<Vec<String>, Error>;
+    /// Downloads information for the given game links, skipping the ones already in database
+    /// unless `overwrite` is set.
+    async fn fetch_games(
+        &self,
+        links: Vec<String>,
+        overwrite: bool,
+        database: &crate::saved::GameTextDatabase,
+        http_client: &crate::client::HttpClient,
+        log_client: &crate::log::LoggingClient,
+    ) -> Result<Vec<crate::saved::GameTextInformation>, Error>;
     async fn fetch_metadata(
         &self,
         page: u32,
         overwrite: bool,
         database: &crate::saved::GameTextDatabase,
-        http_client: &isahc::HttpClient,
+        http_client: &crate::client::HttpClient,
         log_client: &crate::log::LoggingClient,
-    ) -> Result<Vec<crate::saved::GameTextInformation>, String>;
+    ) -> Result<Vec<crate::saved::GameTextInformation>, Error> {
+        let links = self.fetch_index(page, http_client, log_client).await?;
+        self.fetch_games(links, overwrite, database, http_client, log_client)
+            .await
+    }
+    fn game_id(&self, link: &str) -> u64 {
+        seahash::hash(link.as_bytes())
+    }
     async fn download_user_avatars(
         &self,
         avatar_url: String,
-        http_client: &isahc::HttpClient,
+        http_client: &crate::client::HttpClient,
         log_client: &crate::log::LoggingClient,
-    ) -> Result<Vec<u8>, String>;
+    ) -> Result<Vec<u8>, Error>;
     async fn download_screenshot(
         &self,
         screenshot_url: String,
         game_info: &crate::saved::GameTextInformation,
-        http_client: &isahc::HttpClient,
+        http_client: &crate::client::HttpClient,
         log_client: &crate::log::LoggingClient,
-    ) -> Result<Vec<u8>, String>;
+    ) -> Result<Vec<u8>, Error>;
     async fn download_http_game(
         &self,
         link: String,
         game_info: &crate::saved::GameTextInformation,
         file: String,
-        http_client: &isahc::HttpClient,
+        http_client: &crate::client::HttpClient,
         log_client: &crate::log::LoggingClient,
-    ) -> Result<(), String>;
+    ) -> Result<(), Error>;
+}
+/// A parse error at the tag of the page which is followed by `remaining` of its `total` tags.
+pub fn parse_error(url: &str, total: usize, remaining: usize, message: String) -> Error {
+    Error::parse(
+        url,
+        format!("tag {} of {}", total - remaining, total),
+        message,
+    )
+}
+/// What a request should return, checked against the Content-Type of the response. Responses
+/// without one are accepted.
+#[derive(Clone, Copy)]
+pub enum Expected {
+    Page,
+    Image,
+    /// Anything but an html page
+    File,
+}
+impl Expected {
+    fn accepts(self, content_type: &str) -> bool {
+        let content_type = content_type.to_ascii_lowercase();
+        match self {
+            Self::Page => {
+                content_type.starts_with("text/")
+                    || content_type.contains("html")
+                    || content_type.contains("json")
+                    || content_type.contains("xml")
+            }
+            Self::Image => {
+                content_type.starts_with("image/")
+                    || content_type.starts_with("application/octet-stream")
+                    || content_type.starts_with("binary/")
+            }
+            Self::File => !content_type.starts_with("text/html"),
+        }
+    }
 }
-async fn game_download_helper<'a>(
-    response: isahc::ResponseFuture<'a>,
+/// Turns a response with an error status or an unexpected Content-Type into a `ResponseError`.
+pub async fn check_response(
+    mut response: isahc::http::Response<isahc::Body>,
+    url: &str,
+    expected: Expected,
+) -> Result<isahc::http::Response<isahc::Body>, Error> {
+    let content_type = response
+        .headers()
+        .get(isahc::http::header::CONTENT_TYPE)
+        .and_then(|x| x.to_str().ok())
+        .map(|x| x.to_string());
+    let retry_after = response
+        .headers()
+        .get(isahc::http::header::RETRY_AFTER)
+        .and_then(|x| x.to_str().ok())
+        .and_then(parse_retry_after);
+    if response.status().is_success()
+        && content_type
+            .as_deref()
+            .map_or(true, |x| expected.accepts(x))
+    {
+        return Ok(response);
+    }
+    let mut body = Vec::new();
+    // The snippet is only informative, a broken connection doesn't matter here
+    let _ = response
+        .body_mut()
+        .take(crate::error::SNIPPET_LENGTH)
+        .read_to_end(&mut body)
+        .await;
+    Err(Error::Response(ResponseError {
+        status: response.status(),
+        url: url.to_string(),
+        content_type,
+        snippet: String::from_utf8_lossy(&body)
+            .split_whitespace()
+            .collect::<Vec<_>>()
+            .join(" "),
+        retry_after,
+    }))
+}
+/// Retry-After is either a number of seconds or an HTTP date.
+fn parse_retry_after(value: &str) -> Option<std::time::Duration> {
+    let value = value.trim();
+    if let Ok(i) = value.parse() {
+        return Some(std::time::Duration::from_secs(i));
+    }
+    let date = time::PrimitiveDateTime::parse(value, "%a, %d %b %Y %T GMT")
+        .ok()?
+        .assume_utc();
+    let seconds = (date - time::OffsetDateTime::now_utc()).whole_seconds();
+    Some(std::time::Duration::from_secs(seconds.max(0) as u64))
+}
+/// Sends the request and reads the whole body, see `check_response`.
+pub async fn read_response(
+    request: isahc::http::Request<()>,
+    expected: Expected,
+    http_client: &crate::client::HttpClient,
+) -> Result<Vec<u8>, Error> {
+    let url = request.uri().to_string();
+    let response = http_client.send_async(request).await?;
+    let mut response = check_response(response, &url, expected).await?;
+    let mut body = Vec::new();
+    // Failing to read the body is a network error, not a local one
+    response
+        .body_mut()
+        .read_to_end(&mut body)
+        .await
+        .map_err(isahc::Error::from)?;
+    Ok(body)
+}
+/// Downloads a game into `file`, going through `file.part` so that an interrupted download can be
+/// resumed with a `Range` request next time. The partial file is only renamed into place once its
+/// size matches what the server (or, failing that, the website) reported.
+async fn game_download_helper(
+    request: isahc::http::request::Builder,
     file: &str,
+    expected_size: Option<u128>,
+    http_client: &crate::client::HttpClient,
     log_client: &crate::log::LoggingClient,
-) -> Result<(), String> {
-    let mut response_mapped = response.await.map_err(|x| x.to_string())?;
+) -> Result<(), Error> {
+    let partial_file = format!("{}.part", file);
+    let mut offset = tokio::fs::metadata(&partial_file)
+        .await
+        .map(|x| x.len())
+        .unwrap_or(0);
+    let request = if offset != 0 {
+        request.header(isahc::http::header::RANGE, format!("bytes={}-", offset))
+    } else {
+        request
+    };
+    let request = request.metrics(true).body(())?;
+    let url = request.uri().to_string();
+    let response_mapped = http_client.send_async(request).await?;
+    // An error page must not end up in the partial file
+    let mut response_mapped =
+        if response_mapped.status() == isahc::http::StatusCode::RANGE_NOT_SATISFIABLE {
+            response_mapped
+        } else {
+            check_response(response_mapped, &url, Expected::File).await?
+        };
+    let header = |name: isahc::http::header::HeaderName| {
+        response_mapped
+            .headers()
+            .get(name)
+            .and_then(|x| x.to_str().ok())
+            .map(|x| x.to_string())
+    };
+    if response_mapped.status() == isahc::http::StatusCode::RANGE_NOT_SATISFIABLE {
+        // Nothing left to download, the partial file should be complete already
+        let total_size = header(isahc::http::header::CONTENT_RANGE)
+            .and_then(|x| parse_content_range(&x))
+            .and_then(|x| x.1);
+        if total_size != Some(offset) {
+            return discard_partial_download(
+                &partial_file,
+                format!(
+                    "Server refused to resume {} from {} bytes, its size is {}",
+                    file,
+                    offset,
+                    total_size.map_or(String::from("unknown"), |x| format!("{} bytes", x))
+                ),
+                log_client,
+            )
+            .await;
+        }
+        return finish_partial_download(&partial_file, file, total_size, expected_size, log_client)
+            .await;
+    }
+    let accept_ranges = header(isahc::http::header::ACCEPT_RANGES);
+    let content_length: Option<u64> =
+        header(isahc::http::header::CONTENT_LENGTH).and_then(|x| x.parse().ok());
+    let resumed = response_mapped.status() == isahc::http::StatusCode::PARTIAL_CONTENT;
+    let range = header(isahc::http::header::CONTENT_RANGE).and_then(|x| parse_content_range(&x));
+    if resumed && range.and_then(|x| x.0) != Some(offset) {
+        // Appending the body would put its bytes at the wrong position
+        return discard_partial_download(
+            &partial_file,
+            format!(
+                "Server resumed {} from {} instead of {} bytes",
+                file,
+                range
+                    .and_then(|x| x.0)
+                    .map_or(String::from("an unknown position"), |x| x.to_string()),
+                offset
+            ),
+            log_client,
+        )
+        .await;
+    }
+    if offset != 0 && !resumed {
+        log_client.log(
+            crate::log::LoggingLevel::Warning,
+            &format!(
+                "Server refused to resume {} from {} bytes, starting over",
+                file, offset
+            ),
+        );
+        offset = 0;
+    } else if offset == 0 && accept_ranges.as_deref() != Some("bytes") {
+        log_client.log(
+            crate::log::LoggingLevel::Warning,
+            &format!(
+                "Server does not accept ranges, {} cannot be resumed if interrupted",
+                file
+            ),
+        );
+    } else if resumed {
+        log_client.log(
+            crate::log::LoggingLevel::Message,
+            &format!("Resuming {} from {} bytes", file, offset),
+        );
+    }
+    let total_size = range
+        .and_then(|x| x.1)
+        .or(content_length.map(|x| x + offset));
     let metrics = response_mapped.metrics().unwrap().clone();
     let mut stream = response_mapped.body_mut().compat();
-    let mut file_handle = tokio::fs::File::create(file)
-        .await
-        .map_err(|x| x.to_string())?;
-    async fn logger(log_client: &crate::log::LoggingClient, metrics: &isahc::Metrics, file: &str) {
+    let mut file_handle = if resumed {
+        tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&partial_file)
+            .await?
+    } else {
+        tokio::fs::File::create(&partial_file).await?
+    };
+    async fn logger(
+        log_client: &crate::log::LoggingClient,
+        metrics: &isahc::Metrics,
+        file: &str,
+        offset: u64,
+    ) {
         let mut last_byte_count = 0;
         let mut last_timestamp = std::time::Instant::now();
         loop {
             tokio::time::sleep(std::time::Duration::from_secs(5)).await;
-            let progress = metrics.download_progress().0 as f64 / metrics.download_progress().1 as f64;
+            let progress = (metrics.download_progress().0 + offset) as f64
+                / (metrics.download_progress().1 + offset) as f64
+                * 100.0;
             let speed = (metrics.download_progress().0 - last_byte_count) as f64 / last_timestamp.elapsed().as_secs_f64();
             last_byte_count = metrics.download_progress().0;
             last_timestamp = std::time::Instant::now();
@@ -62,7 +307,7 @@ async fn game_download_helper<'a>(
                     "{} => {} {}({:.2}%) {}/s",
                     file,
                     humantime::format_duration(std::time::Duration::from_secs(metrics.total_time().as_secs())),
-                    byte_unit::Byte::from_bytes(metrics.download_progress().0 as u128)
+                    byte_unit::Byte::from_bytes((metrics.download_progress().0 + offset) as u128)
                         .get_appropriate_unit(true)
                         .to_string(),
                     progress,
@@ -74,7 +319,291 @@ async fn game_download_helper<'a>(
         }
     }
     futures::select! {
-        result = tokio::io::copy(&mut stream, &mut file_handle).fuse() => return result.map_err(|x| x.to_string()).map(|_| ()),
-        _ = logger(log_client, &metrics, file).fuse() => Err(String::from("Error while waiting logger to finish")),
+        result = tokio::io::copy(&mut stream, &mut file_handle).fuse() => result.map_err(isahc::Error::from).map(|_| ())?,
+        _ = logger(log_client, &metrics, file, offset).fuse() => return Err(isahc::Error::Aborted.into()),
+    }
+    drop(file_handle);
+    finish_partial_download(&partial_file, file, total_size, expected_size, log_client).await
+}
+/// Start and total size of a `Content-Range` such as `bytes 100-199/200`, either of them `None`
+/// when given as `*`.
+fn parse_content_range(value: &str) -> Option<(Option<u64>, Option<u64>)> {
+    let (range, total) = value.trim().strip_prefix("bytes ")?.split_once('/')?;
+    let start = range.split('-').next()?.trim().parse().ok();
+    Some((start, total.trim().parse().ok()))
+}
+async fn finish_partial_download(
+    partial_file: &str,
+    file: &str,
+    total_size: Option<u64>,
+    expected_size: Option<u128>,
+    log_client: &crate::log::LoggingClient,
+) -> Result<(), Error> {
+    let size = tokio::fs::metadata(partial_file).await?.len();
+    if let Some(i) = total_size {
+        if size > i {
+            return discard_partial_download(
+                partial_file,
+                format!(
+                    "{} is {} bytes but server reported {} bytes",
+                    partial_file, size, i
+                ),
+                log_client,
+            )
+            .await;
+        } else if size != i {
+            // The connection was closed early, asking again resumes the download
+            return Err(isahc::Error::ResponseBodyError(Some(format!(
+                "{} is {} bytes but server reported {} bytes, keeping partial file",
+                partial_file, size, i
+            )))
+            .into());
+        }
+    } else if let Some(i) = expected_size {
+        // Sizes scraped from websites are rounded human readable strings, and it's not always
+        // clear whether they're in decimal or binary units, so only reject obvious mismatches.
+        if (size as f64 - i as f64).abs() > i as f64 * 0.1 {
+            return Err(isahc::Error::ResponseBodyError(Some(format!(
+                "{} is {} bytes but website reported about {} bytes, keeping partial file",
+                partial_file, size, i
+            )))
+            .into());
+        }
+    }
+    Ok(tokio::fs::rename(partial_file, file).await?)
+}
+/// Removes a partial file which can't be part of the remote one, so that the next attempt starts
+/// over instead of failing the same way forever.
+async fn discard_partial_download(
+    partial_file: &str,
+    reason: String,
+    log_client: &crate::log::LoggingClient,
+) -> Result<(), Error> {
+    log_client.log(
+        crate::log::LoggingLevel::Warning,
+        &format!("{}, removing partial file to start over", reason),
+    );
+    tokio::fs::remove_file(partial_file).await?;
+    Err(isahc::Error::ResponseBodyError(Some(reason)).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{game_download_helper, parse_content_range, parse_retry_after};
+    use std::time::Duration;
+    struct Reply {
+        status: u16,
+        content_range: Option<&'static str>,
+        body: &'static [u8],
+        /// Sent without a Content-Length
+        chunked: bool,
+    }
+    impl Reply {
+        fn new(status: u16, content_range: Option<&'static str>, body: &'static [u8]) -> Self {
+            Self {
+                status,
+                content_range,
+                body,
+                chunked: false,
+            }
+        }
+    }
+    /// Downloads a file from a local server which answers with `reply`, starting from `partial`.
+    /// Returns the result, the `Range` asked for, and the contents of the file and the partial
+    /// file afterwards.
+    async fn download(
+        name: &str,
+        partial: Option<&[u8]>,
+        expected_size: Option<u128>,
+        reply: Reply,
+    ) -> (
+        Result<(), crate::error::Error>,
+        Option<String>,
+        Option<Vec<u8>>,
+        Option<Vec<u8>>,
+    ) {
+        let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/game.zip", server.server_addr());
+        let (sender, receiver) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            let request = server.recv().unwrap();
+            sender
+                .send(
+                    request
+                        .headers()
+                        .iter()
+                        .find(|x| x.field.equiv("Range"))
+                        .map(|x| x.value.to_string()),
+                )
+                .unwrap();
+            let mut headers =
+                vec![
+                    tiny_http::Header::from_bytes(&b"Content-Type"[..], &b"application/zip"[..])
+                        .unwrap(),
+                ];
+            if let Some(i) = reply.content_range {
+                headers.push(tiny_http::Header::from_bytes(&b"Content-Range"[..], i).unwrap());
+            }
+            let length = if reply.chunked {
+                None
+            } else {
+                Some(reply.body.len())
+            };
+            let _ = request.respond(tiny_http::Response::new(
+                tiny_http::StatusCode(reply.status),
+                headers,
+                reply.body,
+                length,
+                None,
+            ));
+        });
+        let file = std::env::temp_dir().join(format!("bishojo-download-{}", name));
+        let file = file.to_str().unwrap().to_string();
+        let partial_file = format!("{}.part", file);
+        let _ = std::fs::remove_file(&file);
+        let _ = std::fs::remove_file(&partial_file);
+        if let Some(i) = partial {
+            std::fs::write(&partial_file, i).unwrap();
+        }
+        let client = crate::client::HttpClient::new(
+            isahc::HttpClient::new().unwrap(),
+            crate::client::Limits {
+                requests_per_second: None,
+                max_concurrency: None,
+            },
+            Default::default(),
+        );
+        let result = game_download_helper(
+            isahc::http::Request::get(url),
+            &file,
+            expected_size,
+            &client,
+            &crate::log::LoggingClient::new(),
+        )
+        .await;
+        let (content, partial) = (std::fs::read(&file).ok(), std::fs::read(&partial_file).ok());
+        let _ = std::fs::remove_file(&file);
+        let _ = std::fs::remove_file(&partial_file);
+        (result, receiver.recv().unwrap(), content, partial)
+    }
+    #[tokio::test]
+    async fn resumes_partial_downloads() {
+        let (result, range, content, partial) = download(
+            "resume",
+            Some(b"0123"),
+            None,
+            Reply::new(206, Some("bytes 4-9/10"), b"456789"),
+        )
+        .await;
+        assert!(result.is_ok());
+        assert_eq!(range.as_deref(), Some("bytes=4-"));
+        assert_eq!(content.as_deref(), Some(&b"0123456789"[..]));
+        assert_eq!(partial, None);
+        let (result, range, content, _) =
+            download("fresh", None, None, Reply::new(200, None, b"0123456789")).await;
+        assert!(result.is_ok());
+        assert_eq!(range, None);
+        assert_eq!(content.as_deref(), Some(&b"0123456789"[..]));
+    }
+    #[tokio::test]
+    async fn starts_over_when_the_range_is_ignored() {
+        let (result, _, content, _) = download(
+            "ignored",
+            Some(b"0123"),
+            None,
+            Reply::new(200, None, b"0123456789"),
+        )
+        .await;
+        assert!(result.is_ok());
+        assert_eq!(content.as_deref(), Some(&b"0123456789"[..]));
+        // Resumed from another position, the partial file can't be trusted anymore
+        let (result, _, content, partial) = download(
+            "elsewhere",
+            Some(b"0123"),
+            None,
+            Reply::new(206, Some("bytes 2-9/10"), b"23456789"),
+        )
+        .await;
+        assert!(result.is_err());
+        assert_eq!(content, None);
+        assert_eq!(partial, None);
+    }
+    #[tokio::test]
+    async fn finishes_or_discards_on_range_not_satisfiable() {
+        let (result, _, content, _) = download(
+            "complete",
+            Some(b"0123456789"),
+            None,
+            Reply::new(416, Some("bytes */10"), b""),
+        )
+        .await;
+        assert!(result.is_ok());
+        assert_eq!(content.as_deref(), Some(&b"0123456789"[..]));
+        let (result, _, content, partial) = download(
+            "larger",
+            Some(b"0123456789"),
+            None,
+            Reply::new(416, Some("bytes */5"), b""),
+        )
+        .await;
+        assert!(result.is_err());
+        assert_eq!(content, None);
+        assert_eq!(partial, None);
+    }
+    #[tokio::test]
+    async fn keeps_partial_files_which_are_too_short() {
+        let (result, _, content, partial) = download(
+            "short",
+            Some(b"0123"),
+            None,
+            Reply::new(206, Some("bytes 4-9/10"), b"45"),
+        )
+        .await;
+        assert!(result.is_err());
+        assert_eq!(content, None);
+        assert_eq!(partial.as_deref(), Some(&b"012345"[..]));
+    }
+    #[tokio::test]
+    async fn compares_unknown_sizes_with_the_website() {
+        let chunked = || Reply {
+            chunked: true,
+            ..Reply::new(200, None, b"0123456789")
+        };
+        // Sizes from websites are rounded, so they only need to be within 10%
+        let (result, _, content, _) = download("rounded", None, Some(11), chunked()).await;
+        assert!(result.is_ok());
+        assert!(content.is_some());
+        let (result, _, content, partial) = download("mismatch", None, Some(100), chunked()).await;
+        assert!(result.is_err());
+        assert_eq!(content, None);
+        assert_eq!(partial.as_deref(), Some(&b"0123456789"[..]));
+    }
+    #[test]
+    fn parses_content_ranges() {
+        assert_eq!(
+            parse_content_range("bytes 100-199/200"),
+            Some((Some(100), Some(200)))
+        );
+        assert_eq!(parse_content_range("bytes 0-9/*"), Some((Some(0), None)));
+        assert_eq!(parse_content_range("bytes */200"), Some((None, Some(200))));
+        assert_eq!(parse_content_range("items 0-9/10"), None);
+    }
+    #[test]
+    fn parses_retry_after_seconds() {
+        assert_eq!(parse_retry_after(" 120 "), Some(Duration::from_secs(120)));
+        assert_eq!(parse_retry_after("soon"), None);
+        assert_eq!(parse_retry_after("-1"), None);
+    }
+    #[test]
+    fn parses_retry_after_dates() {
+        let date = (time::OffsetDateTime::now_utc() + time::Duration::seconds(120))
+            .format("%a, %d %b %Y %T GMT");
+        let delay = parse_retry_after(&date).unwrap();
+        assert!(delay <= Duration::from_secs(120) && delay >= Duration::from_secs(118));
+        // Dates which passed already don't make anyone wait
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:28:00 GMT"),
+            Some(Duration::from_secs(0))
+        );
     }
 }